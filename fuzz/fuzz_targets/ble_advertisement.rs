@@ -0,0 +1,22 @@
+#![no_main]
+
+use domo_wot_bridge::bleutils::registry::{BleDecoderRegistry, SENSOR_TOPIC_NAMES};
+use libfuzzer_sys::fuzz_target;
+
+// the advertisements of every format, decoded with and without key by the
+// decoders of every topic
+fuzz_target!(|adv: &[u8]| {
+    let registry = BleDecoderRegistry::default();
+
+    for topic_name in SENSOR_TOPIC_NAMES {
+        for token in [None, Some("231d39c1d7cc1ab1aee224cd096db932")] {
+            let _ = registry.decode(topic_name, "a4:c1:38:00:00:01", adv, token);
+        }
+    }
+
+    let _ = domo_wot_bridge::bleutils::parse_atc(
+        "a4:c1:38:00:00:01",
+        adv,
+        "231d39c1d7cc1ab1aee224cd096db932",
+    );
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use domo_wot_bridge::messages::BleBeaconMessage;
+use libfuzzer_sys::fuzz_target;
+
+// beacon_adv and valve_operation strings, and mac addresses, of the esp32
+fuzz_target!(|input: (&str, &str)| {
+    let (socket_string, actuator) = input;
+
+    let _ = BleBeaconMessage::from(socket_string, actuator);
+});
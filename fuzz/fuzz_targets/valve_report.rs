@@ -0,0 +1,7 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|payload: &str| {
+    let _ = domo_wot_bridge::bleutils::parse_valve_report(payload);
+});
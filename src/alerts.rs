@@ -0,0 +1,321 @@
+use crate::bleutils::registry::SENSOR_TOPIC_NAMES;
+use crate::dhtmanager::DHTManager;
+
+// Health of the BLE sensors. A sensor whose last_update_timestamp is older
+// than its stale_timeout_secs is marked "stale" in its topic, and a sensor
+// whose battery drops to its low_battery_threshold or critical_battery_threshold
+// (percent) raises an alert. The optional fields of the sensor topics are
+//
+// {
+//   "stale_timeout_secs": 3600,
+//   "low_battery_threshold": 20,
+//   "critical_battery_threshold": 10
+// }
+//
+// a stale_timeout_secs of 0 disabling the staleness check. The alerts are
+// written as domo_alert topics, whose uuid is the uuid of the sensor topic
+// followed by the alert type, e.g.
+//
+// {
+//   "alert_type": "low_battery",
+//   "severity": "warning",
+//   "active": true,
+//   "topic_name": "domo_ble_thermometer",
+//   "topic_uuid": "...",
+//   "name": "living room thermometer",
+//   "battery": 18,
+//   "last_update_timestamp": 1700000000000,
+//   "timestamp": 1700000060000
+// }
+//
+// An alert is written when it is raised, when its severity changes and, with
+// "active": false, when it is cleared.
+
+pub const ALERT_TOPIC_NAME: &str = "domo_alert";
+
+// the thermometers are the default source of the climate zones, whose
+// temperatures are too old after DEFAULT_MAX_TEMPERATURE_AGE_SECS
+const DEFAULT_THERMOMETER_STALE_TIMEOUT_SECS: u64 = 1800;
+
+const DEFAULT_STALE_TIMEOUT_SECS: u64 = 3 * 3600;
+
+// the buttons advertise only when pressed
+const DEFAULT_BUTTON_STALE_TIMEOUT_SECS: u64 = 0;
+
+const DEFAULT_LOW_BATTERY_THRESHOLD: u64 = 20;
+
+const DEFAULT_CRITICAL_BATTERY_THRESHOLD: u64 = 10;
+
+// a battery alert is cleared only above its threshold plus this, the battery
+// level of most sensors goes up and down by a few percent with temperature
+const BATTERY_HYSTERESIS: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "warning" => Some(Severity::Warning),
+            "critical" => Some(Severity::Critical),
+            _ => None,
+        }
+    }
+}
+
+fn stale_timeout_secs(topic_name: &str, value: &serde_json::Value) -> u64 {
+    value["stale_timeout_secs"]
+        .as_u64()
+        .unwrap_or(match topic_name {
+            "domo_ble_thermometer" => DEFAULT_THERMOMETER_STALE_TIMEOUT_SECS,
+            "domo_ble_button" => DEFAULT_BUTTON_STALE_TIMEOUT_SECS,
+            _ => DEFAULT_STALE_TIMEOUT_SECS,
+        })
+}
+
+// sensors that never reported are stale once the bridge has been running for
+// their timeout
+fn is_stale(topic_name: &str, value: &serde_json::Value, started_ms: u64, now_ms: u64) -> bool {
+    let timeout_ms = stale_timeout_secs(topic_name, value) * 1000;
+
+    if timeout_ms == 0 {
+        return false;
+    }
+
+    let last_update = value["last_update_timestamp"]
+        .as_u64()
+        .unwrap_or(0)
+        .max(started_ms);
+
+    now_ms.saturating_sub(last_update) > timeout_ms
+}
+
+fn battery_severity(value: &serde_json::Value, previous: Option<Severity>) -> Option<Severity> {
+    let battery = value["battery"].as_u64()?;
+
+    let low = value["low_battery_threshold"]
+        .as_u64()
+        .unwrap_or(DEFAULT_LOW_BATTERY_THRESHOLD);
+    let critical = value["critical_battery_threshold"]
+        .as_u64()
+        .unwrap_or(DEFAULT_CRITICAL_BATTERY_THRESHOLD);
+
+    if battery <= critical
+        || (previous == Some(Severity::Critical) && battery <= critical + BATTERY_HYSTERESIS)
+    {
+        Some(Severity::Critical)
+    } else if battery <= low || (previous.is_some() && battery <= low + BATTERY_HYSTERESIS) {
+        Some(Severity::Warning)
+    } else {
+        None
+    }
+}
+
+pub struct BleHealthMonitor {
+    started_ms: u64,
+}
+
+impl BleHealthMonitor {
+    pub fn new() -> Self {
+        BleHealthMonitor {
+            started_ms: sifis_dht::utils::get_epoch_ms() as u64,
+        }
+    }
+
+    pub async fn check(&mut self, dht_manager: &mut DHTManager) {
+        let now_ms = sifis_dht::utils::get_epoch_ms() as u64;
+
+        for topic_name in SENSOR_TOPIC_NAMES {
+            let topics = match dht_manager.cache.get_topic_name(topic_name) {
+                Ok(topics) => topics,
+                Err(_) => continue,
+            };
+
+            let topics = match topics.as_array() {
+                Some(topics) => topics.to_owned(),
+                None => continue,
+            };
+
+            for topic in topics {
+                let topic_uuid = match topic["topic_uuid"].as_str() {
+                    Some(uuid) => uuid,
+                    None => continue,
+                };
+
+                let value = &topic["value"];
+
+                let stale = is_stale(topic_name, value, self.started_ms, now_ms);
+
+                if value["stale"].as_bool().unwrap_or(false) != stale {
+                    let mut new_value = value.clone();
+                    new_value["stale"] = serde_json::json!(stale);
+
+                    dht_manager
+                        .write_topic(topic_name, topic_uuid, &new_value)
+                        .await;
+                }
+
+                let stale_severity = if stale { Some(Severity::Warning) } else { None };
+                self.update_alert(dht_manager, topic_name, topic_uuid, value, "stale", |_| {
+                    stale_severity
+                })
+                .await;
+
+                self.update_alert(
+                    dht_manager,
+                    topic_name,
+                    topic_uuid,
+                    value,
+                    "low_battery",
+                    |previous| battery_severity(value, previous),
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn update_alert(
+        &self,
+        dht_manager: &mut DHTManager,
+        topic_name: &str,
+        topic_uuid: &str,
+        value: &serde_json::Value,
+        alert_type: &str,
+        severity: impl Fn(Option<Severity>) -> Option<Severity>,
+    ) {
+        let alert_uuid = format!("{}_{}", topic_uuid, alert_type);
+
+        let alert = dht_manager
+            .cache
+            .get_topic_uuid(ALERT_TOPIC_NAME, &alert_uuid)
+            .map(|alert| alert["value"].clone())
+            .unwrap_or_default();
+
+        let previous = if alert["active"].as_bool().unwrap_or(false) {
+            alert["severity"].as_str().and_then(Severity::from_name)
+        } else {
+            None
+        };
+
+        let severity = severity(previous);
+
+        if severity == previous {
+            return;
+        }
+
+        log::info!(
+            "BLE ALERT {} {} {} {}",
+            alert_type,
+            severity.map(|s| s.name()).unwrap_or("cleared"),
+            topic_name,
+            value["name"].as_str().unwrap_or(topic_uuid)
+        );
+
+        let alert = serde_json::json!({
+            "alert_type": alert_type,
+            "severity": severity.or(previous).map(|s| s.name()),
+            "active": severity.is_some(),
+            "topic_name": topic_name,
+            "topic_uuid": topic_uuid,
+            "name": value["name"],
+            "battery": value["battery"],
+            "last_update_timestamp": value["last_update_timestamp"],
+            "timestamp": sifis_dht::utils::get_epoch_ms() as u64
+        });
+
+        dht_manager
+            .write_topic(ALERT_TOPIC_NAME, &alert_uuid, &alert)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale() {
+        let started = 1_000_000;
+        let value = serde_json::json!({ "last_update_timestamp": 2_000_000 });
+
+        assert!(!is_stale(
+            "domo_ble_thermometer",
+            &value,
+            started,
+            2_000_000 + 1_800_000
+        ));
+        assert!(is_stale(
+            "domo_ble_thermometer",
+            &value,
+            started,
+            2_000_000 + 1_800_001
+        ));
+        assert!(!is_stale(
+            "domo_ble_contact",
+            &value,
+            started,
+            2_000_000 + 1_800_001
+        ));
+        assert!(!is_stale("domo_ble_button", &value, started, u64::MAX));
+
+        // never reported since the start of the bridge
+        let value = serde_json::json!({ "stale_timeout_secs": 60 });
+        assert!(!is_stale(
+            "domo_ble_sensor",
+            &value,
+            started,
+            started + 60_000
+        ));
+        assert!(is_stale(
+            "domo_ble_sensor",
+            &value,
+            started,
+            started + 60_001
+        ));
+    }
+
+    #[test]
+    fn test_battery_severity() {
+        let battery = |b: u64| serde_json::json!({ "battery": b });
+
+        assert_eq!(battery_severity(&battery(50), None), None);
+        assert_eq!(
+            battery_severity(&battery(20), None),
+            Some(Severity::Warning)
+        );
+        assert_eq!(
+            battery_severity(&battery(10), None),
+            Some(Severity::Critical)
+        );
+
+        // cleared only with hysteresis
+        assert_eq!(
+            battery_severity(&battery(22), Some(Severity::Warning)),
+            Some(Severity::Warning)
+        );
+        assert_eq!(
+            battery_severity(&battery(26), Some(Severity::Warning)),
+            None
+        );
+        assert_eq!(
+            battery_severity(&battery(12), Some(Severity::Critical)),
+            Some(Severity::Critical)
+        );
+        assert_eq!(
+            battery_severity(&battery(16), Some(Severity::Critical)),
+            Some(Severity::Warning)
+        );
+
+        assert_eq!(battery_severity(&serde_json::json!({}), None), None);
+    }
+}
@@ -0,0 +1,292 @@
+use crate::command_parser;
+use crate::macaddress::MacAddress;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Direct bindings between the input of an actuator and the output of another
+// one, stored in the DHT as domo_input_binding topics, e.g.
+//
+// {
+//   "source_mac_address": "aa:bb:cc:dd:ee:01",
+//   "source_input": 1,
+//   "target_mac_address": "aa:bb:cc:dd:ee:02",
+//   "target_output": 2,
+//   "action": "toggle",
+//   "edge": "rising"
+// }
+//
+// The mac addresses may be in any format. The bindings are evaluated as soon
+// as the input update is received from the actuator, without waiting for the
+// DHT round trip.
+
+pub const BINDING_TOPIC_NAME: &str = "domo_input_binding";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BindingAction {
+    Toggle,
+    On,
+    Off,
+    Follow,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BindingEdge {
+    Rising,
+    Falling,
+    Both,
+}
+
+fn default_action() -> BindingAction {
+    BindingAction::Toggle
+}
+
+fn default_edge() -> BindingEdge {
+    BindingEdge::Rising
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InputBinding {
+    pub source_mac_address: String,
+    pub source_input: u64,
+    pub target_mac_address: String,
+    pub target_output: u64,
+    #[serde(default = "default_action")]
+    pub action: BindingAction,
+    #[serde(default = "default_edge")]
+    pub edge: BindingEdge,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+pub struct InputBindings {
+    index: HashMap<MacAddress, Vec<InputBinding>>,
+    // last known output states, key is mac address and channel
+    output_states: HashMap<(MacAddress, u64), bool>,
+}
+
+fn as_bool(value: &serde_json::Value) -> Option<bool> {
+    if let Some(b) = value.as_bool() {
+        return Some(b);
+    }
+    value.as_u64().map(|v| v != 0)
+}
+
+impl InputBindings {
+    pub fn new() -> Self {
+        InputBindings {
+            index: HashMap::new(),
+            output_states: HashMap::new(),
+        }
+    }
+
+    pub fn build_index(&mut self, binding_topics: &serde_json::Value) {
+        self.index.clear();
+
+        if let Some(topics) = binding_topics.as_array() {
+            for topic in topics {
+                match serde_json::from_value::<InputBinding>(topic["value"].clone()) {
+                    Ok(binding) => {
+                        if !binding.enabled {
+                            continue;
+                        }
+
+                        match (
+                            binding.source_mac_address.parse::<MacAddress>(),
+                            binding.target_mac_address.parse::<MacAddress>(),
+                        ) {
+                            (Ok(source), Ok(_)) => {
+                                self.index.entry(source).or_default().push(binding);
+                            }
+                            _ => log::warn!(
+                                "invalid input binding {}: err_mac_address",
+                                topic["topic_uuid"]
+                            ),
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("invalid input binding {}: {}", topic["topic_uuid"], e);
+                    }
+                }
+            }
+        }
+
+        log::debug!("INPUT BINDINGS INDEX {}", self.index.len());
+    }
+
+    pub fn update_output_states(&mut self, mac_address: &str, status: &serde_json::Value) {
+        let mac_address = match mac_address.parse::<MacAddress>() {
+            Ok(mac_address) => mac_address,
+            Err(_) => return,
+        };
+
+        if let Some(status) = status.as_object() {
+            for (key, value) in status {
+                if let Some(channel) = key.strip_prefix("output") {
+                    if let (Ok(channel), Some(value)) = (channel.parse::<u64>(), as_bool(value)) {
+                        self.output_states.insert((mac_address, channel), value);
+                    }
+                }
+            }
+        }
+    }
+
+    // returns the actuator commands triggered by the input changes in status
+    pub fn get_commands(
+        &mut self,
+        mac_address: &str,
+        status: &serde_json::Value,
+    ) -> Vec<serde_json::Value> {
+        let mut commands = vec![];
+
+        let bindings = match mac_address
+            .parse::<MacAddress>()
+            .ok()
+            .and_then(|mac_address| self.index.get(&mac_address))
+        {
+            Some(bindings) => bindings,
+            None => return commands,
+        };
+
+        let updated_props = match status["updated_properties"].as_array() {
+            Some(props) => props,
+            None => return commands,
+        };
+
+        for binding in bindings {
+            let input_key = "input".to_owned() + &binding.source_input.to_string();
+
+            if !updated_props.iter().any(|p| p == input_key.as_str()) {
+                continue;
+            }
+
+            let input_value = match as_bool(&status[&input_key]) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let edge_ok = match binding.edge {
+                BindingEdge::Rising => input_value,
+                BindingEdge::Falling => !input_value,
+                BindingEdge::Both => true,
+            };
+
+            if !edge_ok {
+                continue;
+            }
+
+            // checked when the index is built
+            let target = binding.target_mac_address.parse::<MacAddress>().unwrap();
+            let key = (target, binding.target_output);
+
+            let desired_state = match binding.action {
+                BindingAction::Toggle => !self.output_states.get(&key).copied().unwrap_or(false),
+                BindingAction::On => true,
+                BindingAction::Off => false,
+                BindingAction::Follow => input_value,
+            };
+
+            // optimistic update, so that fast repeated presses keep toggling
+            self.output_states.insert(key, desired_state);
+
+            commands.push(command_parser::set_output_command(
+                &serde_json::Value::String(binding.target_mac_address.clone()),
+                binding.target_output,
+                desired_state,
+            ));
+        }
+
+        commands
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toggle_binding() {
+        let mut bindings = InputBindings::new();
+
+        bindings.build_index(&serde_json::json!([{
+            "topic_uuid": "b1",
+            "value": {
+                "source_mac_address": "AA:BB:CC:DD:EE:01",
+                "source_input": 1,
+                "target_mac_address": "aa:bb:cc:dd:ee:02",
+                "target_output": 2
+            }
+        }]));
+
+        bindings.update_output_states("aa:bb:cc:dd:ee:02", &serde_json::json!({ "output2": true }));
+
+        let press = serde_json::json!({ "input1": true, "updated_properties": ["input1"] });
+        let release = serde_json::json!({ "input1": false, "updated_properties": ["input1"] });
+
+        let commands = bindings.get_commands("aa:bb:cc:dd:ee:01", &press);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0]["mac_address"], "aa:bb:cc:dd:ee:02");
+
+        let payload = commands[0]["shelly_action"]["input"]["action"]["action_payload"]
+            .as_str()
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["value"], false);
+        assert_eq!(payload["output_number"], 2);
+
+        assert!(bindings
+            .get_commands("aa:bb:cc:dd:ee:01", &release)
+            .is_empty());
+
+        let commands = bindings.get_commands("aa:bb:cc:dd:ee:01", &press);
+        let payload = commands[0]["shelly_action"]["input"]["action"]["action_payload"]
+            .as_str()
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["value"], true);
+    }
+
+    #[test]
+    fn test_mac_address_formats() {
+        let mut bindings = InputBindings::new();
+
+        bindings.build_index(&serde_json::json!([{
+            "topic_uuid": "b1",
+            "value": {
+                "source_mac_address": "AABBCCDDEE01",
+                "source_input": 1,
+                "target_mac_address": "aa-bb-cc-dd-ee-02",
+                "target_output": 1,
+                "action": "toggle"
+            }
+        }, {
+            "topic_uuid": "b2",
+            "value": {
+                "source_mac_address": "not a mac",
+                "source_input": 1,
+                "target_mac_address": "aa:bb:cc:dd:ee:02",
+                "target_output": 1
+            }
+        }]));
+
+        assert_eq!(bindings.index.len(), 1);
+
+        bindings.update_output_states("AA:BB:CC:DD:EE:02", &serde_json::json!({ "output1": true }));
+
+        let press = serde_json::json!({ "input1": true, "updated_properties": ["input1"] });
+
+        let commands = bindings.get_commands("aa:bb:cc:dd:ee:01", &press);
+        assert_eq!(commands.len(), 1);
+
+        let payload = commands[0]["shelly_action"]["input"]["action"]["action_payload"]
+            .as_str()
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["value"], false);
+    }
+}
@@ -0,0 +1,293 @@
+use super::error::BleError;
+use super::registry::BleDecoder;
+use super::replay::FrameCounter;
+use super::{BleReading, ButtonEvent, DecodedAdvertisement};
+use crate::macaddress::MacAddress;
+use aead::{generic_array::GenericArray, Aead, KeyInit, Payload};
+use ccm::{
+    consts::{U13, U4},
+    Ccm,
+};
+
+// BTHome v2 advertisements (https://bthome.io/format/), service data with
+// uuid 0xfcd2 made of a device information byte followed by the objects,
+// each one an object id and a little endian value.
+//
+// When bit 0 of the device information is set the objects are encrypted
+// with AES-CCM, the key being the "token" of the topic, and followed by a
+// 4 bytes counter and a 4 bytes MIC. The nonce is the mac address, the uuid,
+// the device information and the counter.
+
+pub const BTHOME_UUID: u16 = 0xfcd2;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct BthomeResult {
+    pub packet_id: Option<u8>,
+    // counter of the encrypted advertisements
+    pub counter: Option<u32>,
+    pub readings: Vec<BleReading>,
+}
+
+pub fn is_bthome(adv: &[u8]) -> bool {
+    super::find_service_data(adv, BTHOME_UUID).is_some()
+}
+
+fn decrypt_bthome(
+    mac: &str,
+    device_info: u8,
+    data: &[u8],
+    key: &str,
+) -> Result<(Vec<u8>, u32), BleError> {
+    let key = super::key_bytes(key)?;
+
+    // counter and MIC
+    if data.len() < 8 {
+        return Err(BleError::TooShort("bthome"));
+    }
+
+    let (payload, trailer) = data.split_at(data.len() - 8);
+    let counter = &trailer[0..4];
+    let mic = &trailer[4..8];
+
+    let mut nonce = mac.parse::<MacAddress>()?.bytes().to_vec();
+    nonce.extend_from_slice(&BTHOME_UUID.to_le_bytes());
+    nonce.push(device_info);
+    nonce.extend_from_slice(counter);
+
+    let mut msg = payload.to_vec();
+    msg.extend_from_slice(mic);
+
+    type Cipher = Ccm<aes::Aes128, U4, U13>;
+    let c = Cipher::new(GenericArray::from_slice(&key));
+
+    let res = c
+        .decrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                aad: &[],
+                msg: &msg,
+            },
+        )
+        .map_err(|_| BleError::DecryptionFailed("bthome"))?;
+
+    Ok((
+        res,
+        u32::from_le_bytes([counter[0], counter[1], counter[2], counter[3]]),
+    ))
+}
+
+// size of the value of the objects, None for the ones not in the v2 format
+fn object_len(object_id: u8) -> Option<usize> {
+    let len = match object_id {
+        0x00 | 0x01 | 0x09 | 0x0f..=0x11 | 0x15..=0x2f | 0x3a | 0x46 | 0x57..=0x5a | 0x60 => 1,
+        0x02 | 0x03 | 0x06..=0x08 | 0x0c..=0x0e | 0x12..=0x14 | 0x3c | 0x3d | 0x3f..=0x41 => 2,
+        0x43..=0x45 | 0x47..=0x4a | 0x51 | 0x52 | 0x56 | 0x5d..=0x5f | 0xf0 => 2,
+        0x04 | 0x05 | 0x0a | 0x0b | 0x42 | 0x4b | 0xf2 => 3,
+        0x3e | 0x4c..=0x50 | 0x55 | 0x5b | 0x5c | 0xf1 => 4,
+        _ => return None,
+    };
+
+    Some(len)
+}
+
+fn unsigned(value: &[u8]) -> u64 {
+    value
+        .iter()
+        .rev()
+        .fold(0, |acc, byte| (acc << 8) | *byte as u64)
+}
+
+fn signed(value: &[u8]) -> i64 {
+    let bits = value.len() * 8;
+    let raw = unsigned(value);
+
+    if raw & (1 << (bits - 1)) != 0 {
+        raw as i64 - (1 << bits)
+    } else {
+        raw as i64
+    }
+}
+
+fn button_event(value: u64) -> Option<ButtonEvent> {
+    match value {
+        0x01 => Some(ButtonEvent::Press),
+        0x02 => Some(ButtonEvent::DoublePress),
+        0x03 => Some(ButtonEvent::TriplePress),
+        0x04 => Some(ButtonEvent::LongPress),
+        0x05 => Some(ButtonEvent::LongDoublePress),
+        0x06 => Some(ButtonEvent::LongTriplePress),
+        0x80 => Some(ButtonEvent::HoldPress),
+        _ => None,
+    }
+}
+
+fn parse_objects(data: &[u8], result: &mut BthomeResult) -> Result<(), BleError> {
+    let mut i = 0;
+
+    while i < data.len() {
+        let object_id = data[i];
+        i += 1;
+
+        // text and raw objects start with their length
+        let len = match object_id {
+            0x53 | 0x54 => {
+                let len = *data.get(i).ok_or(BleError::TooShort("bthome"))? as usize;
+                i += 1;
+                len
+            }
+            _ => {
+                object_len(object_id).ok_or(BleError::UnknownObject("bthome", object_id as u16))?
+            }
+        };
+
+        let value = data.get(i..i + len).ok_or(BleError::TooShort("bthome"))?;
+        i += len;
+
+        let reading = match object_id {
+            0x00 => {
+                result.packet_id = Some(value[0]);
+                None
+            }
+            0x01 => Some(BleReading::Battery(unsigned(value))),
+            0x02 => Some(BleReading::Temperature(signed(value) as f64 / 100.0)),
+            0x45 => Some(BleReading::Temperature(signed(value) as f64 / 10.0)),
+            0x57 => Some(BleReading::Temperature(signed(value) as f64)),
+            0x03 => Some(BleReading::Humidity(unsigned(value) as f64 / 100.0)),
+            0x2e => Some(BleReading::Humidity(unsigned(value) as f64)),
+            0x04 => Some(BleReading::Pressure(unsigned(value) as f64 / 100.0)),
+            0x05 => Some(BleReading::Illuminance(unsigned(value) as f64 / 100.0)),
+            // motion and occupancy
+            0x21 | 0x23 => Some(BleReading::Motion(value[0] != 0)),
+            // door, opening and window
+            0x1a | 0x11 | 0x2d => Some(BleReading::Door(value[0] != 0)),
+            0x3a => button_event(unsigned(value)).map(BleReading::Button),
+            _ => None,
+        };
+
+        if let Some(reading) = reading {
+            result.readings.push(reading);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn parse_bthome(mac: &str, adv: &[u8], key: Option<&str>) -> Result<BthomeResult, BleError> {
+    let data = super::find_service_data(adv, BTHOME_UUID).ok_or(BleError::NotFound("bthome"))?;
+
+    let device_info = *data.first().ok_or(BleError::TooShort("bthome"))?;
+
+    if device_info >> 5 != 2 {
+        return Err(BleError::Unsupported("bthome", "version"));
+    }
+
+    let mut result = BthomeResult::default();
+
+    if device_info & 0x01 != 0 {
+        let key = key.ok_or(BleError::MissingToken("bthome"))?;
+        let (objects, counter) = decrypt_bthome(mac, device_info, &data[1..], key)?;
+        result.counter = Some(counter);
+        parse_objects(&objects, &mut result)?;
+    } else {
+        parse_objects(&data[1..], &mut result)?;
+    }
+
+    Ok(result)
+}
+
+pub struct BthomeDecoder;
+
+impl BleDecoder for BthomeDecoder {
+    fn name(&self) -> &'static str {
+        "bthome"
+    }
+
+    fn matches(&self, adv: &[u8]) -> bool {
+        is_bthome(adv)
+    }
+
+    fn decode(
+        &self,
+        mac_address: &str,
+        adv: &[u8],
+        token: Option<&str>,
+    ) -> Result<DecodedAdvertisement, BleError> {
+        let ret = parse_bthome(mac_address, adv, token)?;
+
+        Ok(DecodedAdvertisement {
+            readings: ret.readings,
+            counter: ret.counter.map(|c| FrameCounter::new(c as u64, 32)),
+            packet_id: ret.packet_id.map(u64::from),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bthome_plain() {
+        // packet id 9, battery 93%, 25.00 °C, 50.55 %, button press
+        let adv = hex::decode("0201061016d2fc400009015d02c40903bf133a01").unwrap();
+
+        let ret = parse_bthome("a4:c1:38:00:00:01", &adv, None).unwrap();
+
+        assert_eq!(ret.packet_id, Some(9));
+        assert_eq!(ret.counter, None);
+        assert_eq!(
+            ret.readings,
+            vec![
+                BleReading::Battery(93),
+                BleReading::Temperature(25.0),
+                BleReading::Humidity(50.55),
+                BleReading::Button(ButtonEvent::Press),
+            ]
+        );
+
+        // pressure 1008.83 hPa, 13460.67 lux, motion, door open
+        let adv = hex::decode("0201061016d2fc4004138a0105138a1421011a01").unwrap();
+        let ret = parse_bthome("a4:c1:38:00:00:01", &adv, None).unwrap();
+        assert_eq!(
+            ret.readings,
+            vec![
+                BleReading::Pressure(1008.83),
+                BleReading::Illuminance(13460.67),
+                BleReading::Motion(true),
+                BleReading::Door(true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bthome_encrypted() {
+        // example of the BTHome specification, 25.06 °C and 50.55 %
+        let mac = "54:48:e6:8f:80:a5";
+        let key = "231d39c1d7cc1ab1aee224cd096db932";
+        let adv = hex::decode("0201061216d2fc41a47266c95f730011223378237214").unwrap();
+
+        let ret = parse_bthome(mac, &adv, Some(key)).unwrap();
+
+        assert_eq!(ret.counter, Some(0x33221100));
+        assert_eq!(
+            ret.readings,
+            vec![BleReading::Temperature(25.06), BleReading::Humidity(50.55)]
+        );
+
+        assert!(parse_bthome(mac, &adv, None).is_err());
+        assert!(parse_bthome(mac, &adv, Some("00112233445566778899aabbccddeeff")).is_err());
+    }
+
+    #[test]
+    fn test_bthome_truncated() {
+        let adv = hex::decode("0201060716d2fc4002c409").unwrap();
+        assert!(parse_bthome("a4:c1:38:00:00:01", &adv, None).is_ok());
+
+        let adv = hex::decode("0201060616d2fc4002c4").unwrap();
+        assert!(parse_bthome("a4:c1:38:00:00:01", &adv, None).is_err());
+
+        // unknown object id
+        let adv = hex::decode("0201060616d2fc40ee01").unwrap();
+        assert!(parse_bthome("a4:c1:38:00:00:01", &adv, None).is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use crate::macaddress::MacAddress;
+use crate::messages::BleBeaconMessage;
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+// Every ESP32 acting as a scanner forwards the advertisements it receives, so
+// the same advertisement usually arrives once per scanner in a short time.
+// Only the first one is decoded and written to the DHT, the others are still
+// used for their RSSI.
+
+const DEDUP_WINDOW_MS: u64 = 2000;
+
+pub struct BeaconDeduplicator {
+    window: Duration,
+    // (device mac address, payload) and first reception
+    seen: HashMap<(MacAddress, String), Instant>,
+}
+
+impl BeaconDeduplicator {
+    pub fn new() -> Self {
+        BeaconDeduplicator {
+            window: Duration::from_millis(DEDUP_WINDOW_MS),
+            seen: HashMap::new(),
+        }
+    }
+
+    // returns true for the first reception of the advertisement in the window
+    pub fn observe(&mut self, message: &BleBeaconMessage) -> bool {
+        self.observe_at(message, Instant::now())
+    }
+
+    fn observe_at(&mut self, message: &BleBeaconMessage, now: Instant) -> bool {
+        let window = self.window;
+        self.seen
+            .retain(|_, first| now.saturating_duration_since(*first) < window);
+
+        // the scanners send valid mac addresses, nothing to deduplicate
+        let mac_address = match message.mac_address.parse::<MacAddress>() {
+            Ok(mac_address) => mac_address,
+            Err(_) => return true,
+        };
+
+        let key = (mac_address, message.payload.clone());
+
+        if self.seen.contains_key(&key) {
+            return false;
+        }
+
+        self.seen.insert(key, now);
+        true
+    }
+}
+
+impl Default for BeaconDeduplicator {
+    fn default() -> Self {
+        BeaconDeduplicator::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::beacon;
+
+    const DEVICE: &str = "a4:c1:38:00:00:01";
+
+    #[test]
+    fn test_dedup_window() {
+        let mut dedup = BeaconDeduplicator::new();
+        let start = Instant::now();
+
+        assert!(dedup.observe_at(&beacon("aa:aa:aa:aa:aa:01", DEVICE, "AgEG", -70), start));
+        assert!(!dedup.observe_at(
+            &beacon("aa:aa:aa:aa:aa:02", DEVICE, "AgEG", -60),
+            start + Duration::from_millis(300)
+        ));
+        assert!(dedup.observe_at(
+            &beacon("aa:aa:aa:aa:aa:02", DEVICE, "AgEH", -60),
+            start + Duration::from_millis(300)
+        ));
+
+        // whatever the format of the mac address
+        assert!(!dedup.observe_at(
+            &beacon("aa:aa:aa:aa:aa:03", "A4C138000001", "AgEH", -65),
+            start + Duration::from_millis(400)
+        ));
+        assert!(dedup.observe_at(
+            &beacon("aa:aa:aa:aa:aa:01", DEVICE, "AgEG", -70),
+            start + Duration::from_millis(2500)
+        ));
+    }
+}
@@ -0,0 +1,47 @@
+use crate::macaddress::InvalidMacAddress;
+use std::error::Error;
+use std::fmt;
+
+// Errors of the BLE parsers. The format is the name of the decoder, so that
+// the errors of the different formats can be told apart in the logs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BleError {
+    // the advertisement doesn't contain data of the format
+    NotFound(&'static str),
+    // e.g. the version or the length of the data
+    Unsupported(&'static str, &'static str),
+    TooShort(&'static str),
+    UnknownObject(&'static str, u16),
+    InvalidMacAddress,
+    // the key is not 16 bytes of hex
+    InvalidToken,
+    // encrypted advertisement of a device without key
+    MissingToken(&'static str),
+    // wrong key, or corrupted advertisement
+    DecryptionFailed(&'static str),
+}
+
+impl fmt::Display for BleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BleError::NotFound(format) => write!(f, "{}_not_found", format),
+            BleError::Unsupported(format, what) => write!(f, "{}_unsupported_{}", format, what),
+            BleError::TooShort(format) => write!(f, "{}_packet_too_short", format),
+            BleError::UnknownObject(format, id) => {
+                write!(f, "{}_unknown_object_{:#06x}", format, id)
+            }
+            BleError::InvalidMacAddress => write!(f, "err_mac_address"),
+            BleError::InvalidToken => write!(f, "err_token"),
+            BleError::MissingToken(format) => write!(f, "{}_missing_token", format),
+            BleError::DecryptionFailed(format) => write!(f, "{}_decryption_failed", format),
+        }
+    }
+}
+
+impl Error for BleError {}
+
+impl From<InvalidMacAddress> for BleError {
+    fn from(_: InvalidMacAddress) -> Self {
+        BleError::InvalidMacAddress
+    }
+}
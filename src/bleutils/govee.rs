@@ -0,0 +1,106 @@
+use super::error::BleError;
+use super::registry::BleDecoder;
+use super::{BleReading, DecodedAdvertisement};
+
+// Govee thermometers, manufacturer data with company id 0xec88:
+//
+//   H5072/H5075 (6 bytes): 0x00, temperature and humidity packed in a big
+//   endian u24 as temperature * 10000 + humidity * 10, the top bit being the
+//   sign of the temperature, battery (u8, %), 0x00
+//
+//   H5074 (7 bytes): 0x00, temperature (i16, 0.01 °C), humidity
+//   (u16, 0.01 %), battery (u8, %), 0x02, little endian
+
+pub const GOVEE_COMPANY_ID: u16 = 0xec88;
+
+const H5075_LEN: usize = 6;
+const H5074_LEN: usize = 7;
+
+pub fn parse_govee(data: &[u8]) -> Result<DecodedAdvertisement, BleError> {
+    let readings = match data.len() {
+        H5075_LEN => {
+            let packed = u32::from_be_bytes([0, data[1], data[2], data[3]]);
+            let value = packed & 0x7fffff;
+
+            let temperature = (value / 1000) as f64 / 10.0;
+            let temperature = if packed & 0x800000 != 0 {
+                -temperature
+            } else {
+                temperature
+            };
+
+            vec![
+                BleReading::Temperature(temperature),
+                BleReading::Humidity((value % 1000) as f64 / 10.0),
+                BleReading::Battery(data[4] as u64),
+            ]
+        }
+        H5074_LEN => vec![
+            BleReading::Temperature(i16::from_le_bytes([data[1], data[2]]) as f64 / 100.0),
+            BleReading::Humidity(u16::from_le_bytes([data[3], data[4]]) as f64 / 100.0),
+            BleReading::Battery(data[5] as u64),
+        ],
+        _ => return Err(BleError::Unsupported("govee", "format")),
+    };
+
+    Ok(DecodedAdvertisement {
+        readings,
+        counter: None,
+        packet_id: None,
+    })
+}
+
+pub struct GoveeDecoder;
+
+impl BleDecoder for GoveeDecoder {
+    fn name(&self) -> &'static str {
+        "govee"
+    }
+
+    fn matches(&self, adv: &[u8]) -> bool {
+        super::find_manufacturer_data(adv, GOVEE_COMPANY_ID).is_some()
+    }
+
+    fn decode(
+        &self,
+        _mac_address: &str,
+        adv: &[u8],
+        _token: Option<&str>,
+    ) -> Result<DecodedAdvertisement, BleError> {
+        let data = super::find_manufacturer_data(adv, GOVEE_COMPANY_ID)
+            .ok_or(BleError::NotFound("govee"))?;
+        parse_govee(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_govee() {
+        let adv = hex::decode("02010609ff88ec000393d86400").unwrap();
+
+        let ret = GoveeDecoder.decode("", &adv, None).unwrap();
+
+        assert_eq!(
+            ret.readings,
+            vec![
+                BleReading::Temperature(23.4),
+                BleReading::Humidity(45.6),
+                BleReading::Battery(100),
+            ]
+        );
+
+        let ret = parse_govee(&hex::decode("0080cc563200").unwrap()).unwrap();
+        assert_eq!(ret.readings[0], BleReading::Temperature(-5.2));
+        assert_eq!(ret.readings[1], BleReading::Humidity(31.0));
+
+        // H5074
+        let ret = parse_govee(&hex::decode("00290919113702").unwrap()).unwrap();
+        assert_eq!(ret.readings[0], BleReading::Temperature(23.45));
+        assert_eq!(ret.readings[1], BleReading::Humidity(43.77));
+
+        assert!(parse_govee(&[0x00, 0x01]).is_err());
+    }
+}
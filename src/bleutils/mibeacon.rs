@@ -0,0 +1,339 @@
+use super::error::BleError;
+use super::registry::BleDecoder;
+use super::replay::FrameCounter;
+use super::{BleReading, ButtonEvent, DecodedAdvertisement};
+use crate::macaddress::MacAddress;
+use aead::{generic_array::GenericArray, Aead, KeyInit, Payload};
+use ccm::{
+    consts::{U12, U4},
+    Ccm,
+};
+
+// Xiaomi MiBeacon advertisements, service data with uuid 0xfe95:
+//
+//   frame control (2 bytes), product id (2), frame counter (1),
+//   mac address (6, reversed) if bit 4 of the frame control is set,
+//   capability (1, plus 2 bytes of io capability) if bit 5 is set,
+//   objects if bit 6 is set
+//
+// The version is in the upper 4 bits of the frame control. Each object is an
+// object id (2 bytes), a length (1) and a little endian value. When bit 3 is
+// set the objects of v4/v5 frames are encrypted with AES-CCM, the key being
+// the "token" of the topic, and followed by a 3 bytes extended counter and a
+// 4 bytes MIC. The legacy encryption of v2/v3 frames is not supported.
+
+pub const MIBEACON_UUID: u16 = 0xfe95;
+
+const FRAME_ENCRYPTED: u16 = 0x0008;
+const FRAME_MAC_ADDRESS: u16 = 0x0010;
+const FRAME_CAPABILITY: u16 = 0x0020;
+const FRAME_OBJECTS: u16 = 0x0040;
+
+const CAPABILITY_IO: u8 = 0x20;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MiBeaconResult {
+    pub version: u8,
+    pub encrypted: bool,
+    pub product_id: u16,
+    pub frame_counter: u8,
+    // extended counter of the encrypted frames
+    pub counter: Option<u32>,
+    // as advertised, e.g. "e4:aa:ec:53:9e:2b"
+    pub mac_address: Option<String>,
+    pub readings: Vec<BleReading>,
+}
+
+pub fn is_mibeacon(adv: &[u8]) -> bool {
+    super::find_service_data(adv, MIBEACON_UUID).is_some()
+}
+
+fn read<'a>(data: &'a [u8], i: &mut usize, len: usize) -> Result<&'a [u8], BleError> {
+    let value = data
+        .get(*i..*i + len)
+        .ok_or(BleError::TooShort("mibeacon"))?;
+    *i += len;
+    Ok(value)
+}
+
+fn unsigned(value: &[u8]) -> u64 {
+    value
+        .iter()
+        .rev()
+        .fold(0, |acc, byte| (acc << 8) | *byte as u64)
+}
+
+fn signed16(value: &[u8]) -> f64 {
+    i16::from_le_bytes([value[0], value[1]]) as f64
+}
+
+fn decrypt_mibeacon(
+    mac_reversed: &[u8],
+    header: &[u8],
+    data: &[u8],
+    key: &str,
+) -> Result<(Vec<u8>, u32), BleError> {
+    let key = super::key_bytes(key)?;
+
+    // extended counter and MIC
+    if data.len() < 7 {
+        return Err(BleError::TooShort("mibeacon"));
+    }
+
+    let (payload, trailer) = data.split_at(data.len() - 7);
+    let ext_counter = &trailer[0..3];
+    let mic = &trailer[3..7];
+
+    // mac address, product id, frame counter and extended counter
+    let mut nonce = mac_reversed.to_vec();
+    nonce.extend_from_slice(&header[2..5]);
+    nonce.extend_from_slice(ext_counter);
+
+    let mut msg = payload.to_vec();
+    msg.extend_from_slice(mic);
+
+    type Cipher = Ccm<aes::Aes128, U4, U12>;
+    let c = Cipher::new(GenericArray::from_slice(&key));
+
+    let res = c
+        .decrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                aad: &[0x11],
+                msg: &msg,
+            },
+        )
+        .map_err(|_| BleError::DecryptionFailed("mibeacon"))?;
+
+    let counter = (unsigned(ext_counter) << 8) as u32 | header[4] as u32;
+
+    Ok((res, counter))
+}
+
+fn button_event(press_type: u8) -> Option<ButtonEvent> {
+    match press_type {
+        0x00 => Some(ButtonEvent::Press),
+        0x01 => Some(ButtonEvent::DoublePress),
+        0x02 => Some(ButtonEvent::LongPress),
+        0x03 => Some(ButtonEvent::TriplePress),
+        _ => None,
+    }
+}
+
+fn parse_object(object_id: u16, value: &[u8], readings: &mut Vec<BleReading>) {
+    let expect = |len: usize| value.len() >= len;
+
+    match object_id {
+        // button index (2 bytes) and press type
+        0x1001 if expect(3) => {
+            if let Some(event) = button_event(value[2]) {
+                readings.push(BleReading::Button(event));
+            }
+        }
+        0x1004 if expect(2) => readings.push(BleReading::Temperature(signed16(value) / 10.0)),
+        0x1006 if expect(2) => {
+            readings.push(BleReading::Humidity(unsigned(&value[0..2]) as f64 / 10.0))
+        }
+        0x100d if expect(4) => {
+            readings.push(BleReading::Temperature(signed16(value) / 10.0));
+            readings.push(BleReading::Humidity(unsigned(&value[2..4]) as f64 / 10.0));
+        }
+        0x1007 if expect(3) => {
+            readings.push(BleReading::Illuminance(unsigned(&value[0..3]) as f64))
+        }
+        0x100a if expect(1) => readings.push(BleReading::Battery(value[0] as u64)),
+        // motion detected, with the illuminance
+        0x000f if expect(3) => {
+            readings.push(BleReading::Motion(true));
+            readings.push(BleReading::Illuminance(unsigned(&value[0..3]) as f64));
+        }
+        // seconds without motion
+        0x1017 if expect(4) && unsigned(&value[0..4]) > 0 => {
+            readings.push(BleReading::Motion(false))
+        }
+        // weak (0) or strong (1) light
+        0x1018 if expect(1) => readings.push(BleReading::Light(value[0] != 0)),
+        // 0 open, 1 closed, 2 not closed after a timeout, 3 reset
+        0x1019 if expect(1) => match value[0] {
+            0 | 2 => readings.push(BleReading::Door(true)),
+            1 => readings.push(BleReading::Door(false)),
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+fn parse_objects(data: &[u8], readings: &mut Vec<BleReading>) -> Result<(), BleError> {
+    let mut i = 0;
+
+    while i < data.len() {
+        let object_id = unsigned(read(data, &mut i, 2)?) as u16;
+        let len = read(data, &mut i, 1)?[0] as usize;
+        let value = read(data, &mut i, len)?;
+
+        parse_object(object_id, value, readings);
+    }
+
+    Ok(())
+}
+
+pub fn parse_mibeacon(
+    mac: &str,
+    adv: &[u8],
+    key: Option<&str>,
+) -> Result<MiBeaconResult, BleError> {
+    let data =
+        super::find_service_data(adv, MIBEACON_UUID).ok_or(BleError::NotFound("mibeacon"))?;
+
+    let mut i = 0;
+    let header = read(data, &mut i, 5)?;
+    let frame_control = unsigned(&header[0..2]) as u16;
+
+    let mut result = MiBeaconResult {
+        version: (frame_control >> 12) as u8,
+        encrypted: frame_control & FRAME_ENCRYPTED != 0,
+        product_id: unsigned(&header[2..4]) as u16,
+        frame_counter: header[4],
+        ..Default::default()
+    };
+
+    if result.version < 2 {
+        return Err(BleError::Unsupported("mibeacon", "version"));
+    }
+
+    let mac_reversed = if frame_control & FRAME_MAC_ADDRESS != 0 {
+        let mac_reversed = read(data, &mut i, 6)?.to_vec();
+
+        result.mac_address = Some(MacAddress::from_reversed_bytes(&mac_reversed)?.to_string());
+
+        mac_reversed
+    } else {
+        mac.parse::<MacAddress>()?.reversed_bytes().to_vec()
+    };
+
+    if frame_control & FRAME_CAPABILITY != 0 {
+        let capability = read(data, &mut i, 1)?[0];
+        if capability & CAPABILITY_IO != 0 && result.version >= 5 {
+            read(data, &mut i, 2)?;
+        }
+    }
+
+    if frame_control & FRAME_OBJECTS == 0 {
+        return Ok(result);
+    }
+
+    let objects = &data[i..];
+
+    if result.encrypted {
+        if result.version < 4 {
+            return Err(BleError::Unsupported("mibeacon", "legacy_encryption"));
+        }
+
+        let key = key.ok_or(BleError::MissingToken("mibeacon"))?;
+        let (objects, counter) = decrypt_mibeacon(&mac_reversed, header, objects, key)?;
+        result.counter = Some(counter);
+        parse_objects(&objects, &mut result.readings)?;
+    } else {
+        parse_objects(objects, &mut result.readings)?;
+    }
+
+    Ok(result)
+}
+
+pub struct MiBeaconDecoder;
+
+impl BleDecoder for MiBeaconDecoder {
+    fn name(&self) -> &'static str {
+        "mibeacon"
+    }
+
+    fn matches(&self, adv: &[u8]) -> bool {
+        is_mibeacon(adv)
+    }
+
+    fn decode(
+        &self,
+        mac_address: &str,
+        adv: &[u8],
+        token: Option<&str>,
+    ) -> Result<DecodedAdvertisement, BleError> {
+        let ret = parse_mibeacon(mac_address, adv, token)?;
+
+        Ok(DecodedAdvertisement {
+            readings: ret.readings,
+            counter: ret.counter.map(|c| FrameCounter::new(c as u64, 32)),
+            packet_id: Some(ret.frame_counter as u64),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mibeacon_encrypted_door() {
+        // MCCGQ02HL door/window sensor
+        let mac = "e4:aa:ec:53:9e:2b";
+        let key = "6b1db353566f01c6d3585100b9d348f4";
+        let adv =
+            hex::decode("020106191695fe58588b09482b9e53ecaae46db81e190d00007d32b33c").unwrap();
+
+        let ret = parse_mibeacon(mac, &adv, Some(key)).unwrap();
+
+        assert_eq!(ret.version, 5);
+        assert!(ret.encrypted);
+        assert_eq!(ret.product_id, 0x098b);
+        assert_eq!(ret.mac_address.as_deref(), Some(mac));
+        assert_eq!(ret.counter, Some(0x0d48));
+        assert_eq!(ret.readings, vec![BleReading::Door(false)]);
+
+        assert!(parse_mibeacon(mac, &adv, None).is_err());
+    }
+
+    #[test]
+    fn test_contact_parse() {
+        // capture of a door sensor, the previous tests stored it with the
+        // length prefix and the rssi byte added by the bridge
+        let mac = "e4:aa:ec:53:9e:2b";
+        let key = "6b1db353566f01c6d3585100b9d348f4";
+        let data = "020106191695fe58588b09482b9e53ecaae46db81e190d00007d32b33c";
+
+        let adv = hex::decode(data).unwrap();
+        assert!(crate::bleutils::find_service_data(&adv, 0xfe95).is_some());
+
+        let ret = parse_mibeacon(mac, &adv, Some(key)).unwrap();
+        assert_eq!(ret.counter, Some(0x0d48));
+        assert_eq!(ret.readings, vec![BleReading::Door(false)]);
+    }
+
+    #[test]
+    fn test_mibeacon_plain() {
+        // temperature 22.0 and humidity 55.0
+        let adv = hex::decode("020106151695fe50505b0501ffeeddccbbaa0d1004dc002602").unwrap();
+
+        let ret = parse_mibeacon("aa:bb:cc:dd:ee:ff", &adv, None).unwrap();
+
+        assert_eq!(ret.version, 5);
+        assert!(!ret.encrypted);
+        assert_eq!(ret.frame_counter, 1);
+        assert_eq!(ret.mac_address.as_deref(), Some("aa:bb:cc:dd:ee:ff"));
+        assert_eq!(
+            ret.readings,
+            vec![BleReading::Temperature(22.0), BleReading::Humidity(55.0)]
+        );
+
+        // strong light, without the mac address
+        let adv = hex::decode("0201060c1695fe40508b090218100101").unwrap();
+        let ret = parse_mibeacon("aa:bb:cc:dd:ee:ff", &adv, None).unwrap();
+        assert_eq!(ret.readings, vec![BleReading::Light(true)]);
+
+        // legacy encryption
+        let adv = hex::decode("020106101695fe58308b0903ffeeddccbbaa0000").unwrap();
+        assert!(parse_mibeacon("aa:bb:cc:dd:ee:ff", &adv, Some("00")).is_err());
+
+        // truncated object
+        let adv = hex::decode("0201060c1695fe40508b090218100201").unwrap();
+        assert!(parse_mibeacon("aa:bb:cc:dd:ee:ff", &adv, None).is_err());
+    }
+}
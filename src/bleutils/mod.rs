@@ -0,0 +1,379 @@
+use crate::macaddress::MacAddress;
+use aead::{generic_array::GenericArray, Aead, KeyInit, Payload};
+use ccm::{
+    consts::{U11, U4},
+    Ccm,
+};
+use error::BleError;
+use hex_literal::hex;
+use replay::FrameCounter;
+
+pub mod bthome;
+pub mod dedup;
+pub mod error;
+pub mod govee;
+pub mod mibeacon;
+pub mod pvvx;
+pub mod registry;
+pub mod replay;
+pub mod ruuvi;
+
+#[derive(Debug)]
+pub struct AtcResult {
+    pub temperature: f32,
+    pub humidity: f32,
+    pub battery: f32,
+    // frame counter of the nonce
+    pub counter: Option<u8>,
+}
+
+// decoded measurements, shared by the decoders of the different advertisement
+// formats
+#[derive(Debug, Clone, PartialEq)]
+pub enum BleReading {
+    Temperature(f64),
+    Humidity(f64),
+    // hPa
+    Pressure(f64),
+    // lux
+    Illuminance(f64),
+    // strong light, for the sensors reporting only two levels
+    Light(bool),
+    Motion(bool),
+    Door(bool),
+    // percentage
+    Battery(u64),
+    // volts
+    BatteryVoltage(f64),
+    Button(ButtonEvent),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ButtonEvent {
+    Press,
+    DoublePress,
+    TriplePress,
+    LongPress,
+    LongDoublePress,
+    LongTriplePress,
+    HoldPress,
+}
+
+impl ButtonEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ButtonEvent::Press => "press",
+            ButtonEvent::DoublePress => "double_press",
+            ButtonEvent::TriplePress => "triple_press",
+            ButtonEvent::LongPress => "long_press",
+            ButtonEvent::LongDoublePress => "long_double_press",
+            ButtonEvent::LongTriplePress => "long_triple_press",
+            ButtonEvent::HoldPress => "hold_press",
+        }
+    }
+}
+
+// AES-128 key of the encrypted formats, the "token" of the topics
+pub fn key_bytes(token: &str) -> Result<[u8; 16], BleError> {
+    let key = hex::decode(token).map_err(|_| BleError::InvalidToken)?;
+
+    key.try_into().map_err(|_| BleError::InvalidToken)
+}
+
+// advertising data structures sent by the esp32, type and data, stops at the
+// first malformed one
+fn ad_structures(adv: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut structures = vec![];
+    let mut i = 0;
+
+    while i < adv.len() {
+        let len = adv[i] as usize;
+        if len == 0 || i + 1 + len > adv.len() {
+            break;
+        }
+
+        structures.push((adv[i + 1], &adv[i + 2..i + 1 + len]));
+        i += 1 + len;
+    }
+
+    structures
+}
+
+// returns the service data with the given 16 bit uuid, without the uuid
+pub fn find_service_data(adv: &[u8], uuid: u16) -> Option<&[u8]> {
+    ad_structures(adv)
+        .into_iter()
+        .find(|(ad_type, data)| {
+            *ad_type == 0x16 && data.len() >= 2 && u16::from_le_bytes([data[0], data[1]]) == uuid
+        })
+        .map(|(_, data)| &data[2..])
+}
+
+// returns the manufacturer specific data of the given company, without the
+// company id
+pub fn find_manufacturer_data(adv: &[u8], company_id: u16) -> Option<&[u8]> {
+    ad_structures(adv)
+        .into_iter()
+        .find(|(ad_type, data)| {
+            *ad_type == 0xff
+                && data.len() >= 2
+                && u16::from_le_bytes([data[0], data[1]]) == company_id
+        })
+        .map(|(_, data)| &data[2..])
+}
+
+// readings of an advertisement, with the counter of the encrypted formats
+// and the packet id of the plain ones identifying it when available
+#[derive(Debug, Default, PartialEq)]
+pub struct DecodedAdvertisement {
+    pub readings: Vec<BleReading>,
+    pub counter: Option<FrameCounter>,
+    pub packet_id: Option<u64>,
+}
+
+// writes the readings into the value of a domo_ble_* topic, the door state
+// uses the "status" convention of domo_ble_contact (1 closed, 0 open)
+pub fn write_readings(value: &mut serde_json::Value, readings: &[BleReading]) {
+    for reading in readings {
+        match reading {
+            BleReading::Temperature(t) => value["temperature"] = serde_json::json!(t),
+            BleReading::Humidity(h) => value["humidity"] = serde_json::json!(h),
+            BleReading::Pressure(p) => value["pressure"] = serde_json::json!(p),
+            BleReading::Illuminance(l) => value["illuminance"] = serde_json::json!(l),
+            BleReading::Light(l) => value["light"] = serde_json::json!(l),
+            BleReading::Motion(m) => value["motion"] = serde_json::json!(m),
+            BleReading::Door(open) => value["status"] = serde_json::json!(u64::from(!open)),
+            BleReading::Battery(b) => value["battery"] = serde_json::json!(b),
+            BleReading::BatteryVoltage(v) => value["battery_voltage"] = serde_json::json!(v),
+            BleReading::Button(event) => value["button_event"] = serde_json::json!(event.name()),
+        }
+    }
+}
+
+// status of a radiator valve as reported by the esp32 after a command, either
+// the legacy "0"/"1" or a json object with the optional position (0-100),
+// temperature and battery reported by the valve
+#[derive(Debug, PartialEq)]
+pub struct ValveReport {
+    pub status: bool,
+    pub position: Option<f64>,
+    pub temperature: Option<f64>,
+    pub battery: Option<u64>,
+}
+
+pub fn parse_valve_report(payload: &str) -> Option<ValveReport> {
+    if payload == "0" || payload == "1" {
+        return Some(ValveReport {
+            status: payload == "1",
+            position: None,
+            temperature: None,
+            battery: None,
+        });
+    }
+
+    if !payload.starts_with('{') {
+        return None;
+    }
+
+    let report: serde_json::Value = serde_json::from_str(payload).ok()?;
+
+    let position = report["position"].as_f64();
+
+    let status = match report["status"].as_bool() {
+        Some(status) => status,
+        None => position? > 0.0,
+    };
+
+    Some(ValveReport {
+        status,
+        position,
+        temperature: report["temperature"].as_f64(),
+        battery: report["battery"].as_u64(),
+    })
+}
+
+// the encrypted formats of the custom firmwares of the Xiaomi thermometers,
+// AES-CCM with a 4 bytes MIC, the 11 bytes nonce being the reversed mac
+// address followed by the first 5 bytes of the advertising data structure
+fn decrypt_atc(payload: &[u8], key: &[u8; 16], nonce: &[u8; 11]) -> Result<AtcResult, BleError> {
+    type Cipher = Ccm<aes::Aes128, U4, U11>;
+    let c = Cipher::new(GenericArray::from_slice(key));
+
+    let aad = hex!("11");
+
+    let res = c
+        .decrypt(
+            GenericArray::from_slice(nonce),
+            Payload {
+                aad: &aad,
+                msg: payload,
+            },
+        )
+        .map_err(|_| BleError::DecryptionFailed("atc"))?;
+
+    match res[..] {
+        // atc1441: temperature (0.5 °C, +40), humidity (0.5 %), battery (7 bits)
+        [temperature, humidity, battery] => Ok(AtcResult {
+            temperature: temperature as f32 / 2.0 - 40.0,
+            humidity: humidity as f32 / 2.0,
+            battery: (battery & 0x7f) as f32,
+            counter: None,
+        }),
+        // pvvx: temperature (0.01 °C), humidity (0.01 %), battery, flags
+        [t0, t1, h0, h1, battery, _] => Ok(AtcResult {
+            temperature: i16::from_le_bytes([t0, t1]) as f32 / 100.0,
+            humidity: u16::from_le_bytes([h0, h1]) as f32 / 100.0,
+            battery: battery as f32,
+            counter: None,
+        }),
+        _ => Err(BleError::Unsupported("atc", "format")),
+    }
+}
+
+pub fn parse_atc(mac: &str, adv: &[u8], token: &str) -> Result<AtcResult, BleError> {
+    let key = key_bytes(token)?;
+    let mac = mac.parse::<MacAddress>()?.reversed_bytes();
+
+    let data = find_service_data(adv, pvvx::ENVIRONMENTAL_SENSING_UUID)
+        .ok_or(BleError::NotFound("atc"))?;
+
+    // counter, at least one byte of payload and the MIC
+    if data.len() < 6 {
+        return Err(BleError::TooShort("atc"));
+    }
+
+    let counter = data[0];
+
+    // length, type and uuid of the structure, and counter
+    let header = [
+        (data.len() + 3) as u8,
+        0x16,
+        (pvvx::ENVIRONMENTAL_SENSING_UUID & 0xff) as u8,
+        (pvvx::ENVIRONMENTAL_SENSING_UUID >> 8) as u8,
+        counter,
+    ];
+
+    let mut nonce = [0; 11];
+    nonce[..6].copy_from_slice(&mac);
+    nonce[6..].copy_from_slice(&header);
+
+    let mut ret = decrypt_atc(&data[1..], &key, &nonce)?;
+    ret.counter = Some(counter);
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // encrypted advertisement of the custom firmwares, as sent by the device
+    fn encrypt_atc(mac: &str, key: &[u8; 16], counter: u8, payload: &[u8]) -> Vec<u8> {
+        let mut nonce = mac.parse::<MacAddress>().unwrap().reversed_bytes().to_vec();
+
+        let header = [payload.len() as u8 + 8, 0x16, 0x1a, 0x18, counter];
+        nonce.extend_from_slice(&header);
+
+        type Cipher = Ccm<aes::Aes128, U4, U11>;
+        let encrypted = Cipher::new(GenericArray::from_slice(key))
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    aad: &[0x11],
+                    msg: payload,
+                },
+            )
+            .unwrap();
+
+        [&[0x02, 0x01, 0x06][..], &header, &encrypted].concat()
+    }
+
+    #[test]
+    fn test_atc_parse() {
+        let mac = "a4:c1:38:00:00:01";
+        let key = [0x5a; 16];
+        let token = hex::encode(key);
+
+        // pvvx: 23.45 °C, 43.69 %, 80 %
+        let adv = encrypt_atc(mac, &key, 7, &[0x29, 0x09, 0x11, 0x11, 80, 0]);
+        let ret = parse_atc(mac, &adv, &token).unwrap();
+        assert_eq!(ret.temperature, 23.45);
+        assert_eq!(ret.humidity, 43.69);
+        assert_eq!(ret.battery, 80.0);
+        assert_eq!(ret.counter, Some(7));
+
+        // atc1441: 20 °C, 50 %, 75 %
+        let adv = encrypt_atc(mac, &key, 8, &[120, 100, 0x80 | 75]);
+        let ret = parse_atc(mac, &adv, &token).unwrap();
+        assert_eq!(ret.temperature, 20.0);
+        assert_eq!(ret.humidity, 50.0);
+        assert_eq!(ret.battery, 75.0);
+
+        assert_eq!(
+            parse_atc("a4:c1:38:00:00:02", &adv, &token).unwrap_err(),
+            BleError::DecryptionFailed("atc")
+        );
+        assert_eq!(
+            parse_atc(mac, &adv, "00").unwrap_err(),
+            BleError::InvalidToken
+        );
+        assert_eq!(
+            parse_atc("a4:c1:38", &adv, &token).unwrap_err(),
+            BleError::InvalidMacAddress
+        );
+        assert_eq!(
+            parse_atc(mac, &adv[..9], &token).unwrap_err(),
+            BleError::NotFound("atc")
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_parse_atc_never_panics(
+            adv in proptest::collection::vec(any::<u8>(), 0..64),
+            mac in "[0-9a-f:]{0,20}",
+            token in "[0-9a-f]{0,34}",
+        ) {
+            let _ = parse_atc(&mac, &adv, &token);
+            let _ = find_service_data(&adv, 0x181a);
+            let _ = find_manufacturer_data(&adv, 0x0499);
+        }
+
+        #[test]
+        fn prop_atc_roundtrip(
+            temperature in -4000i16..6000,
+            humidity in 0u16..10000,
+            battery in 0u8..=100,
+            counter in any::<u8>(),
+            key in any::<[u8; 16]>(),
+        ) {
+            let mac = "a4:c1:38:00:00:01";
+            let t = temperature.to_le_bytes();
+            let h = humidity.to_le_bytes();
+            let adv = encrypt_atc(mac, &key, counter, &[t[0], t[1], h[0], h[1], battery, 0]);
+
+            let ret = parse_atc(mac, &adv, &hex::encode(key)).unwrap();
+            prop_assert_eq!(ret.temperature, temperature as f32 / 100.0);
+            prop_assert_eq!(ret.humidity, humidity as f32 / 100.0);
+            prop_assert_eq!(ret.battery, battery as f32);
+            prop_assert_eq!(ret.counter, Some(counter));
+        }
+    }
+
+    #[test]
+    fn test_valve_report_parse() {
+        let report = parse_valve_report("1").unwrap();
+        assert!(report.status);
+        assert_eq!(report.position, None);
+
+        let report =
+            parse_valve_report(r#"{"position": 40, "temperature": 21.5, "battery": 87}"#).unwrap();
+        assert!(report.status);
+        assert_eq!(report.position, Some(40.0));
+        assert_eq!(report.battery, Some(87));
+
+        assert!(parse_valve_report("AgEGGxb").is_none());
+        assert!(parse_valve_report("{}").is_none());
+    }
+}
@@ -0,0 +1,167 @@
+use super::error::BleError;
+use super::registry::BleDecoder;
+use super::replay::FrameCounter;
+use super::{BleReading, DecodedAdvertisement};
+
+// Unencrypted formats of the custom firmwares of the Xiaomi thermometers,
+// service data with the environmental sensing uuid 0x181a:
+//
+//   pvvx (15 bytes): mac address (6, reversed), temperature (i16, 0.01 °C),
+//   humidity (u16, 0.01 %), battery mV (u16), battery (u8, %), counter (u8),
+//   flags (u8), little endian
+//
+//   atc1441 (13 bytes): mac address (6), temperature (i16, 0.1 °C),
+//   humidity (u8, %), battery (u8, %), battery mV (u16), counter (u8),
+//   big endian
+//
+// The encrypted formats are shorter and handled by parse_atc, through
+// AtcDecoder.
+
+pub const ENVIRONMENTAL_SENSING_UUID: u16 = 0x181a;
+
+const PVVX_LEN: usize = 15;
+const ATC1441_LEN: usize = 13;
+
+pub fn is_plain(data: &[u8]) -> bool {
+    data.len() == PVVX_LEN || data.len() == ATC1441_LEN
+}
+
+pub fn parse_pvvx(data: &[u8]) -> Result<DecodedAdvertisement, BleError> {
+    let (readings, counter) = match data.len() {
+        PVVX_LEN => (
+            vec![
+                BleReading::Temperature(i16::from_le_bytes([data[6], data[7]]) as f64 / 100.0),
+                BleReading::Humidity(u16::from_le_bytes([data[8], data[9]]) as f64 / 100.0),
+                BleReading::Battery(data[12] as u64),
+                BleReading::BatteryVoltage(
+                    u16::from_le_bytes([data[10], data[11]]) as f64 / 1000.0,
+                ),
+            ],
+            data[13],
+        ),
+        ATC1441_LEN => (
+            vec![
+                BleReading::Temperature(i16::from_be_bytes([data[6], data[7]]) as f64 / 10.0),
+                BleReading::Humidity(data[8] as f64),
+                BleReading::Battery(data[9] as u64),
+                BleReading::BatteryVoltage(
+                    u16::from_be_bytes([data[10], data[11]]) as f64 / 1000.0,
+                ),
+            ],
+            data[12],
+        ),
+        _ => return Err(BleError::Unsupported("pvvx", "format")),
+    };
+
+    Ok(DecodedAdvertisement {
+        readings,
+        counter: None,
+        packet_id: Some(counter as u64),
+    })
+}
+
+pub struct PvvxDecoder;
+
+impl BleDecoder for PvvxDecoder {
+    fn name(&self) -> &'static str {
+        "pvvx"
+    }
+
+    fn matches(&self, adv: &[u8]) -> bool {
+        matches!(super::find_service_data(adv, ENVIRONMENTAL_SENSING_UUID), Some(data) if is_plain(data))
+    }
+
+    fn decode(
+        &self,
+        _mac_address: &str,
+        adv: &[u8],
+        _token: Option<&str>,
+    ) -> Result<DecodedAdvertisement, BleError> {
+        let data = super::find_service_data(adv, ENVIRONMENTAL_SENSING_UUID)
+            .ok_or(BleError::NotFound("pvvx"))?;
+        parse_pvvx(data)
+    }
+}
+
+pub struct AtcDecoder;
+
+fn round2(value: f32) -> f64 {
+    (value as f64 * 100.0).round() / 100.0
+}
+
+impl BleDecoder for AtcDecoder {
+    fn name(&self) -> &'static str {
+        "atc"
+    }
+
+    fn matches(&self, adv: &[u8]) -> bool {
+        matches!(super::find_service_data(adv, ENVIRONMENTAL_SENSING_UUID), Some(data) if !is_plain(data))
+    }
+
+    fn decode(
+        &self,
+        mac_address: &str,
+        adv: &[u8],
+        token: Option<&str>,
+    ) -> Result<DecodedAdvertisement, BleError> {
+        let token = token.ok_or(BleError::MissingToken("atc"))?;
+
+        let ret = super::parse_atc(mac_address, adv, token)?;
+
+        Ok(DecodedAdvertisement {
+            readings: vec![
+                BleReading::Temperature(round2(ret.temperature)),
+                BleReading::Humidity(round2(ret.humidity)),
+                BleReading::Battery(ret.battery as u64),
+            ],
+            counter: ret.counter.map(|c| FrameCounter::new(c as u64, 8)),
+            packet_id: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pvvx_custom() {
+        let adv = hex::decode("02010612161a1833221138c1a42909d711860b570c04").unwrap();
+
+        let ret = PvvxDecoder.decode("", &adv, None).unwrap();
+
+        assert_eq!(ret.packet_id, Some(12));
+        assert_eq!(
+            ret.readings,
+            vec![
+                BleReading::Temperature(23.45),
+                BleReading::Humidity(45.67),
+                BleReading::Battery(87),
+                BleReading::BatteryVoltage(2.95),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_atc1441() {
+        let adv = hex::decode("02010610161a18a4c13811223300eb2e570b860c").unwrap();
+
+        let ret = PvvxDecoder.decode("", &adv, None).unwrap();
+
+        assert_eq!(ret.packet_id, Some(12));
+        assert_eq!(
+            ret.readings,
+            vec![
+                BleReading::Temperature(23.5),
+                BleReading::Humidity(46.0),
+                BleReading::Battery(87),
+                BleReading::BatteryVoltage(2.95),
+            ]
+        );
+
+        // encrypted advertisements are left to parse_atc
+        let adv = hex::decode("0201060e161a18bd9ed1b6c6c3a6ba4b0b7d").unwrap();
+        assert!(!PvvxDecoder.matches(&adv));
+        assert!(AtcDecoder.matches(&adv));
+    }
+}
@@ -0,0 +1,211 @@
+use super::error::BleError;
+use super::{bthome, govee, mibeacon, pvvx, ruuvi, DecodedAdvertisement};
+use std::collections::HashMap;
+
+// A decoder recognizes the advertisements of a device family and turns them
+// into readings. The registry keeps, for each domo_ble_* topic name, the
+// decoders tried in order on the advertisements of the devices registered
+// with that topic; the first matching one is used.
+//
+// A new device family is added with a module implementing BleDecoder and a
+// register call in BleDecoderRegistry::default.
+
+pub trait BleDecoder: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // whether the advertisement is in the format of the decoder
+    fn matches(&self, adv: &[u8]) -> bool;
+
+    // token is the key of the encrypted formats, from the topic
+    fn decode(
+        &self,
+        mac_address: &str,
+        adv: &[u8],
+        token: Option<&str>,
+    ) -> Result<DecodedAdvertisement, BleError>;
+}
+
+// topics of the sensors using the generic fields written by write_readings
+pub const SENSOR_TOPIC_NAMES: [&str; 5] = [
+    "domo_ble_thermometer",
+    "domo_ble_contact",
+    "domo_ble_motion",
+    "domo_ble_button",
+    "domo_ble_sensor",
+];
+
+pub struct BleDecoderRegistry {
+    decoders: HashMap<String, Vec<Box<dyn BleDecoder>>>,
+}
+
+impl BleDecoderRegistry {
+    pub fn new() -> Self {
+        BleDecoderRegistry {
+            decoders: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, topic_name: &str, decoder: Box<dyn BleDecoder>) {
+        self.decoders
+            .entry(topic_name.to_owned())
+            .or_default()
+            .push(decoder);
+    }
+
+    pub fn find(&self, topic_name: &str, adv: &[u8]) -> Option<&dyn BleDecoder> {
+        self.decoders
+            .get(topic_name)?
+            .iter()
+            .find(|decoder| decoder.matches(adv))
+            .map(|decoder| decoder.as_ref())
+    }
+
+    // decoder of the advertisement of a device not registered yet, whatever
+    // its topic
+    pub fn find_any(&self, adv: &[u8]) -> Option<&dyn BleDecoder> {
+        SENSOR_TOPIC_NAMES
+            .iter()
+            .find_map(|topic_name| self.find(topic_name, adv))
+    }
+
+    // None when no decoder of the topic matches the advertisement
+    pub fn decode(
+        &self,
+        topic_name: &str,
+        mac_address: &str,
+        adv: &[u8],
+        token: Option<&str>,
+    ) -> Option<Result<DecodedAdvertisement, BleError>> {
+        let decoder = self.find(topic_name, adv)?;
+
+        Some(decoder.decode(mac_address, adv, token))
+    }
+}
+
+impl Default for BleDecoderRegistry {
+    fn default() -> Self {
+        let mut registry = BleDecoderRegistry::new();
+
+        for topic_name in SENSOR_TOPIC_NAMES {
+            registry.register(topic_name, Box::new(bthome::BthomeDecoder));
+            registry.register(topic_name, Box::new(mibeacon::MiBeaconDecoder));
+            registry.register(topic_name, Box::new(ruuvi::RuuviDecoder));
+            registry.register(topic_name, Box::new(govee::GoveeDecoder));
+            registry.register(topic_name, Box::new(pvvx::PvvxDecoder));
+        }
+
+        registry.register("domo_ble_thermometer", Box::new(pvvx::AtcDecoder));
+
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bleutils::BleReading;
+    use proptest::prelude::*;
+
+    const MAC_ADDRESS: &str = "a4:c1:38:00:00:01";
+    const TOKEN: &str = "231d39c1d7cc1ab1aee224cd096db932";
+
+    // every decoder of every topic, with and without key
+    fn decode_all(registry: &BleDecoderRegistry, adv: &[u8]) {
+        for topic_name in SENSOR_TOPIC_NAMES {
+            for token in [None, Some(TOKEN), Some("00")] {
+                let _ = registry.decode(topic_name, MAC_ADDRESS, adv, token);
+            }
+        }
+    }
+
+    #[test]
+    fn test_registry_decode() {
+        let registry = BleDecoderRegistry::default();
+
+        let ruuvi =
+            hex::decode("0201061bff99040512fc5394c37c0004fffc040cac364200cdcbb8334c884f").unwrap();
+
+        let ret = registry
+            .decode("domo_ble_thermometer", "cb:b8:33:4c:88:4f", &ruuvi, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(ret.readings[0], BleReading::Temperature(24.3));
+
+        // encrypted pvvx advertisements are only decoded for the thermometers
+        let atc = hex::decode("0201060e161a18bd9ed1b6c6c3a6ba4b0b7d").unwrap();
+        assert!(registry
+            .decode(
+                "domo_ble_thermometer",
+                "a4:c1:38:00:00:01",
+                &atc,
+                Some("00")
+            )
+            .unwrap()
+            .is_err());
+        assert!(registry
+            .decode("domo_ble_contact", "a4:c1:38:00:00:01", &atc, None)
+            .is_none());
+
+        assert!(registry
+            .decode("domo_ble_valve", "cb:b8:33:4c:88:4f", &ruuvi, None)
+            .is_none());
+    }
+
+    #[test]
+    fn test_truncated_advertisements() {
+        let registry = BleDecoderRegistry::default();
+
+        let captures = [
+            "0201061bff99040512fc5394c37c0004fffc040cac364200cdcbb8334c884f",
+            "0201061216d2fc41a47266c95f730011223378237214",
+            "020106191695fe58588b09482b9e53ecaae46db81e190d00007d32b33c",
+            "02010612161a1833221138c1a42909d711860b570c04",
+            "0201060e161a18bd9ed1b6c6c3a6ba4b0b7d",
+            "02010609ff88ec000393d86400",
+        ];
+
+        for capture in captures {
+            let adv = hex::decode(capture).unwrap();
+
+            for len in 0..adv.len() {
+                decode_all(&registry, &adv[..len]);
+
+                // and with a corrupted byte
+                let mut corrupted = adv.clone();
+                corrupted[len] ^= 0xff;
+                decode_all(&registry, &corrupted);
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_decoders_never_panic(adv in proptest::collection::vec(any::<u8>(), 0..64)) {
+            decode_all(&BleDecoderRegistry::default(), &adv);
+        }
+
+        #[test]
+        fn prop_service_data_never_panics(
+            uuid in prop::sample::select(vec![0xfcd2u16, 0xfe95, 0x181a]),
+            data in proptest::collection::vec(any::<u8>(), 0..28),
+        ) {
+            let mut adv = vec![0x02, 0x01, 0x06, data.len() as u8 + 3, 0x16];
+            adv.extend_from_slice(&uuid.to_le_bytes());
+            adv.extend_from_slice(&data);
+
+            decode_all(&BleDecoderRegistry::default(), &adv);
+        }
+
+        #[test]
+        fn prop_manufacturer_data_never_panics(
+            company_id in prop::sample::select(vec![0x0499u16, 0xec88]),
+            data in proptest::collection::vec(any::<u8>(), 0..28),
+        ) {
+            let mut adv = vec![0x02, 0x01, 0x06, data.len() as u8 + 3, 0xff];
+            adv.extend_from_slice(&company_id.to_le_bytes());
+            adv.extend_from_slice(&data);
+
+            decode_all(&BleDecoderRegistry::default(), &adv);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::macaddress::MacAddress;
+use std::collections::HashMap;
+
+// Replay protection of the encrypted advertisements. A frame is accepted
+// only when its counter is ahead of the last accepted one. The counters wrap
+// around, a counter is ahead when it is less than half of its range after
+// the last one.
+//
+// The last accepted counter of each device is tracked by the bridge and
+// stored in the "counter" field of its topic, so it survives the restarts of
+// the bridge. The topic is written only when the publish policy lets the
+// readings through, so the stored counter may be behind the tracked one.
+//
+// A device whose counter restarts from 0 (e.g. after a factory reset) is
+// accepted again once the "counter" field is removed from its topic.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameCounter {
+    pub value: u64,
+    // width of the counter in the advertisement
+    pub bits: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CounterCheck {
+    New,
+    // the same frame, repeated by the device or by another scanner
+    Duplicate,
+    Stale,
+}
+
+impl FrameCounter {
+    pub fn new(value: u64, bits: u32) -> Self {
+        FrameCounter { value, bits }
+    }
+
+    pub fn check(&self, last: Option<u64>) -> CounterCheck {
+        let last = match last {
+            Some(last) => last,
+            None => return CounterCheck::New,
+        };
+
+        let mask = u64::MAX >> (64 - self.bits);
+        let diff = self.value.wrapping_sub(last) & mask;
+
+        if diff == 0 {
+            CounterCheck::Duplicate
+        } else if diff <= mask >> 1 {
+            CounterCheck::New
+        } else {
+            CounterCheck::Stale
+        }
+    }
+}
+
+// last accepted counters and replay attempts since the start of the
+// bridge, for each device
+pub struct ReplayTracker {
+    counters: HashMap<MacAddress, u64>,
+    attempts: HashMap<MacAddress, u64>,
+}
+
+impl ReplayTracker {
+    pub fn new() -> Self {
+        ReplayTracker {
+            counters: HashMap::new(),
+            attempts: HashMap::new(),
+        }
+    }
+
+    // the counter the next frame is checked against, given the one stored in
+    // the topic; removing the stored counter resets the device
+    pub fn last_counter(&mut self, mac_address: &str, stored: Option<u64>) -> Option<u64> {
+        let mac_address = mac_address.parse::<MacAddress>().ok()?;
+
+        match stored {
+            Some(stored) => Some(*self.counters.entry(mac_address).or_insert(stored)),
+            None => {
+                self.counters.remove(&mac_address);
+                None
+            }
+        }
+    }
+
+    pub fn accept(&mut self, mac_address: &str, counter: u64) {
+        if let Ok(mac_address) = mac_address.parse::<MacAddress>() {
+            self.counters.insert(mac_address, counter);
+        }
+    }
+
+    // the counters are only checked for the encrypted formats, whose mac
+    // addresses are valid
+    pub fn record(&mut self, mac_address: &str) -> u64 {
+        let mac_address = match mac_address.parse::<MacAddress>() {
+            Ok(mac_address) => mac_address,
+            Err(_) => return 0,
+        };
+
+        let attempts = self.attempts.entry(mac_address).or_default();
+        *attempts += 1;
+        *attempts
+    }
+
+    pub fn attempts(&self, mac_address: &str) -> u64 {
+        mac_address
+            .parse::<MacAddress>()
+            .ok()
+            .and_then(|mac_address| self.attempts.get(&mac_address))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.attempts.values().sum()
+    }
+}
+
+impl Default for ReplayTracker {
+    fn default() -> Self {
+        ReplayTracker::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_check() {
+        let counter = FrameCounter::new(10, 32);
+        assert_eq!(counter.check(None), CounterCheck::New);
+        assert_eq!(counter.check(Some(9)), CounterCheck::New);
+        assert_eq!(counter.check(Some(10)), CounterCheck::Duplicate);
+        assert_eq!(counter.check(Some(11)), CounterCheck::Stale);
+
+        // 8 bits counters wrap around
+        assert_eq!(FrameCounter::new(2, 8).check(Some(250)), CounterCheck::New);
+        assert_eq!(
+            FrameCounter::new(200, 8).check(Some(250)),
+            CounterCheck::Stale
+        );
+    }
+
+    #[test]
+    fn test_replay_tracker() {
+        let mut tracker = ReplayTracker::new();
+        assert_eq!(tracker.record("E4:AA:EC:53:9E:2B"), 1);
+        assert_eq!(tracker.record("e4:aa:ec:53:9e:2b"), 2);
+        assert_eq!(tracker.attempts("E4AAEC539E2B"), 2);
+        assert_eq!(tracker.total(), 2);
+    }
+
+    #[test]
+    fn test_last_counter() {
+        let mut tracker = ReplayTracker::new();
+        let mac = "E4:AA:EC:53:9E:2B";
+
+        assert_eq!(tracker.last_counter(mac, Some(5)), Some(5));
+
+        // the accepted counter is not written to the topic
+        tracker.accept(mac, 8);
+        assert_eq!(tracker.last_counter("e4aaec539e2b", Some(5)), Some(8));
+        assert_eq!(
+            FrameCounter::new(7, 32).check(tracker.last_counter(mac, Some(5))),
+            CounterCheck::Stale
+        );
+
+        assert_eq!(tracker.last_counter(mac, None), None);
+        assert_eq!(tracker.last_counter(mac, Some(2)), Some(2));
+    }
+}
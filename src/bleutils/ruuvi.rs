@@ -0,0 +1,125 @@
+use super::error::BleError;
+use super::registry::BleDecoder;
+use super::{BleReading, DecodedAdvertisement};
+
+// RuuviTag data format 5 (RAWv2), manufacturer data of Ruuvi Innovations:
+//
+//   format (0x05), temperature (i16, 0.005 °C), humidity (u16, 0.0025 %),
+//   pressure (u16, Pa - 50000), acceleration x, y, z (3 x i16),
+//   power info (u16, 11 bits of battery mV - 1600 and 5 bits of tx power),
+//   movement counter (u8), measurement sequence (u16), mac address (6)
+//
+// all big endian, the maximum value of each field means not available.
+
+pub const RUUVI_COMPANY_ID: u16 = 0x0499;
+
+const RAWV2_FORMAT: u8 = 0x05;
+const RAWV2_LEN: usize = 24;
+
+fn be16(data: &[u8], i: usize) -> u16 {
+    u16::from_be_bytes([data[i], data[i + 1]])
+}
+
+pub fn parse_ruuvi(data: &[u8]) -> Result<DecodedAdvertisement, BleError> {
+    if data.first() != Some(&RAWV2_FORMAT) {
+        return Err(BleError::Unsupported("ruuvi", "format"));
+    }
+
+    if data.len() < RAWV2_LEN {
+        return Err(BleError::TooShort("ruuvi"));
+    }
+
+    let mut result = DecodedAdvertisement::default();
+
+    let temperature = be16(data, 1) as i16;
+    if temperature != i16::MIN {
+        result
+            .readings
+            .push(BleReading::Temperature(temperature as f64 / 200.0));
+    }
+
+    let humidity = be16(data, 3);
+    if humidity != u16::MAX {
+        result
+            .readings
+            .push(BleReading::Humidity(humidity as f64 / 400.0));
+    }
+
+    let pressure = be16(data, 5);
+    if pressure != u16::MAX {
+        result
+            .readings
+            .push(BleReading::Pressure((pressure as f64 + 50000.0) / 100.0));
+    }
+
+    let battery_mv = be16(data, 13) >> 5;
+    if battery_mv != 0x7ff {
+        result.readings.push(BleReading::BatteryVoltage(
+            (battery_mv as f64 + 1600.0) / 1000.0,
+        ));
+    }
+
+    let sequence = be16(data, 16);
+    if sequence != u16::MAX {
+        result.packet_id = Some(sequence as u64);
+    }
+
+    Ok(result)
+}
+
+pub struct RuuviDecoder;
+
+impl BleDecoder for RuuviDecoder {
+    fn name(&self) -> &'static str {
+        "ruuvi"
+    }
+
+    fn matches(&self, adv: &[u8]) -> bool {
+        super::find_manufacturer_data(adv, RUUVI_COMPANY_ID).is_some()
+    }
+
+    fn decode(
+        &self,
+        _mac_address: &str,
+        adv: &[u8],
+        _token: Option<&str>,
+    ) -> Result<DecodedAdvertisement, BleError> {
+        let data = super::find_manufacturer_data(adv, RUUVI_COMPANY_ID)
+            .ok_or(BleError::NotFound("ruuvi"))?;
+        parse_ruuvi(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ruuvi_rawv2() {
+        // example of the Ruuvi documentation
+        let adv =
+            hex::decode("0201061bff99040512fc5394c37c0004fffc040cac364200cdcbb8334c884f").unwrap();
+
+        let ret = RuuviDecoder.decode("", &adv, None).unwrap();
+
+        assert_eq!(ret.packet_id, Some(205));
+        assert_eq!(
+            ret.readings,
+            vec![
+                BleReading::Temperature(24.3),
+                BleReading::Humidity(53.49),
+                BleReading::Pressure(1000.44),
+                BleReading::BatteryVoltage(2.977),
+            ]
+        );
+
+        // not available values
+        let data = hex::decode("058000ffffffff800080008000ffffffffffffffffffffff").unwrap();
+        let ret = parse_ruuvi(&data).unwrap();
+        assert!(ret.readings.is_empty());
+        assert_eq!(ret.packet_id, None);
+
+        assert!(parse_ruuvi(&data[0..10]).is_err());
+        assert!(parse_ruuvi(&[0x03]).is_err());
+    }
+}
@@ -0,0 +1,407 @@
+use crate::dhtmanager::DHTManager;
+use crate::rules;
+use chrono::{Datelike, Local, Timelike};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+// Climate zones are stored in the DHT as domo_climate_zone topics, e.g.
+//
+// {
+//   "name": "living room",
+//   "mode": "heating",
+//   "thermometer": { "topic_name": "domo_ble_thermometer", "topic_uuid": "..." },
+//   "setpoint": 18.0,
+//   "schedule": [
+//     { "from": "06:30", "to": "22:00", "weekdays": [1, 2, 3, 4, 5], "setpoint": 21.0 }
+//   ],
+//   "hysteresis": 0.3,
+//   "actuators": [
+//     { "topic_name": "domo_ble_valve", "topic_uuid": "..." },
+//     { "topic_name": "domo_fan_coil", "topic_uuid": "..." }
+//   ]
+// }
+//
+// The bridge runs the control loop locally and writes back "demand",
+// "current_setpoint" and "current_temperature". The first matching schedule
+// entry wins, "setpoint" applies outside of them. When the thermometer stops
+// reporting, or is marked stale, the actuators are turned off.
+//
+// The actuators are commanded when the demand changes; the command is sent
+// again to those whose "status" doesn't match the demand after
+// COMMAND_CONFIRM_SECS, e.g. when a valve didn't receive it.
+
+pub const CLIMATE_ZONE_TOPIC_NAME: &str = "domo_climate_zone";
+
+const DEFAULT_MAX_TEMPERATURE_AGE_SECS: u64 = 1800;
+
+// the valves report their status every minute or so
+const COMMAND_CONFIRM_SECS: u64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneMode {
+    Heating,
+    Cooling,
+    Off,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TopicRef {
+    pub topic_name: String,
+    pub topic_uuid: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetpointEntry {
+    pub from: String,
+    pub to: String,
+    pub weekdays: Option<Vec<u32>>,
+    pub setpoint: f64,
+}
+
+fn default_mode() -> ZoneMode {
+    ZoneMode::Heating
+}
+
+fn default_hysteresis() -> f64 {
+    0.5
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_max_temperature_age_secs() -> u64 {
+    DEFAULT_MAX_TEMPERATURE_AGE_SECS
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClimateZone {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_mode")]
+    pub mode: ZoneMode,
+    pub thermometer: TopicRef,
+    pub setpoint: f64,
+    #[serde(default)]
+    pub schedule: Vec<SetpointEntry>,
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: f64,
+    pub actuators: Vec<TopicRef>,
+    #[serde(default = "default_max_temperature_age_secs")]
+    pub max_temperature_age_secs: u64,
+    pub demand: Option<bool>,
+}
+
+impl ClimateZone {
+    // weekday from 1 (monday) to 7, minutes from midnight
+    pub fn active_setpoint(&self, weekday: u32, minutes: u32) -> f64 {
+        for entry in &self.schedule {
+            if let Some(weekdays) = &entry.weekdays {
+                if !weekdays.contains(&weekday) {
+                    continue;
+                }
+            }
+
+            let from = rules::parse_time_of_day(&entry.from);
+            let to = rules::parse_time_of_day(&entry.to);
+
+            if let (Some(from), Some(to)) = (from, to) {
+                if rules::time_in_range(minutes, from, to) {
+                    return entry.setpoint;
+                }
+            }
+        }
+
+        self.setpoint
+    }
+}
+
+// two position control, inside the hysteresis band the previous demand is kept
+pub fn zone_demand(
+    mode: ZoneMode,
+    temperature: f64,
+    setpoint: f64,
+    hysteresis: f64,
+    previous: bool,
+) -> bool {
+    match mode {
+        ZoneMode::Heating => {
+            if temperature <= setpoint - hysteresis {
+                true
+            } else if temperature >= setpoint + hysteresis {
+                false
+            } else {
+                previous
+            }
+        }
+        ZoneMode::Cooling => {
+            if temperature >= setpoint + hysteresis {
+                true
+            } else if temperature <= setpoint - hysteresis {
+                false
+            } else {
+                previous
+            }
+        }
+        ZoneMode::Off => false,
+    }
+}
+
+fn actuator_command(actuator: &TopicRef, demand: bool) -> Option<serde_json::Value> {
+    let command_type = match actuator.topic_name.as_str() {
+        "domo_ble_valve" => "valve_command",
+        "domo_floor_valve" | "domo_fan_coil" => "turn_command",
+        _ => return None,
+    };
+
+    Some(serde_json::json!({
+        "command": {
+            "command_type": command_type,
+            "value": { "topic_uuid": actuator.topic_uuid, "desired_state": demand }
+        }
+    }))
+}
+
+struct Commanded {
+    demand: bool,
+    sent: Instant,
+}
+
+pub struct ClimateController {
+    // demand last commanded to the actuators of each zone
+    commanded: HashMap<String, Commanded>,
+}
+
+impl ClimateController {
+    pub fn new() -> Self {
+        ClimateController {
+            commanded: HashMap::new(),
+        }
+    }
+
+    // indexes of the actuators to command, given whether each one reports the
+    // demand already
+    fn actuators_to_command_at(
+        &mut self,
+        topic_uuid: &str,
+        demand: bool,
+        confirmed: &[bool],
+        now: Instant,
+    ) -> Vec<usize> {
+        let all = match self.commanded.get(topic_uuid) {
+            Some(commanded) if commanded.demand == demand => {
+                let confirm_time = Duration::from_secs(COMMAND_CONFIRM_SECS);
+
+                if now.saturating_duration_since(commanded.sent) < confirm_time {
+                    return vec![];
+                }
+
+                false
+            }
+            _ => true,
+        };
+
+        let actuators: Vec<usize> = (0..confirmed.len())
+            .filter(|i| all || !confirmed[*i])
+            .collect();
+
+        if all || !actuators.is_empty() {
+            self.commanded
+                .insert(topic_uuid.to_owned(), Commanded { demand, sent: now });
+        }
+
+        actuators
+    }
+
+    // returns the volatile commands for the actuators whose state has to change
+    pub async fn check(&mut self, dht_manager: &mut DHTManager) -> Vec<serde_json::Value> {
+        let mut commands = vec![];
+
+        let topics = match dht_manager.cache.get_topic_name(CLIMATE_ZONE_TOPIC_NAME) {
+            Ok(topics) => topics,
+            Err(_) => return commands,
+        };
+
+        let topics = match topics.as_array() {
+            Some(topics) => topics.to_owned(),
+            None => return commands,
+        };
+
+        let now = Local::now();
+        let weekday = now.weekday().number_from_monday();
+        let minutes = now.hour() * 60 + now.minute();
+
+        for topic in topics {
+            let topic_uuid = match topic["topic_uuid"].as_str() {
+                Some(uuid) => uuid,
+                None => continue,
+            };
+
+            let zone = match serde_json::from_value::<ClimateZone>(topic["value"].clone()) {
+                Ok(zone) => zone,
+                Err(e) => {
+                    log::warn!("invalid climate zone {}: {}", topic_uuid, e);
+                    continue;
+                }
+            };
+
+            if !zone.enabled {
+                continue;
+            }
+
+            let thermometer = dht_manager
+                .cache
+                .get_topic_uuid(&zone.thermometer.topic_name, &zone.thermometer.topic_uuid)
+                .unwrap_or_default();
+
+            let temperature = thermometer["value"]["temperature"].as_f64();
+            let last_update = thermometer["value"]["last_update_timestamp"]
+                .as_u64()
+                .unwrap_or(0);
+
+            let age_secs =
+                (sifis_dht::utils::get_epoch_ms() as u64).saturating_sub(last_update) / 1000;
+
+            let setpoint = zone.active_setpoint(weekday, minutes);
+            let previous = zone.demand.unwrap_or(false);
+
+            // marked by the health checks of the BLE sensors
+            let stale = thermometer["value"]["stale"].as_bool().unwrap_or(false);
+
+            let demand = match temperature {
+                Some(temperature) if age_secs <= zone.max_temperature_age_secs && !stale => {
+                    zone_demand(zone.mode, temperature, setpoint, zone.hysteresis, previous)
+                }
+                _ => {
+                    if previous {
+                        log::warn!("climate zone {} thermometer not reporting", topic_uuid);
+                    }
+                    false
+                }
+            };
+
+            let confirmed: Vec<bool> = zone
+                .actuators
+                .iter()
+                .map(|actuator| {
+                    dht_manager
+                        .cache
+                        .get_topic_uuid(&actuator.topic_name, &actuator.topic_uuid)
+                        .map(|t| {
+                            rules::values_match(&t["value"]["status"], &serde_json::json!(demand))
+                        })
+                        .unwrap_or(false)
+                })
+                .collect();
+
+            let actuators =
+                self.actuators_to_command_at(topic_uuid, demand, &confirmed, Instant::now());
+
+            if !actuators.is_empty() {
+                log::debug!(
+                    "CLIMATE ZONE {} {} demand {}",
+                    topic_uuid,
+                    zone.name,
+                    demand
+                );
+            }
+
+            for i in actuators {
+                if let Some(command) = actuator_command(&zone.actuators[i], demand) {
+                    commands.push(command);
+                }
+            }
+
+            let mut value = topic["value"].clone();
+            value["demand"] = serde_json::json!(demand);
+            value["current_setpoint"] = serde_json::json!(setpoint);
+            value["current_temperature"] = serde_json::json!(temperature);
+
+            if value != topic["value"] {
+                dht_manager
+                    .write_topic(CLIMATE_ZONE_TOPIC_NAME, topic_uuid, &value)
+                    .await;
+            }
+        }
+
+        commands
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zone_demand() {
+        assert!(zone_demand(ZoneMode::Heating, 19.4, 20.0, 0.5, false));
+        assert!(zone_demand(ZoneMode::Heating, 20.2, 20.0, 0.5, true));
+        assert!(!zone_demand(ZoneMode::Heating, 20.2, 20.0, 0.5, false));
+        assert!(!zone_demand(ZoneMode::Heating, 20.5, 20.0, 0.5, true));
+        assert!(zone_demand(ZoneMode::Cooling, 25.6, 25.0, 0.5, false));
+        assert!(!zone_demand(ZoneMode::Off, 10.0, 20.0, 0.5, true));
+    }
+
+    #[test]
+    fn test_command_confirmation() {
+        let mut controller = ClimateController::new();
+        let start = Instant::now();
+
+        // a new demand goes to every actuator
+        assert_eq!(
+            controller.actuators_to_command_at("zone", true, &[false, true], start),
+            vec![0, 1]
+        );
+        assert!(controller
+            .actuators_to_command_at(
+                "zone",
+                true,
+                &[false, true],
+                start + Duration::from_secs(10)
+            )
+            .is_empty());
+
+        // the actuator still not reporting the demand is commanded again
+        let retry = start + Duration::from_secs(COMMAND_CONFIRM_SECS);
+        assert_eq!(
+            controller.actuators_to_command_at("zone", true, &[false, true], retry),
+            vec![0]
+        );
+        assert!(controller
+            .actuators_to_command_at(
+                "zone",
+                true,
+                &[true, true],
+                retry + Duration::from_secs(COMMAND_CONFIRM_SECS)
+            )
+            .is_empty());
+
+        assert_eq!(
+            controller.actuators_to_command_at("zone", false, &[true, true], retry),
+            vec![0, 1]
+        );
+    }
+
+    #[test]
+    fn test_active_setpoint() {
+        let zone: ClimateZone = serde_json::from_value(serde_json::json!({
+            "thermometer": { "topic_name": "domo_ble_thermometer", "topic_uuid": "t" },
+            "setpoint": 17.0,
+            "schedule": [
+                { "from": "06:30", "to": "22:00", "weekdays": [1, 2, 3, 4, 5], "setpoint": 21.0 },
+                { "from": "08:00", "to": "23:00", "setpoint": 20.0 }
+            ],
+            "actuators": []
+        }))
+        .unwrap();
+
+        assert_eq!(zone.active_setpoint(1, 7 * 60), 21.0);
+        assert_eq!(zone.active_setpoint(6, 7 * 60), 17.0);
+        assert_eq!(zone.active_setpoint(6, 9 * 60), 20.0);
+        assert_eq!(zone.active_setpoint(1, 23 * 60), 17.0);
+    }
+}
@@ -0,0 +1,233 @@
+use std::error::Error;
+
+// Colors of the domo_rgbw_light topics, each channel goes from 0 to 255.
+//
+// The desired_state of a rgbw_command may contain one of
+//
+//   { "r_value": 255, "g_value": 128, "b_value": 0, "w_value": 0 }
+//   { "hex": "#ff8000" } or { "hex": "#ff800010" }
+//   { "hue": 30, "saturation": 100 }
+//   { "kelvin": 2700 }
+//
+// optionally followed by "brightness" (0-100), or just "brightness" to
+// change the brightness of the current color.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rgbw {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub w: u8,
+}
+
+impl Rgbw {
+    pub fn from_topic(value: &serde_json::Value) -> Rgbw {
+        let channel = |name: &str| value[name].as_u64().unwrap_or(0).min(255) as u8;
+
+        Rgbw {
+            r: channel("r"),
+            g: channel("g"),
+            b: channel("b"),
+            w: channel("w"),
+        }
+    }
+
+    pub fn channels(&self) -> [u8; 4] {
+        [self.r, self.g, self.b, self.w]
+    }
+
+    pub fn from_channels(channels: [u8; 4]) -> Rgbw {
+        Rgbw {
+            r: channels[0],
+            g: channels[1],
+            b: channels[2],
+            w: channels[3],
+        }
+    }
+
+    // the brightest channel is brought to the requested percentage
+    pub fn with_brightness(&self, brightness: f64) -> Rgbw {
+        let max = self.channels().into_iter().max().unwrap_or(0);
+
+        let base = if max == 0 {
+            Rgbw {
+                r: 0,
+                g: 0,
+                b: 0,
+                w: 255,
+            }
+        } else {
+            *self
+        };
+
+        let max = base.channels().into_iter().max().unwrap_or(255) as f64;
+        let factor = brightness.clamp(0.0, 100.0) / 100.0 * 255.0 / max;
+
+        Rgbw::from_channels(base.channels().map(|c| to_channel(c as f64 * factor)))
+    }
+}
+
+fn to_channel(value: f64) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+fn channel_value(desired_state: &serde_json::Value, name: &str) -> Result<u8, Box<dyn Error>> {
+    desired_state[name]
+        .as_u64()
+        .filter(|v| *v <= 255)
+        .map(|v| v as u8)
+        .ok_or_else(|| ("err_".to_owned() + name).into())
+}
+
+pub fn parse_hex(hex: &str) -> Result<Rgbw, Box<dyn Error>> {
+    let hex = hex.trim_start_matches('#');
+
+    if (hex.len() != 6 && hex.len() != 8) || !hex.is_ascii() {
+        return Err("err_hex".into());
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| "err_hex");
+
+    Ok(Rgbw {
+        r: channel(0)?,
+        g: channel(2)?,
+        b: channel(4)?,
+        w: if hex.len() == 8 { channel(6)? } else { 0 },
+    })
+}
+
+// hue in degrees, saturation and value from 0 to 1
+pub fn hsv_to_rgbw(hue: f64, saturation: f64, value: f64) -> Rgbw {
+    let hue = hue.rem_euclid(360.0);
+    let saturation = saturation.clamp(0.0, 1.0);
+    let value = value.clamp(0.0, 1.0);
+
+    let c = value * saturation;
+    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let m = value - c;
+
+    let (r, g, b) = match (hue / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    Rgbw {
+        r: to_channel((r + m) * 255.0),
+        g: to_channel((g + m) * 255.0),
+        b: to_channel((b + m) * 255.0),
+        w: 0,
+    }
+}
+
+// black body approximation, the common part of the three colors is moved to
+// the white channel
+pub fn kelvin_to_rgbw(kelvin: f64) -> Rgbw {
+    let t = kelvin.clamp(1000.0, 40000.0) / 100.0;
+
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.698727446 * (t - 60.0).powf(-0.1332047592)
+    };
+
+    let g = if t <= 66.0 {
+        99.4708025861 * t.ln() - 161.1195681661
+    } else {
+        288.1221695283 * (t - 60.0).powf(-0.0755148492)
+    };
+
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.5177312231 * (t - 10.0).ln() - 305.0447927307
+    };
+
+    let (r, g, b) = (to_channel(r), to_channel(g), to_channel(b));
+    let w = r.min(g).min(b);
+
+    Rgbw {
+        r: r - w,
+        g: g - w,
+        b: b - w,
+        w,
+    }
+}
+
+pub fn parse_color(
+    desired_state: &serde_json::Value,
+    current: Rgbw,
+) -> Result<Rgbw, Box<dyn Error>> {
+    let brightness = desired_state["brightness"].as_f64();
+
+    let color = if !desired_state["r_value"].is_null() {
+        Rgbw {
+            r: channel_value(desired_state, "r_value")?,
+            g: channel_value(desired_state, "g_value")?,
+            b: channel_value(desired_state, "b_value")?,
+            w: channel_value(desired_state, "w_value")?,
+        }
+    } else if let Some(hex) = desired_state["hex"].as_str() {
+        parse_hex(hex)?
+    } else if let Some(hue) = desired_state["hue"].as_f64() {
+        let saturation = desired_state["saturation"].as_f64().unwrap_or(100.0);
+        hsv_to_rgbw(hue, saturation / 100.0, 1.0)
+    } else if let Some(kelvin) = desired_state["kelvin"].as_f64() {
+        kelvin_to_rgbw(kelvin)
+    } else if brightness.is_some() {
+        current
+    } else {
+        return Err("err_desired_state".into());
+    };
+
+    match brightness {
+        Some(brightness) => Ok(color.with_brightness(brightness)),
+        None => Ok(color),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_color() {
+        let current = Rgbw {
+            r: 0,
+            g: 0,
+            b: 0,
+            w: 0,
+        };
+
+        let color = parse_color(&serde_json::json!({ "hex": "#FF8000" }), current).unwrap();
+        assert_eq!(color.channels(), [255, 128, 0, 0]);
+
+        let color = parse_color(
+            &serde_json::json!({ "hue": 120, "saturation": 100, "brightness": 50 }),
+            current,
+        )
+        .unwrap();
+        assert_eq!(color.channels(), [0, 128, 0, 0]);
+
+        let color = parse_color(&serde_json::json!({ "brightness": 100 }), current).unwrap();
+        assert_eq!(color.channels(), [0, 0, 0, 255]);
+
+        assert!(parse_color(&serde_json::json!({ "r_value": 10 }), current).is_err());
+        assert!(parse_color(&serde_json::json!({ "hex": "#12" }), current).is_err());
+    }
+
+    #[test]
+    fn test_kelvin_to_rgbw() {
+        let warm = kelvin_to_rgbw(2700.0);
+        assert!(warm.r > warm.b);
+        assert!(warm.w > 0);
+
+        let daylight = kelvin_to_rgbw(6600.0);
+        assert_eq!(daylight.w, 255);
+    }
+}
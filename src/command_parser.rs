@@ -1,6 +1,122 @@
-use crate::dhtmanager::{DHTCommand, DHTManager};
+use crate::color::{self, Rgbw};
+use crate::dhtmanager::{DHTCommand, DHTManager, SceneCommand, SceneTargetCommand};
+use crate::dimmer::{self, DimRequest, DimmerConfig};
+use crate::gate::{self, GateAction, GateCommand, GATE_TOPIC_NAME};
+use crate::rules;
+use crate::shutter::{ShutterAction, ShutterCalibration, ShutterCommand};
+use crate::transitions::{TransitionCommand, TransitionKind};
 use std::error::Error;
 
+pub const SCENE_TOPIC_NAME: &str = "domo_scene";
+
+pub fn set_output_command(
+    mac_address: &serde_json::Value,
+    output_number: u64,
+    desired_state: bool,
+) -> serde_json::Value {
+    let action_payload = serde_json::json!({
+        "output_number": output_number,
+        "value": desired_state
+    });
+
+    serde_json::json!({
+        "mac_address": mac_address,
+        "shelly_action": {
+          "input": {
+            "action": {
+              "action_name": "set_output",
+              "action_payload": action_payload.to_string(),
+            },
+          },
+        }
+    })
+}
+
+pub fn set_dimmer_command(
+    mac_address: &serde_json::Value,
+    dim_value: u64,
+    transition_ms: Option<u64>,
+) -> serde_json::Value {
+    let mut action_payload = serde_json::json!({ "dim_value": dim_value });
+
+    if let Some(transition_ms) = transition_ms {
+        action_payload["transition_ms"] = serde_json::json!(transition_ms);
+    }
+
+    serde_json::json!({
+        "mac_address": mac_address,
+        "shelly_action": {
+          "input": {
+            "action": {
+              "action_name": "set_dimmer",
+              "action_payload": action_payload.to_string(),
+            },
+          },
+        }
+    })
+}
+
+pub fn set_led_dimmer_command(
+    mac_address: &serde_json::Value,
+    channel: &str,
+    value: u64,
+    transition_ms: Option<u64>,
+) -> serde_json::Value {
+    let mut action_payload = serde_json::json!({
+        "led_dimmer_status": {
+                "channel": channel,
+                "value": value
+        }
+    });
+
+    if let Some(transition_ms) = transition_ms {
+        action_payload["transition_ms"] = serde_json::json!(transition_ms);
+    }
+
+    serde_json::json!({
+        "mac_address": mac_address,
+        "shelly_action": {
+          "input": {
+            "action": {
+              "action_name": "set_led_dimmer",
+              "action_payload": action_payload.to_string(),
+            },
+          },
+        }
+    })
+}
+
+pub fn set_rgbw_command(
+    mac_address: &serde_json::Value,
+    channels: [u64; 4],
+    transition_ms: Option<u64>,
+) -> serde_json::Value {
+    let mut action_payload = serde_json::json!({
+        "rgbw_status": {
+            "r_value": channels[0],
+            "g_value": channels[1],
+            "b_value": channels[2],
+            "w_value": channels[3]
+        }
+    });
+
+    if let Some(transition_ms) = transition_ms {
+        action_payload["transition_ms"] = serde_json::json!(transition_ms);
+    }
+
+    serde_json::json!({
+        "mac_address": mac_address,
+        "shelly_action": {
+          "input": {
+            "action": {
+              "action_name": "set_rgbw",
+              "action_payload": action_payload.to_string(),
+            },
+          },
+        }
+    })
+}
+
 pub async fn handle_turn_command(
     dht_manager: &DHTManager,
     command: &serde_json::Value,
@@ -74,22 +190,11 @@ pub async fn handle_turn_command(
 
                     if let Some(value) = actuator_topic.get("value") {
                         if let Some(mac_address) = value.get("mac_address") {
-                            let action_payload = serde_json::json!({
-                                "output_number": target_channel_number,
-                                "value": desired_state
-                            });
-
-                            let value = serde_json::json!({
-                                "mac_address": mac_address,
-                                "shelly_action": {
-                                  "input": {
-                                    "action": {
-                                      "action_name": "set_output",
-                                      "action_payload": action_payload.to_string(),
-                                    },
-                                  },
-                                }
-                            });
+                            let value = set_output_command(
+                                mac_address,
+                                target_channel_number,
+                                desired_state,
+                            );
 
                             //println!("DOMO: RETURN ACTUATOR COMMAND {}", get_epoch_ms());
                             return Ok(DHTCommand::ActuatorCommand(value));
@@ -109,59 +214,94 @@ pub async fn handle_shutter_command(
     command: &serde_json::Value,
 ) -> Result<DHTCommand, Box<dyn Error>> {
     if let Some(value) = command.get("value") {
-        let topic_uuid = value.get("topic_uuid").unwrap().as_str().unwrap();
-        let shutter_command = value.get("shutter_command").unwrap().as_str().unwrap();
+        let topic_uuid = value
+            .get("topic_uuid")
+            .and_then(|t| t.as_str())
+            .ok_or("err_topic_uuid")?;
 
         let dht_connection_topic = dht_manager
             .cache
             .get_topic_uuid("domo_actuator_connection", topic_uuid)?;
 
-        let dht_connection_topic = dht_connection_topic.get("value").unwrap();
+        let dht_connection_topic = dht_connection_topic.get("value").ok_or("no connection")?;
 
         if let Some(target_topic_name) = dht_connection_topic.get("target_topic_name") {
             if let Some(target_topic_uuid) = dht_connection_topic.get("target_topic_uuid") {
-                if let Some(_target_channel_number) =
+                if let Some(target_channel_number) =
                     dht_connection_topic.get("target_channel_number")
                 {
-                    let target_topic_name = target_topic_name.as_str().unwrap();
-                    let target_topic_uuid = target_topic_uuid.as_str().unwrap();
+                    let target_topic_name =
+                        target_topic_name.as_str().ok_or("err_target_topic_name")?;
+                    let target_topic_uuid =
+                        target_topic_uuid.as_str().ok_or("err_target_topic_uuid")?;
 
                     let actuator_topic = dht_manager
                         .cache
                         .get_topic_uuid(target_topic_name, target_topic_uuid)?;
 
-                    if let Some(value) = actuator_topic.get("value") {
-                        if let Some(mac_address) = value.get("mac_address") {
-                            let mut shut_cmd = 0;
-                            if shutter_command == "up" {
-                                shut_cmd = 0;
+                    if let Some(actuator_value) = actuator_topic.get("value") {
+                        if let Some(mac_address) = actuator_value.get("mac_address") {
+                            let source_topic_name = dht_connection_topic["source_topic_name"]
+                                .as_str()
+                                .unwrap_or("domo_roller_shutter");
+
+                            if source_topic_name == GATE_TOPIC_NAME {
+                                let channel_number = target_channel_number
+                                    .as_u64()
+                                    .ok_or("err_target_channel_number")?;
+
+                                return handle_gate_command(
+                                    dht_manager,
+                                    topic_uuid,
+                                    mac_address,
+                                    channel_number,
+                                    value,
+                                );
                             }
 
-                            if shutter_command == "down" {
-                                shut_cmd = 1;
-                            }
+                            let action = ShutterAction::parse(value)?;
 
-                            if shutter_command == "stop" {
-                                shut_cmd = 2;
-                            }
+                            // calibration and last position are kept in the shutter topic
+                            let shutter_topic = dht_manager
+                                .cache
+                                .get_topic_uuid(source_topic_name, topic_uuid)
+                                .unwrap_or_default();
+
+                            let shutter_value = &shutter_topic["value"];
+
+                            let calibration = ShutterCalibration::from_topic(shutter_value);
+
+                            let native_positioning = shutter_value["native_positioning"]
+                                .as_bool()
+                                .unwrap_or(false);
 
-                            let action_payload = serde_json::json!({
-                                "shutter_command": shut_cmd,
-                            });
-
-                            let value = serde_json::json!({
-                                "mac_address": mac_address,
-                                "shelly_action": {
-                                  "input": {
-                                    "action": {
-                                      "action_name": "set_shutter",
-                                      "action_payload": action_payload.to_string(),
-                                    },
-                                  },
+                            match action {
+                                ShutterAction::GoToPosition(_)
+                                    if !native_positioning && calibration.is_none() =>
+                                {
+                                    return Err("shutter_not_calibrated".into());
                                 }
-                            });
+                                ShutterAction::Home if calibration.is_none() => {
+                                    return Err("shutter_not_calibrated".into());
+                                }
+                                ShutterAction::Tilt(_)
+                                    if calibration.map(|c| c.tilt_time_ms).unwrap_or(0) == 0 =>
+                                {
+                                    return Err("tilt_not_supported".into());
+                                }
+                                _ => {}
+                            }
 
-                            return Ok(DHTCommand::ActuatorCommand(value));
+                            return Ok(DHTCommand::ShutterCommand(ShutterCommand {
+                                topic_name: source_topic_name.to_owned(),
+                                topic_uuid: topic_uuid.to_owned(),
+                                mac_address: mac_address.to_owned(),
+                                action,
+                                calibration,
+                                native_positioning,
+                                position: shutter_value["shutter_position"].as_f64(),
+                                tilt: shutter_value["tilt_position"].as_f64(),
+                            }));
                         }
                     }
                 }
@@ -172,13 +312,72 @@ pub async fn handle_shutter_command(
     Err("not_able_to_parse_command".into())
 }
 
+fn handle_gate_command(
+    dht_manager: &DHTManager,
+    topic_uuid: &str,
+    mac_address: &serde_json::Value,
+    channel_number: u64,
+    command_value: &serde_json::Value,
+) -> Result<DHTCommand, Box<dyn Error>> {
+    let action = GateAction::parse(command_value)?;
+
+    let gate_topic = dht_manager
+        .cache
+        .get_topic_uuid(GATE_TOPIC_NAME, topic_uuid)?;
+
+    let gate_value = &gate_topic["value"];
+
+    if action == GateAction::Pulse && !gate::is_impulse_mode(gate_value) {
+        return Err("pulse_not_supported".into());
+    }
+
+    gate::check_interlock(
+        action,
+        gate_value,
+        presence_detected(dht_manager, gate_value),
+    )?;
+
+    Ok(DHTCommand::GateCommand(GateCommand::new(
+        topic_uuid,
+        mac_address,
+        channel_number,
+        action,
+        gate_value,
+    )))
+}
+
+// whether any of the presence sensors of the gate is active
+fn presence_detected(dht_manager: &DHTManager, gate_value: &serde_json::Value) -> bool {
+    let sensors = match gate_value["presence_sensors"].as_array() {
+        Some(sensors) => sensors,
+        None => return false,
+    };
+
+    sensors.iter().any(|sensor| {
+        dht_manager
+            .cache
+            .get_topic_uuid(
+                sensor["topic_name"].as_str().unwrap_or_default(),
+                sensor["topic_uuid"].as_str().unwrap_or_default(),
+            )
+            .map(|topic| {
+                rules::values_match(&topic["value"]["status"], &serde_json::Value::Bool(true))
+            })
+            .unwrap_or(false)
+    })
+}
+
 pub async fn handle_dim_command(
     dht_manager: &DHTManager,
     command: &serde_json::Value,
 ) -> Result<DHTCommand, Box<dyn Error>> {
     if let Some(value) = command.get("value") {
-        let topic_uuid = value.get("topic_uuid").unwrap().as_str().unwrap();
-        let desired_state = value.get("desired_state").unwrap().as_u64().unwrap();
+        let topic_uuid = value
+            .get("topic_uuid")
+            .and_then(|t| t.as_str())
+            .ok_or("err_topic_uuid")?;
+
+        let request = DimRequest::parse(value)?;
 
         let dht_connection_topic = dht_manager
             .cache
@@ -195,73 +394,73 @@ pub async fn handle_dim_command(
                 if let Some(target_channel_number) =
                     dht_connection_topic.get("target_channel_number")
                 {
-                    let target_topic_name = target_topic_name.as_str().unwrap();
-                    let target_topic_uuid = target_topic_uuid.as_str().unwrap();
-                    let target_channel_number = target_channel_number.as_u64().unwrap();
+                    let target_topic_name =
+                        target_topic_name.as_str().ok_or("err_target_topic_name")?;
+                    let target_topic_uuid =
+                        target_topic_uuid.as_str().ok_or("err_target_topic_uuid")?;
+                    let target_channel_number = target_channel_number
+                        .as_u64()
+                        .ok_or("err_target_channel_number")?;
 
                     let actuator_topic = dht_manager
                         .cache
                         .get_topic_uuid(target_topic_name, target_topic_uuid)?;
 
-                    if let Some(value) = actuator_topic.get("value") {
-                        if let Some(mac_address) = value.get("mac_address") {
-                            if target_topic_name == "shelly_dimmer" {
-                                let action_payload =
-                                    serde_json::json!({ "dim_value": desired_state });
-
-                                let value = serde_json::json!({
-                                    "mac_address": mac_address,
-                                    "shelly_action": {
-                                      "input": {
-                                        "action": {
-                                          "action_name": "set_dimmer",
-                                          "action_payload": action_payload.to_string(),
-                                        },
-                                      },
-                                    }
-                                });
-                                return Ok(DHTCommand::ActuatorCommand(value));
-                            }
+                    if let Some(actuator_value) = actuator_topic.get("value") {
+                        if let Some(mac_address) = actuator_value.get("mac_address") {
+                            // levels and options are kept in the light topic
+                            let light_topic = dht_manager
+                                .cache
+                                .get_topic_uuid("domo_light_dimmable", topic_uuid)
+                                .unwrap_or_default();
 
-                            if target_topic_name == "shelly_rgbw" {
-                                let mut channel = "r";
-                                if target_channel_number == 1 {
-                                    channel = "r";
-                                }
+                            let light_value = &light_topic["value"];
 
-                                if target_channel_number == 2 {
-                                    channel = "g";
-                                }
+                            let config = DimmerConfig::from_topic(light_value);
 
-                                if target_channel_number == 3 {
-                                    channel = "b";
-                                }
+                            let current = light_value["status"].as_f64().unwrap_or(0.0);
+
+                            let level = dimmer::target_level(
+                                request,
+                                &config,
+                                current,
+                                light_value["last_level"].as_f64(),
+                            );
+
+                            let transition_ms =
+                                value["transition_ms"].as_u64().unwrap_or(config.fade_ms);
+
+                            let native_transitions =
+                                light_value["native_transitions"].as_bool().unwrap_or(false);
 
-                                if target_channel_number == 4 {
-                                    channel = "w";
+                            let kind = match target_topic_name {
+                                "shelly_dimmer" => TransitionKind::Dimmer,
+                                "shelly_rgbw" => {
+                                    let channel = match target_channel_number {
+                                        2 => "g",
+                                        3 => "b",
+                                        4 => "w",
+                                        _ => "r",
+                                    };
+
+                                    TransitionKind::LedDimmer(channel.to_owned())
                                 }
+                                _ => return Err("unsupported_target_topic_name".into()),
+                            };
 
-                                let action_payload = serde_json::json!({
-                                    "led_dimmer_status": {
-                                            "channel": channel,
-                                            "value": desired_state
-                                    }
-
-                                });
-
-                                let value = serde_json::json!({
-                                    "mac_address": mac_address,
-                                    "shelly_action": {
-                                      "input": {
-                                        "action": {
-                                          "action_name": "set_led_dimmer",
-                                          "action_payload": action_payload.to_string(),
-                                        },
-                                      },
-                                    }
-                                });
+                            if native_transitions {
+                                let value =
+                                    kind.command(mac_address, &[level], Some(transition_ms));
                                 return Ok(DHTCommand::ActuatorCommand(value));
                             }
+
+                            return Ok(DHTCommand::TransitionCommand(TransitionCommand {
+                                mac_address: mac_address.to_owned(),
+                                kind,
+                                from: vec![current],
+                                to: vec![level],
+                                duration_ms: transition_ms,
+                            }));
                         }
                     }
                 }
@@ -277,22 +476,27 @@ pub async fn handle_rgbw_command(
     command: &serde_json::Value,
 ) -> Result<DHTCommand, Box<dyn Error>> {
     if let Some(value) = command.get("value") {
-        let topic_uuid = value.get("topic_uuid").unwrap().as_str().unwrap();
-        let desired_state: serde_json::Value = value.get("desired_state").unwrap().to_owned();
+        let topic_uuid = value
+            .get("topic_uuid")
+            .and_then(|t| t.as_str())
+            .ok_or("err_topic_uuid")?;
+        let desired_state = value.get("desired_state").ok_or("err_desired_state")?;
+        let transition_ms = value["transition_ms"].as_u64().unwrap_or(0);
 
         let dht_connection_topic = dht_manager
             .cache
             .get_topic_uuid("domo_actuator_connection", topic_uuid)?;
 
-
         if let Some(dht_connection_topic) = dht_connection_topic.get("value") {
             if let Some(target_topic_name) = dht_connection_topic.get("target_topic_name") {
                 if let Some(target_topic_uuid) = dht_connection_topic.get("target_topic_uuid") {
                     if let Some(_target_channel_number) =
-                    dht_connection_topic.get("target_channel_number")
+                        dht_connection_topic.get("target_channel_number")
                     {
-                        let target_topic_name = target_topic_name.as_str().unwrap();
-                        let target_topic_uuid = target_topic_uuid.as_str().unwrap();
+                        let target_topic_name =
+                            target_topic_name.as_str().ok_or("err_target_topic_name")?;
+                        let target_topic_uuid =
+                            target_topic_uuid.as_str().ok_or("err_target_topic_uuid")?;
 
                         let actuator_topic = dht_manager
                             .cache
@@ -300,28 +504,37 @@ pub async fn handle_rgbw_command(
 
                         if let Some(value) = actuator_topic.get("value") {
                             if let Some(mac_address) = value.get("mac_address") {
-                                let action_payload = serde_json::json!({
-                                "rgbw_status": {
-                                    "r_value": desired_state.get("r_value").unwrap().as_u64().unwrap(),
-                                    "g_value": desired_state.get("g_value").unwrap().as_u64().unwrap(),
-                                    "b_value": desired_state.get("b_value").unwrap().as_u64().unwrap(),
-                                    "w_value": desired_state.get("w_value").unwrap().as_u64().unwrap()
-                                }
-                            });
-
-                                let value = serde_json::json!({
-                                "mac_address": mac_address,
-                                "shelly_action": {
-                                  "input": {
-                                    "action": {
-                                      "action_name": "set_rgbw",
-                                      "action_payload": action_payload.to_string(),
-                                    },
-                                  },
+                                // current color and options are kept in the light topic
+                                let light_topic = dht_manager
+                                    .cache
+                                    .get_topic_uuid("domo_rgbw_light", topic_uuid)
+                                    .unwrap_or_default();
+
+                                let current = Rgbw::from_topic(&light_topic["value"]);
+
+                                let color = color::parse_color(desired_state, current)?;
+
+                                let native_transitions = light_topic["value"]["native_transitions"]
+                                    .as_bool()
+                                    .unwrap_or(false);
+
+                                if native_transitions {
+                                    let value = set_rgbw_command(
+                                        mac_address,
+                                        color.channels().map(|c| c as u64),
+                                        Some(transition_ms),
+                                    );
+
+                                    return Ok(DHTCommand::ActuatorCommand(value));
                                 }
-                            });
 
-                                return Ok(DHTCommand::ActuatorCommand(value));
+                                return Ok(DHTCommand::TransitionCommand(TransitionCommand {
+                                    mac_address: mac_address.to_owned(),
+                                    kind: TransitionKind::Rgbw,
+                                    from: current.channels().map(|c| c as f64).to_vec(),
+                                    to: color.channels().map(|c| c as f64).to_vec(),
+                                    duration_ms: transition_ms,
+                                }));
                             }
                         }
                     }
@@ -338,8 +551,31 @@ pub async fn handle_valve_command(
     command: &serde_json::Value,
 ) -> Result<DHTCommand, Box<dyn Error>> {
     if let Some(value) = command.get("value") {
-        let topic_uuid = value.get("topic_uuid").unwrap().as_str().unwrap();
-        let desired_state = value.get("desired_state").unwrap().as_bool().unwrap();
+        let topic_uuid = value
+            .get("topic_uuid")
+            .and_then(|t| t.as_str())
+            .ok_or("err_topic_uuid")?;
+
+        // set_position, calibrate or anti_calcification
+        let valve_action = value["valve_action"].as_str().unwrap_or("set_position");
+
+        let position = match valve_action {
+            "set_position" => {
+                let desired_state = value.get("desired_state").ok_or("err_desired_state")?;
+
+                match desired_state.as_bool() {
+                    Some(open) => Some(if open { 100 } else { 0 }),
+                    None => Some(
+                        desired_state
+                            .as_u64()
+                            .filter(|p| *p <= 100)
+                            .ok_or("err_desired_state")?,
+                    ),
+                }
+            }
+            "calibrate" | "anti_calcification" => None,
+            _ => return Err("unknown_valve_action".into()),
+        };
 
         let valve_topic = dht_manager
             .cache
@@ -347,22 +583,31 @@ pub async fn handle_valve_command(
 
         if let Some(value) = valve_topic.get("value") {
             if let Some(mac_address) = value.get("mac_address") {
-                let action_payload = serde_json::json!({
+                let mut action_payload = serde_json::json!({
                     "mac_address": mac_address,
-                    "value": desired_state
+                    "valve_action": valve_action
                 });
 
-                let value = serde_json::json!({
+                let mut value = serde_json::json!({
                     "mac_address": mac_address,
-                    "desired_state": desired_state,
-                    "shelly_action": {
-                      "input": {
-                        "action": {
-                          "action_name": "control_radiator_valve",
-                          "action_payload": action_payload.to_string(),
-                        },
-                      },
-                    }
+                    "valve_action": valve_action
+                });
+
+                // the open/closed value is kept for the firmwares without positioning
+                if let Some(position) = position {
+                    action_payload["value"] = serde_json::json!(position > 0);
+                    action_payload["position"] = serde_json::json!(position);
+                    value["desired_state"] = serde_json::json!(position > 0);
+                    value["position"] = serde_json::json!(position);
+                }
+
+                value["shelly_action"] = serde_json::json!({
+                  "input": {
+                    "action": {
+                      "action_name": "control_radiator_valve",
+                      "action_payload": action_payload.to_string(),
+                    },
+                  },
                 });
 
                 return Ok(DHTCommand::ValveCommand(value));
@@ -372,3 +617,168 @@ pub async fn handle_valve_command(
 
     Err("not_able_to_parse_command".into())
 }
+
+// builds the volatile command used to bring a scene target to its desired state
+fn scene_target_command(target: &serde_json::Value) -> Result<serde_json::Value, Box<dyn Error>> {
+    // explicit commands are used as they are
+    if let Some(command) = target.get("command") {
+        return Ok(command.to_owned());
+    }
+
+    let topic_name = target
+        .get("topic_name")
+        .and_then(|t| t.as_str())
+        .ok_or("err_topic_name")?;
+    let topic_uuid = target
+        .get("topic_uuid")
+        .and_then(|t| t.as_str())
+        .ok_or("err_topic_uuid")?;
+    let desired_state = target.get("desired_state").ok_or("err_desired_state")?;
+
+    match topic_name {
+        "domo_light" | "domo_switch" | "domo_siren" | "domo_fan_coil" | "domo_floor_valve" => {
+            Ok(serde_json::json!({
+                "command_type": "turn_command",
+                "value": { "topic_uuid": topic_uuid, "desired_state": desired_state }
+            }))
+        }
+        "domo_light_dimmable" => Ok(serde_json::json!({
+            "command_type": "dim_command",
+            "value": { "topic_uuid": topic_uuid, "desired_state": desired_state }
+        })),
+        "domo_rgbw_light" => Ok(serde_json::json!({
+            "command_type": "rgbw_command",
+            "value": { "topic_uuid": topic_uuid, "desired_state": desired_state }
+        })),
+        "domo_garage_gate" => {
+            let gate_command = match desired_state.as_bool() {
+                Some(true) => serde_json::json!("open"),
+                Some(false) => serde_json::json!("close"),
+                None => desired_state.to_owned(),
+            };
+
+            Ok(serde_json::json!({
+                "command_type": "shutter_command",
+                "value": { "topic_uuid": topic_uuid, "shutter_command": gate_command }
+            }))
+        }
+        "domo_roller_shutter" => match desired_state.as_f64() {
+            Some(position) => Ok(serde_json::json!({
+                "command_type": "shutter_command",
+                "value": {
+                    "topic_uuid": topic_uuid,
+                    "shutter_command": "go_to_position",
+                    "position": position
+                }
+            })),
+            None => Ok(serde_json::json!({
+                "command_type": "shutter_command",
+                "value": { "topic_uuid": topic_uuid, "shutter_command": desired_state }
+            })),
+        },
+        "domo_ble_valve" => Ok(serde_json::json!({
+            "command_type": "valve_command",
+            "value": { "topic_uuid": topic_uuid, "desired_state": desired_state }
+        })),
+        _ => Err("unsupported_topic_name".into()),
+    }
+}
+
+async fn handle_scene_target(
+    dht_manager: &DHTManager,
+    target: &serde_json::Value,
+) -> Result<DHTCommand, Box<dyn Error>> {
+    let command = scene_target_command(target)?;
+
+    let command_type = command
+        .get("command_type")
+        .and_then(|c| c.as_str())
+        .ok_or("err_command_type")?;
+
+    match command_type {
+        "turn_command" => handle_turn_command(dht_manager, &command).await,
+        "dim_command" => handle_dim_command(dht_manager, &command).await,
+        "rgbw_command" => handle_rgbw_command(dht_manager, &command).await,
+        "shutter_command" => handle_shutter_command(dht_manager, &command).await,
+        "valve_command" => handle_valve_command(dht_manager, &command).await,
+        _ => Err("unsupported_command_type".into()),
+    }
+}
+
+pub async fn handle_scene_command(
+    dht_manager: &DHTManager,
+    command: &serde_json::Value,
+) -> Result<DHTCommand, Box<dyn Error>> {
+    if let Some(value) = command.get("value") {
+        let scene_uuid = value
+            .get("topic_uuid")
+            .and_then(|t| t.as_str())
+            .ok_or("err_topic_uuid")?;
+
+        let scene_topic = dht_manager
+            .cache
+            .get_topic_uuid(SCENE_TOPIC_NAME, scene_uuid)?;
+
+        let targets = scene_topic["value"]["targets"]
+            .as_array()
+            .ok_or("err_scene_targets")?;
+
+        let mut scene = SceneCommand {
+            scene_uuid: scene_uuid.to_owned(),
+            targets: vec![],
+        };
+
+        for target in targets {
+            let topic_uuid = target["topic_uuid"].as_str().unwrap_or_default().to_owned();
+
+            let command = handle_scene_target(dht_manager, target)
+                .await
+                .map_err(|e| e.to_string());
+
+            scene.targets.push(SceneTargetCommand {
+                topic_uuid,
+                command,
+            });
+        }
+
+        return Ok(DHTCommand::SceneCommand(scene));
+    }
+
+    Err("not_able_to_parse_command".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scene_target_command() {
+        let target = serde_json::json!({
+            "topic_name": "domo_light_dimmable",
+            "topic_uuid": "dimmer",
+            "desired_state": false
+        });
+
+        let command = scene_target_command(&target).unwrap();
+        assert_eq!(command["command_type"], "dim_command");
+        assert_eq!(command["value"]["desired_state"], false);
+
+        let target = serde_json::json!({
+            "topic_name": "domo_light",
+            "topic_uuid": "light",
+            "desired_state": true
+        });
+
+        let command = scene_target_command(&target).unwrap();
+        assert_eq!(command["command_type"], "turn_command");
+        assert_eq!(command["value"]["topic_uuid"], "light");
+
+        let target = serde_json::json!({
+            "topic_name": "domo_ble_thermometer",
+            "topic_uuid": "thermo",
+            "desired_state": true
+        });
+
+        assert!(scene_target_command(&target).is_err());
+    }
+}
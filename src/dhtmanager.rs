@@ -3,13 +3,43 @@ use sifis_dht::domocache::DomoEvent;
 use sifis_dht::utils::get_epoch_ms;
 use std::error::Error;
 
+use crate::bindings::{InputBindings, BINDING_TOPIC_NAME};
+use crate::gate::GateCommand;
+use crate::macaddress::MacAddress;
+use crate::shutter::ShutterCommand;
+use crate::transitions::TransitionCommand;
 use crate::{command_parser, get_topic_from_actuator_topic};
 
+#[allow(clippy::enum_variant_names)]
 pub enum DHTCommand {
     ActuatorCommand(serde_json::Value),
     ValveCommand(serde_json::Value),
+    SceneCommand(SceneCommand),
+    ScheduleCommand(serde_json::Value),
+    ShutterCommand(ShutterCommand),
+    GateCommand(GateCommand),
+    TransitionCommand(TransitionCommand),
+    BleOnboardingCommand(serde_json::Value),
+    BleAdoptCommand(serde_json::Value),
+    BleGattCommand(serde_json::Value),
 }
 
+pub struct SceneTargetCommand {
+    pub topic_uuid: String,
+    pub command: Result<DHTCommand, String>,
+}
+
+pub struct SceneCommand {
+    pub scene_uuid: String,
+    pub targets: Vec<SceneTargetCommand>,
+}
+
+pub struct TopicUpdate {
+    pub topic_name: String,
+    pub topic_uuid: String,
+    pub old_value: serde_json::Value,
+    pub new_value: serde_json::Value,
+}
 
 pub struct ConnElem {
     pub source_topic_name: String,
@@ -19,14 +49,21 @@ pub struct ConnElem {
 
 pub struct DHTManager {
     pub cache: sifis_dht::domocache::DomoCache,
-    pub actuators_index: HashMap<String, Vec<ConnElem>>
+    pub actuators_index: HashMap<String, Vec<ConnElem>>,
+    pub topic_updates: Vec<TopicUpdate>,
+    pub input_bindings: InputBindings,
 }
 
 impl DHTManager {
     pub async fn new(cache_config: sifis_config::Cache) -> Result<DHTManager, Box<dyn Error>> {
         let sifis_cache = sifis_dht::domocache::DomoCache::new(cache_config).await?;
         let actuators_index = HashMap::new();
-        Ok(DHTManager { cache: sifis_cache, actuators_index })
+        Ok(DHTManager {
+            cache: sifis_cache,
+            actuators_index,
+            topic_updates: vec![],
+            input_bindings: InputBindings::new(),
+        })
     }
 
 
@@ -50,6 +87,15 @@ impl DHTManager {
                     println!("Updating");
                     println!("{} {} ", conn.source_topic_name, conn.source_topic_uuid);
 
+                    let old_value = self.get_topic_value(&conn.source_topic_name, &conn.source_topic_uuid);
+
+                    self.topic_updates.push(TopicUpdate {
+                        topic_name: conn.source_topic_name.clone(),
+                        topic_uuid: conn.source_topic_uuid.clone(),
+                        old_value,
+                        new_value: status.clone(),
+                    });
+
                     self.cache
                         .write_value(&conn.source_topic_name, &conn.source_topic_uuid, status)
                         .await;
@@ -89,9 +135,7 @@ impl DHTManager {
                                 if let Some(conns) = self.actuators_index.get_mut(&k) {
                                     conns.push(c)
                                 } else {
-                                    let mut v: Vec<ConnElem> = vec![];
-                                    v.push(c);
-                                    self.actuators_index.insert(k, v);
+                                    self.actuators_index.insert(k, vec![c]);
                                 }
                             }
                         }
@@ -114,6 +158,12 @@ impl DHTManager {
 
     }
 
+    pub fn build_input_bindings_index(&mut self) {
+        if let Ok(bindings) = self.cache.get_topic_name(BINDING_TOPIC_NAME) {
+            self.input_bindings.build_index(&bindings);
+        }
+    }
+
     pub async fn get_auth_cred(
         &mut self,
         user: &str,
@@ -151,13 +201,13 @@ impl DHTManager {
     pub fn get_topic(
         &mut self,
         topic_name: &str,
-        mac_address: &str,
+        mac_address: &MacAddress,
     ) -> Result<serde_json::Value, Box<dyn Error>> {
         if let Ok(actuators) = self.cache.get_topic_name(topic_name) {
             for act in actuators.as_array().unwrap() {
                 if let Some(value) = act.get("value") {
-                    if let Some(mac) = value.get("mac_address") {
-                        if mac == mac_address {
+                    if let Some(mac) = value.get("mac_address").and_then(|m| m.as_str()) {
+                        if mac_address.matches(mac) {
                             return Ok(act.to_owned());
                         }
                     }
@@ -172,6 +222,8 @@ impl DHTManager {
         &mut self,
         mac_address_req: &str,
     ) -> Result<serde_json::Value, Box<dyn Error>> {
+        let mac_address_req = mac_address_req.parse::<MacAddress>()?;
+
         let actuator_topics = [
             "shelly_1",
             "shelly_1pm",
@@ -185,14 +237,17 @@ impl DHTManager {
             "domo_ble_thermometer",
             "domo_ble_valve",
             "domo_ble_contact",
+            "domo_ble_motion",
+            "domo_ble_button",
+            "domo_ble_sensor",
         ];
 
         for act_type in actuator_topics {
             if let Ok(actuators) = self.cache.get_topic_name(act_type) {
                 for act in actuators.as_array().unwrap() {
                     if let Some(value) = act.get("value") {
-                        if let Some(mac) = value.get("mac_address") {
-                            if mac == mac_address_req {
+                        if let Some(mac) = value.get("mac_address").and_then(|m| m.as_str()) {
+                            if mac_address_req.matches(mac) {
                                 return Ok(act.to_owned());
                             }
                         }
@@ -204,18 +259,49 @@ impl DHTManager {
         Err("err".into())
     }
 
+    fn get_topic_value(&self, topic_name: &str, topic_uuid: &str) -> serde_json::Value {
+        match self.cache.get_topic_uuid(topic_name, topic_uuid) {
+            Ok(topic) => topic["value"].clone(),
+            Err(_) => serde_json::Value::Null,
+        }
+    }
+
+    // returns the topics written by the bridge since the last call, used to
+    // run the local automation rules; the writes of the other nodes are not
+    // included
+    pub fn take_topic_updates(&mut self) -> Vec<TopicUpdate> {
+        std::mem::take(&mut self.topic_updates)
+    }
+
     pub async fn write_topic(
         &mut self,
         topic_name: &str,
         topic_uuid: &str,
         value: &serde_json::Value,
     ) {
+        let old_value = self.get_topic_value(topic_name, topic_uuid);
+
+        self.topic_updates.push(TopicUpdate {
+            topic_name: topic_name.to_owned(),
+            topic_uuid: topic_uuid.to_owned(),
+            old_value,
+            new_value: value.to_owned(),
+        });
+
         self.cache
             .write_value(topic_name, topic_uuid, value.to_owned())
             .await;
     }
 
-    async fn handle_volatile_command(
+    pub async fn delete_topic(&mut self, topic_name: &str, topic_uuid: &str) {
+        self.cache.delete_value(topic_name, topic_uuid).await;
+    }
+
+    pub async fn publish_volatile(&mut self, value: serde_json::Value) {
+        self.cache.pub_value(value).await;
+    }
+
+    pub async fn handle_volatile_command(
         &self,
         command: serde_json::Value,
     ) -> Result<DHTCommand, Box<dyn Error>> {
@@ -252,6 +338,34 @@ impl DHTManager {
                 if command_type == "shutter_command" {
                     return command_parser::handle_shutter_command(self, command).await;
                 }
+
+                if command_type == "scene_command" {
+                    return command_parser::handle_scene_command(self, command).await;
+                }
+
+                if command_type == "schedule_command" {
+                    if let Some(value) = command.get("value") {
+                        return Ok(DHTCommand::ScheduleCommand(value.to_owned()));
+                    }
+                }
+
+                if command_type == "ble_onboarding_command" {
+                    if let Some(value) = command.get("value") {
+                        return Ok(DHTCommand::BleOnboardingCommand(value.to_owned()));
+                    }
+                }
+
+                if command_type == "ble_adopt_command" {
+                    if let Some(value) = command.get("value") {
+                        return Ok(DHTCommand::BleAdoptCommand(value.to_owned()));
+                    }
+                }
+
+                if command_type == "ble_gatt_command" {
+                    if let Some(value) = command.get("value") {
+                        return Ok(DHTCommand::BleGattCommand(value.to_owned()));
+                    }
+                }
             }
         }
 
@@ -274,6 +388,10 @@ impl DHTManager {
             if m.topic_name == "domo_actuator_connection" {
                 self.build_actuators_index().await?;
             }
+
+            if m.topic_name == BINDING_TOPIC_NAME {
+                self.build_input_bindings_index();
+            }
         }
 
         Err("not a volatile message".into())
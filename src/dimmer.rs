@@ -0,0 +1,178 @@
+use std::error::Error;
+
+// Levels of the domo_light_dimmable topics go from 0 (off) to 100.
+//
+// The topic may configure
+//
+//   "min_level" / "max_level": range of the levels sent to the actuator
+//   "fade_ms": default transition duration
+//
+// while "last_level" is kept up to date by the bridge with the last level
+// different from 0, so that turning the light on restores it.
+//
+// The value of a dim_command contains either "desired_state", a level or a
+// boolean, or "step", a signed level increment for the long presses.
+//
+// The channels of the shelly_rgbw go from 0 to 255, the levels are scaled
+// when they are sent to the actuator and when its status is read back.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DimmerConfig {
+    pub min_level: f64,
+    pub max_level: f64,
+    pub fade_ms: u64,
+}
+
+impl DimmerConfig {
+    pub fn from_topic(value: &serde_json::Value) -> DimmerConfig {
+        let min_level = value["min_level"].as_f64().unwrap_or(1.0).clamp(1.0, 100.0);
+        let max_level = value["max_level"]
+            .as_f64()
+            .unwrap_or(100.0)
+            .clamp(min_level, 100.0);
+
+        DimmerConfig {
+            min_level,
+            max_level,
+            fade_ms: value["fade_ms"].as_u64().unwrap_or(0),
+        }
+    }
+
+    fn clamp(&self, level: f64) -> f64 {
+        if level <= 0.0 {
+            0.0
+        } else {
+            level.clamp(self.min_level, self.max_level)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DimRequest {
+    Level(f64),
+    On,
+    Off,
+    Step(f64),
+}
+
+impl DimRequest {
+    pub fn parse(value: &serde_json::Value) -> Result<DimRequest, Box<dyn Error>> {
+        if let Some(step) = value.get("step") {
+            return step
+                .as_f64()
+                .map(DimRequest::Step)
+                .ok_or_else(|| "err_step".into());
+        }
+
+        let desired_state = value.get("desired_state").ok_or("err_desired_state")?;
+
+        if let Some(on) = desired_state.as_bool() {
+            return Ok(if on { DimRequest::On } else { DimRequest::Off });
+        }
+
+        match desired_state.as_f64() {
+            Some(level) if (0.0..=100.0).contains(&level) => Ok(DimRequest::Level(level)),
+            _ => Err("err_desired_state".into()),
+        }
+    }
+}
+
+const RGBW_CHANNEL_MAX: f64 = 255.0;
+
+pub fn level_to_rgbw_channel(level: f64) -> f64 {
+    (level.clamp(0.0, 100.0) * RGBW_CHANNEL_MAX / 100.0).round()
+}
+
+pub fn rgbw_channel_to_level(value: f64) -> f64 {
+    (value.clamp(0.0, RGBW_CHANNEL_MAX) * 100.0 / RGBW_CHANNEL_MAX).round()
+}
+
+pub fn target_level(
+    request: DimRequest,
+    config: &DimmerConfig,
+    current: f64,
+    last_level: Option<f64>,
+) -> f64 {
+    match request {
+        DimRequest::Level(level) => config.clamp(level),
+        DimRequest::On => config.clamp(last_level.filter(|l| *l > 0.0).unwrap_or(100.0)),
+        DimRequest::Off => 0.0,
+        // a step never turns the light off, it stops at the minimum
+        DimRequest::Step(step) => {
+            if current > 0.0 {
+                (current + step).clamp(config.min_level, config.max_level)
+            } else if step > 0.0 {
+                config.min_level
+            } else {
+                0.0
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_level() {
+        let config = DimmerConfig::from_topic(&serde_json::json!({
+            "min_level": 10,
+            "max_level": 80
+        }));
+
+        assert_eq!(
+            target_level(DimRequest::Level(95.0), &config, 0.0, None),
+            80.0
+        );
+        assert_eq!(
+            target_level(DimRequest::Level(5.0), &config, 0.0, None),
+            10.0
+        );
+        assert_eq!(
+            target_level(DimRequest::Level(0.0), &config, 50.0, None),
+            0.0
+        );
+        assert_eq!(target_level(DimRequest::On, &config, 0.0, Some(42.0)), 42.0);
+        assert_eq!(target_level(DimRequest::On, &config, 0.0, None), 80.0);
+        assert_eq!(
+            target_level(DimRequest::Step(10.0), &config, 0.0, None),
+            10.0
+        );
+        assert_eq!(
+            target_level(DimRequest::Step(-10.0), &config, 15.0, None),
+            10.0
+        );
+        assert_eq!(
+            target_level(DimRequest::Step(-10.0), &config, 0.0, None),
+            0.0
+        );
+        assert_eq!(
+            target_level(DimRequest::Step(10.0), &config, 75.0, None),
+            80.0
+        );
+    }
+
+    #[test]
+    fn test_rgbw_channel_scale() {
+        assert_eq!(level_to_rgbw_channel(100.0), 255.0);
+        assert_eq!(level_to_rgbw_channel(50.0), 128.0);
+        assert_eq!(level_to_rgbw_channel(0.0), 0.0);
+
+        assert_eq!(rgbw_channel_to_level(255.0), 100.0);
+        assert_eq!(rgbw_channel_to_level(128.0), 50.0);
+        assert_eq!(rgbw_channel_to_level(level_to_rgbw_channel(42.0)), 42.0);
+    }
+
+    #[test]
+    fn test_parse_dim_request() {
+        let value = serde_json::json!({ "desired_state": true });
+        assert_eq!(DimRequest::parse(&value).unwrap(), DimRequest::On);
+
+        let value = serde_json::json!({ "step": -5 });
+        assert_eq!(DimRequest::parse(&value).unwrap(), DimRequest::Step(-5.0));
+
+        let value = serde_json::json!({ "desired_state": 150 });
+        assert!(DimRequest::parse(&value).is_err());
+    }
+}
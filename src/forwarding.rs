@@ -0,0 +1,305 @@
+use crate::dhtmanager::DHTManager;
+use crate::macaddress::MacAddress;
+use crate::messages::BleBeaconMessage;
+use axum::extract::ws::{Message, WebSocketUpgrade};
+use axum::{extract::Extension, response::IntoResponse, routing::get, Router};
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+
+// Forwarding of the raw BLE advertisements received by the scanners, of the
+// registered devices and of all the others, so that other services can build
+// on the scanner network. The forwarding is off unless enabled by a
+// domo_ble_forwarding topic, e.g.
+//
+// {
+//   "enabled": true,
+//   "publish_volatile": true,
+//   "allow_mac_addresses": ["a4:c1:38"],
+//   "deny_mac_addresses": ["a4:c1:38:00:00:01"]
+// }
+//
+// The entries of the lists are mac addresses or their prefixes, e.g. the
+// manufacturer part, in any format; an empty allow list allows every device
+// and the deny list wins over the allow one. Every advertisement is forwarded
+// as
+//
+// {
+//   "ble_advertisement": {
+//     "scanner": "a0:b7:65:00:00:01",
+//     "mac_address": "a4:c1:38:00:00:01",
+//     "payload": "AgEGEhbS/EGkcmbJX3MAESIzeCNyFA==",
+//     "rssi": -71,
+//     "timestamp": 1700000000000
+//   }
+// }
+//
+// published as a volatile message when publish_volatile is set, and streamed
+// to the clients of the local websocket server started with
+// --ble-forwarding-port. The server listens on localhost only, its clients
+// are services running on the bridge itself.
+
+pub const FORWARDING_TOPIC_NAME: &str = "domo_ble_forwarding";
+
+// advertisements buffered for the slow websocket clients
+const STREAM_CAPACITY: usize = 256;
+
+#[derive(Debug, Default, PartialEq)]
+struct ForwardingConfig {
+    enabled: bool,
+    publish_volatile: bool,
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+// the prefix in the canonical format of the mac addresses, lowercase with
+// colons, e.g. "A4C138" is "a4:c1:38"
+fn mac_address_prefix(prefix: &str) -> Option<String> {
+    let hex: Vec<char> = prefix
+        .chars()
+        .filter(|c| *c != ':' && *c != '-')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    if hex.is_empty() || hex.len() > 12 || !hex.iter().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut canonical = String::with_capacity(17);
+
+    for (i, c) in hex.into_iter().enumerate() {
+        if i > 0 && i % 2 == 0 {
+            canonical.push(':');
+        }
+        canonical.push(c);
+    }
+
+    Some(canonical)
+}
+
+fn mac_address_list(value: &serde_json::Value) -> Vec<String> {
+    value
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .filter_map(|mac_address| mac_address.as_str())
+                .filter_map(|mac_address| {
+                    let prefix = mac_address_prefix(mac_address);
+
+                    if prefix.is_none() {
+                        log::warn!("invalid ble forwarding mac address {}", mac_address);
+                    }
+
+                    prefix
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl ForwardingConfig {
+    fn from_value(value: &serde_json::Value) -> Self {
+        ForwardingConfig {
+            enabled: value["enabled"].as_bool().unwrap_or(false),
+            publish_volatile: value["publish_volatile"].as_bool().unwrap_or(false),
+            allow: mac_address_list(&value["allow_mac_addresses"]),
+            deny: mac_address_list(&value["deny_mac_addresses"]),
+        }
+    }
+
+    fn accepts(&self, mac_address: &MacAddress) -> bool {
+        let mac_address = mac_address.to_string();
+        let matches = |prefix: &String| mac_address.starts_with(prefix.as_str());
+
+        self.enabled
+            && (self.allow.is_empty() || self.allow.iter().any(matches))
+            && !self.deny.iter().any(matches)
+    }
+}
+
+fn advertisement(
+    message: &BleBeaconMessage,
+    mac_address: &MacAddress,
+    timestamp: u64,
+) -> serde_json::Value {
+    serde_json::json!({
+        "ble_advertisement": {
+            "scanner": message.actuator,
+            "mac_address": mac_address.to_string(),
+            "payload": message.payload,
+            "rssi": message.rssi,
+            "timestamp": timestamp
+        }
+    })
+}
+
+pub struct BleForwarder {
+    config: ForwardingConfig,
+    stream_tx: Option<broadcast::Sender<String>>,
+}
+
+impl BleForwarder {
+    pub fn new(stream_port: Option<u16>) -> Self {
+        let stream_tx = stream_port.map(|port| {
+            let (stream_tx, _) = broadcast::channel::<String>(STREAM_CAPACITY);
+
+            let app = Router::new().route(
+                "/",
+                get(BleForwarder::handle_stream_req).layer(Extension(stream_tx.clone())),
+            );
+
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+
+            match axum::Server::try_bind(&addr) {
+                Ok(server) => {
+                    tokio::spawn(server.serve(app.into_make_service()));
+                }
+                Err(e) => log::warn!("ble forwarding stream not started on {}: {}", addr, e),
+            }
+
+            stream_tx
+        });
+
+        BleForwarder {
+            config: ForwardingConfig::default(),
+            stream_tx,
+        }
+    }
+
+    // the configuration is read again periodically, the topic can be written
+    // by any node of the dht
+    pub fn refresh(&mut self, dht_manager: &DHTManager) {
+        let value = dht_manager
+            .cache
+            .get_topic_name(FORWARDING_TOPIC_NAME)
+            .ok()
+            .and_then(|topics| topics.as_array().and_then(|t| t.first().cloned()))
+            .map(|topic| topic["value"].clone())
+            .unwrap_or_default();
+
+        let config = ForwardingConfig::from_value(&value);
+
+        if config.enabled != self.config.enabled {
+            log::info!(
+                "BLE FORWARDING {}",
+                if config.enabled {
+                    "ENABLED"
+                } else {
+                    "DISABLED"
+                }
+            );
+        }
+
+        self.config = config;
+    }
+
+    // called for every advertisement received by every scanner
+    pub async fn forward(&mut self, dht_manager: &mut DHTManager, message: &BleBeaconMessage) {
+        let mac_address = match message.mac_address.parse::<MacAddress>() {
+            Ok(mac_address) => mac_address,
+            Err(_) => return,
+        };
+
+        if !self.config.accepts(&mac_address) {
+            return;
+        }
+
+        let advertisement = advertisement(
+            message,
+            &mac_address,
+            sifis_dht::utils::get_epoch_ms() as u64,
+        );
+
+        if let Some(stream_tx) = &self.stream_tx {
+            // fails only without clients
+            let _ret = stream_tx.send(advertisement.to_string());
+        }
+
+        if self.config.publish_volatile {
+            dht_manager.publish_volatile(advertisement).await;
+        }
+    }
+
+    async fn handle_stream_req(
+        ws: WebSocketUpgrade,
+        Extension(stream_tx): Extension<broadcast::Sender<String>>,
+    ) -> impl IntoResponse {
+        let mut stream_rx = stream_tx.subscribe();
+
+        ws.on_upgrade(|mut socket| async move {
+            loop {
+                tokio::select! {
+                    advertisement = stream_rx.recv() => match advertisement {
+                        Ok(advertisement) => {
+                            if socket.send(Message::Text(advertisement)).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            log::warn!("ble forwarding client skipped {} advertisements", skipped);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    },
+                    message = socket.recv() => match message {
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                        _ => {}
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::beacon;
+
+    #[test]
+    fn test_filters() {
+        let accepts = |config: &ForwardingConfig, mac_address: &str| {
+            config.accepts(&mac_address.parse().unwrap())
+        };
+
+        let config = ForwardingConfig::from_value(&serde_json::json!({
+            "enabled": true,
+            "allow_mac_addresses": ["A4C138", "54:48:e6:8f:80:a5", "c4:7c:8z"],
+            "deny_mac_addresses": ["a4-c1-38-00-00-01"]
+        }));
+
+        assert_eq!(config.allow, vec!["a4:c1:38", "54:48:e6:8f:80:a5"]);
+        assert!(accepts(&config, "a4:c1:38:00:00:02"));
+        assert!(accepts(&config, "5448E68F80A5"));
+        assert!(!accepts(&config, "a4:c1:38:00:00:01"));
+        assert!(!accepts(&config, "c4:7c:8d:6a:12:34"));
+        assert!(!config.publish_volatile);
+
+        let config = ForwardingConfig::from_value(&serde_json::json!({ "enabled": true }));
+        assert!(accepts(&config, "c4:7c:8d:6a:12:34"));
+
+        // off without the topic
+        let config = ForwardingConfig::from_value(&serde_json::Value::Null);
+        assert!(!accepts(&config, "c4:7c:8d:6a:12:34"));
+    }
+
+    #[test]
+    fn test_advertisement() {
+        let message = beacon("a0:b7:65:00:00:01", "A4:C1:38:00:00:01", "AgEG", -71);
+
+        assert_eq!(
+            advertisement(
+                &message,
+                &message.mac_address.parse().unwrap(),
+                1_700_000_000_000
+            ),
+            serde_json::json!({
+                "ble_advertisement": {
+                    "scanner": "a0:b7:65:00:00:01",
+                    "mac_address": "a4:c1:38:00:00:01",
+                    "payload": "AgEG",
+                    "rssi": -71,
+                    "timestamp": 1_700_000_000_000u64
+                }
+            })
+        );
+    }
+}
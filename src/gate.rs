@@ -0,0 +1,504 @@
+use crate::command_parser;
+use crate::dhtmanager::TopicUpdate;
+use crate::shutter::set_shutter_command;
+use std::collections::HashMap;
+use std::error::Error;
+use tokio::time::{Duration, Instant};
+
+// Garage gates are configured in the domo_garage_gate topic, e.g.
+//
+// {
+//   "impulse_mode": true,
+//   "pulse_ms": 500,
+//   "closed_input": 1,
+//   "obstruction_input": 2,
+//   "auto_close_secs": 180,
+//   "presence_sensors": [
+//     { "topic_name": "domo_radar_sensor", "topic_uuid": "..." }
+//   ]
+// }
+//
+// In impulse mode the gate controller is driven by a relay pulse on the
+// connected output, otherwise the actuator works in shutter mode. The end
+// stop and obstruction inputs of the actuator are reported in the "closed"
+// and "obstructed" fields of the topic. A close command is refused while the
+// gate is obstructed or any of the presence sensors is active. In impulse mode
+// every pulse may close the gate, so any command is refused unless the gate is
+// known to be closed. An obstruction stops a gate in shutter mode, while a
+// gate in impulse mode is left to the safety edge of its controller as a pulse
+// could reverse it. The auto close timer starts again when the obstruction is
+// cleared.
+
+pub const GATE_TOPIC_NAME: &str = "domo_garage_gate";
+
+const DEFAULT_PULSE_MS: u64 = 500;
+
+// a refused auto close is tried again after this delay
+const AUTO_CLOSE_RETRY_SECS: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GateAction {
+    Open,
+    Close,
+    Stop,
+    Pulse,
+}
+
+impl GateAction {
+    pub fn parse(value: &serde_json::Value) -> Result<GateAction, Box<dyn Error>> {
+        let gate_command = value
+            .get("shutter_command")
+            .and_then(|c| c.as_str())
+            .ok_or("err_shutter_command")?;
+
+        // up and down are accepted as for the roller shutters
+        match gate_command {
+            "open" | "up" => Ok(GateAction::Open),
+            "close" | "down" => Ok(GateAction::Close),
+            "stop" => Ok(GateAction::Stop),
+            "pulse" => Ok(GateAction::Pulse),
+            _ => Err("unknown_gate_command".into()),
+        }
+    }
+}
+
+pub struct GateCommand {
+    pub topic_uuid: String,
+    pub mac_address: serde_json::Value,
+    pub channel_number: u64,
+    pub action: GateAction,
+    pub impulse_mode: bool,
+    pub pulse_ms: u64,
+    pub auto_close_secs: Option<u64>,
+    pub closed: Option<bool>,
+}
+
+impl GateCommand {
+    pub fn new(
+        topic_uuid: &str,
+        mac_address: &serde_json::Value,
+        channel_number: u64,
+        action: GateAction,
+        gate_value: &serde_json::Value,
+    ) -> GateCommand {
+        GateCommand {
+            topic_uuid: topic_uuid.to_owned(),
+            mac_address: mac_address.to_owned(),
+            channel_number,
+            action,
+            impulse_mode: is_impulse_mode(gate_value),
+            pulse_ms: gate_value["pulse_ms"].as_u64().unwrap_or(DEFAULT_PULSE_MS),
+            auto_close_secs: gate_value["auto_close_secs"].as_u64().filter(|s| *s > 0),
+            closed: gate_value["closed"].as_bool(),
+        }
+    }
+}
+
+pub fn is_impulse_mode(gate_value: &serde_json::Value) -> bool {
+    gate_value["impulse_mode"].as_bool().unwrap_or(false)
+}
+
+// whether the command may move the gate towards closed
+fn may_close(action: GateAction, gate_value: &serde_json::Value) -> bool {
+    if !is_impulse_mode(gate_value) {
+        return action == GateAction::Close;
+    }
+
+    // the pulse of an open command is skipped when the gate is known open
+    match gate_value["closed"].as_bool() {
+        Some(true) => false,
+        Some(false) => action != GateAction::Open,
+        None => true,
+    }
+}
+
+// refuses the commands that may close the gate while it is obstructed or
+// someone is in its way
+pub fn check_interlock(
+    action: GateAction,
+    gate_value: &serde_json::Value,
+    presence_detected: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !may_close(action, gate_value) {
+        return Ok(());
+    }
+
+    if gate_value["obstructed"].as_bool() == Some(true) {
+        return Err("gate_obstructed".into());
+    }
+
+    if presence_detected {
+        return Err("gate_presence_detected".into());
+    }
+
+    Ok(())
+}
+
+// copies the end stop and obstruction inputs of the actuator into the gate topic
+pub fn update_gate_inputs(gate_value: &mut serde_json::Value, actuator_topic: &serde_json::Value) {
+    if let Some(input) = gate_value["closed_input"].as_u64() {
+        let status = &actuator_topic["input".to_owned() + &input.to_string()];
+        if let Some(closed) = as_bool(status) {
+            gate_value["closed"] = serde_json::Value::Bool(closed);
+        }
+    }
+
+    if let Some(input) = gate_value["obstruction_input"].as_u64() {
+        let status = &actuator_topic["input".to_owned() + &input.to_string()];
+        if let Some(obstructed) = as_bool(status) {
+            gate_value["obstructed"] = serde_json::Value::Bool(obstructed);
+        }
+    }
+}
+
+fn as_bool(value: &serde_json::Value) -> Option<bool> {
+    if let Some(b) = value.as_bool() {
+        return Some(b);
+    }
+    value.as_u64().map(|v| v != 0)
+}
+
+fn gate_volatile_command(topic_uuid: &str, gate_command: &str) -> serde_json::Value {
+    serde_json::json!({
+        "command": {
+            "command_type": "shutter_command",
+            "value": { "topic_uuid": topic_uuid, "shutter_command": gate_command }
+        }
+    })
+}
+
+struct Pulse {
+    mac_address: serde_json::Value,
+    channel_number: u64,
+    end: Instant,
+}
+
+#[derive(Default)]
+struct GateState {
+    auto_close_at: Option<Instant>,
+    pulse: Option<Pulse>,
+}
+
+pub struct GateManager {
+    gates: HashMap<String, GateState>,
+}
+
+impl GateManager {
+    pub fn new() -> Self {
+        GateManager {
+            gates: HashMap::new(),
+        }
+    }
+
+    // returns the actuator commands to send
+    pub fn handle_command(&mut self, command: GateCommand) -> Vec<serde_json::Value> {
+        self.handle_command_at(command, Instant::now())
+    }
+
+    fn handle_command_at(&mut self, command: GateCommand, now: Instant) -> Vec<serde_json::Value> {
+        let state = self.gates.entry(command.topic_uuid.clone()).or_default();
+
+        match command.action {
+            GateAction::Open => {
+                if let Some(secs) = command.auto_close_secs {
+                    state.auto_close_at = Some(now + Duration::from_secs(secs));
+                }
+            }
+            GateAction::Close => {
+                state.auto_close_at = None;
+            }
+            GateAction::Stop | GateAction::Pulse => {}
+        }
+
+        if !command.impulse_mode {
+            let shutter_command = match command.action {
+                GateAction::Open => 0,
+                GateAction::Close => 1,
+                GateAction::Stop | GateAction::Pulse => 2,
+            };
+
+            return vec![set_shutter_command(&command.mac_address, shutter_command)];
+        }
+
+        // with a single impulse input the same pulse opens and closes the
+        // gate, it is skipped when the gate is already where requested
+        let already_there = match command.action {
+            GateAction::Open => command.closed == Some(false),
+            GateAction::Close => command.closed == Some(true),
+            GateAction::Stop | GateAction::Pulse => false,
+        };
+
+        if already_there || state.pulse.is_some() {
+            return vec![];
+        }
+
+        state.pulse = Some(Pulse {
+            mac_address: command.mac_address.clone(),
+            channel_number: command.channel_number,
+            end: now + Duration::from_millis(command.pulse_ms),
+        });
+
+        vec![command_parser::set_output_command(
+            &command.mac_address,
+            command.channel_number,
+            true,
+        )]
+    }
+
+    // returns the volatile commands triggered by the update of a gate topic
+    pub fn handle_topic_update(&mut self, update: &TopicUpdate) -> Vec<serde_json::Value> {
+        self.handle_topic_update_at(update, Instant::now())
+    }
+
+    fn handle_topic_update_at(
+        &mut self,
+        update: &TopicUpdate,
+        now: Instant,
+    ) -> Vec<serde_json::Value> {
+        let mut commands = vec![];
+
+        if update.topic_name != GATE_TOPIC_NAME {
+            return commands;
+        }
+
+        let state = self.gates.entry(update.topic_uuid.clone()).or_default();
+
+        let closed = update.new_value["closed"].as_bool();
+        if closed != update.old_value["closed"].as_bool() {
+            match closed {
+                Some(true) => state.auto_close_at = None,
+                Some(false) => {
+                    // opened from the remote or the wall button
+                    if let Some(secs) = update.new_value["auto_close_secs"].as_u64() {
+                        if secs > 0 && state.auto_close_at.is_none() {
+                            state.auto_close_at = Some(now + Duration::from_secs(secs));
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+
+        let obstructed = update.new_value["obstructed"].as_bool() == Some(true);
+        let was_obstructed = update.old_value["obstructed"].as_bool() == Some(true);
+
+        if obstructed && !was_obstructed {
+            if is_impulse_mode(&update.new_value) {
+                log::warn!("gate {} obstructed", update.topic_uuid);
+            } else {
+                log::warn!("gate {} obstructed, stopping", update.topic_uuid);
+                commands.push(gate_volatile_command(&update.topic_uuid, "stop"));
+            }
+        }
+
+        if !obstructed && was_obstructed && state.auto_close_at.is_some() {
+            if let Some(secs) = update.new_value["auto_close_secs"].as_u64() {
+                state.auto_close_at = Some(now + Duration::from_secs(secs));
+            }
+        }
+
+        commands
+    }
+
+    // ends the expired pulses, returns the actuator commands to send
+    pub fn process_pulses(&mut self) -> Vec<serde_json::Value> {
+        self.process_pulses_at(Instant::now())
+    }
+
+    fn process_pulses_at(&mut self, now: Instant) -> Vec<serde_json::Value> {
+        let mut commands = vec![];
+
+        for state in self.gates.values_mut() {
+            if state.pulse.as_ref().map(|p| p.end <= now).unwrap_or(false) {
+                let pulse = state.pulse.take().unwrap();
+                commands.push(command_parser::set_output_command(
+                    &pulse.mac_address,
+                    pulse.channel_number,
+                    false,
+                ));
+            }
+        }
+
+        commands
+    }
+
+    // returns the volatile close commands of the expired auto close timers,
+    // the timer is disarmed only when the close command is accepted
+    pub fn process_auto_close(&mut self) -> Vec<serde_json::Value> {
+        self.process_auto_close_at(Instant::now())
+    }
+
+    fn process_auto_close_at(&mut self, now: Instant) -> Vec<serde_json::Value> {
+        let mut commands = vec![];
+
+        for (topic_uuid, state) in self.gates.iter_mut() {
+            if state.auto_close_at.map(|t| t <= now).unwrap_or(false) {
+                log::info!("DOMO: AUTO CLOSE GATE {}", topic_uuid);
+                state.auto_close_at = Some(now + Duration::from_secs(AUTO_CLOSE_RETRY_SECS));
+                commands.push(gate_volatile_command(topic_uuid, "close"));
+            }
+        }
+
+        commands
+    }
+
+    pub async fn wait_for_next_deadline(&self) {
+        let next_deadline = self
+            .gates
+            .values()
+            .flat_map(|s| [s.auto_close_at, s.pulse.as_ref().map(|p| p.end)])
+            .flatten()
+            .min();
+
+        match next_deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => tokio::time::sleep(Duration::from_secs(3600)).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gate_command(action: GateAction, closed: Option<bool>) -> GateCommand {
+        GateCommand {
+            topic_uuid: String::from("gate"),
+            mac_address: serde_json::json!("aa:bb:cc:dd:ee:ff"),
+            channel_number: 1,
+            action,
+            impulse_mode: true,
+            pulse_ms: 500,
+            auto_close_secs: Some(60),
+            closed,
+        }
+    }
+
+    #[test]
+    fn test_parse_gate_action() {
+        let value = serde_json::json!({ "shutter_command": "down" });
+        assert_eq!(GateAction::parse(&value).unwrap(), GateAction::Close);
+
+        let value = serde_json::json!({ "shutter_command": "go_to_position" });
+        assert!(GateAction::parse(&value).is_err());
+    }
+
+    #[test]
+    fn test_impulse_and_auto_close() {
+        let mut manager = GateManager::new();
+        let start = Instant::now();
+
+        let commands = manager.handle_command_at(gate_command(GateAction::Open, Some(true)), start);
+        assert_eq!(commands.len(), 1);
+
+        // no new pulse while the previous one is active
+        let commands = manager.handle_command_at(gate_command(GateAction::Pulse, None), start);
+        assert!(commands.is_empty());
+
+        let commands = manager.process_pulses_at(start + Duration::from_millis(500));
+        assert_eq!(commands.len(), 1);
+
+        assert!(manager
+            .process_auto_close_at(start + Duration::from_secs(59))
+            .is_empty());
+
+        let commands = manager.process_auto_close_at(start + Duration::from_secs(60));
+        assert_eq!(commands[0]["command"]["value"]["shutter_command"], "close");
+
+        // the close command has been refused, it is tried again later
+        let commands = manager.process_auto_close_at(start + Duration::from_secs(90));
+        assert_eq!(commands.len(), 1);
+
+        let commands = manager.handle_command_at(
+            gate_command(GateAction::Close, Some(false)),
+            start + Duration::from_secs(91),
+        );
+        assert_eq!(commands.len(), 1);
+        assert!(manager
+            .process_auto_close_at(start + Duration::from_secs(200))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_interlock() {
+        let shutter_gate = serde_json::json!({ "closed": false });
+        assert!(check_interlock(GateAction::Stop, &shutter_gate, true).is_ok());
+        assert!(check_interlock(GateAction::Close, &shutter_gate, true).is_err());
+
+        // in impulse mode any pulse may close the gate
+        let gate = serde_json::json!({ "impulse_mode": true });
+        for action in [GateAction::Pulse, GateAction::Stop, GateAction::Open] {
+            let err = check_interlock(action, &gate, true).unwrap_err();
+            assert_eq!(err.to_string(), "gate_presence_detected");
+        }
+
+        let gate = serde_json::json!({ "impulse_mode": true, "closed": false });
+        assert!(check_interlock(GateAction::Open, &gate, true).is_ok());
+        assert!(check_interlock(GateAction::Pulse, &gate, true).is_err());
+
+        let gate = serde_json::json!({ "impulse_mode": true, "closed": false, "obstructed": true });
+        let err = check_interlock(GateAction::Stop, &gate, false).unwrap_err();
+        assert_eq!(err.to_string(), "gate_obstructed");
+
+        let gate = serde_json::json!({ "impulse_mode": true, "closed": true, "obstructed": true });
+        assert!(check_interlock(GateAction::Pulse, &gate, true).is_ok());
+    }
+
+    #[test]
+    fn test_obstruction() {
+        let mut manager = GateManager::new();
+        let start = Instant::now();
+
+        let mut update = TopicUpdate {
+            topic_name: String::from(GATE_TOPIC_NAME),
+            topic_uuid: String::from("gate"),
+            old_value: serde_json::json!({ "closed": true, "auto_close_secs": 60 }),
+            new_value: serde_json::json!({ "closed": false, "auto_close_secs": 60 }),
+        };
+        assert!(manager.handle_topic_update_at(&update, start).is_empty());
+
+        update.old_value = update.new_value.clone();
+        update.new_value["obstructed"] = serde_json::Value::Bool(true);
+        let commands = manager.handle_topic_update_at(&update, start);
+        assert_eq!(commands[0]["command"]["value"]["shutter_command"], "stop");
+
+        // no pulse for a gate in impulse mode
+        update.old_value["impulse_mode"] = serde_json::Value::Bool(true);
+        update.new_value["impulse_mode"] = serde_json::Value::Bool(true);
+        manager.gates.clear();
+        assert!(manager.handle_topic_update_at(&update, start).is_empty());
+
+        // the auto close timer starts again when the obstruction is cleared
+        manager.gates.get_mut("gate").unwrap().auto_close_at = Some(start);
+        std::mem::swap(&mut update.old_value, &mut update.new_value);
+        let cleared = start + Duration::from_secs(30);
+        manager.handle_topic_update_at(&update, cleared);
+        assert!(manager
+            .process_auto_close_at(cleared + Duration::from_secs(59))
+            .is_empty());
+        assert_eq!(
+            manager
+                .process_auto_close_at(cleared + Duration::from_secs(60))
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_end_stop_arms_auto_close() {
+        let mut manager = GateManager::new();
+        let start = Instant::now();
+
+        let update = TopicUpdate {
+            topic_name: String::from(GATE_TOPIC_NAME),
+            topic_uuid: String::from("gate"),
+            old_value: serde_json::json!({ "closed": true, "auto_close_secs": 60 }),
+            new_value: serde_json::json!({ "closed": false, "auto_close_secs": 60 }),
+        };
+
+        assert!(manager.handle_topic_update_at(&update, start).is_empty());
+
+        let commands = manager.process_auto_close_at(start + Duration::from_secs(60));
+        assert_eq!(commands.len(), 1);
+    }
+}
@@ -0,0 +1,553 @@
+use crate::macaddress::MacAddress;
+use crate::messages::{ESP32CommandMessage, ESP32CommandType};
+use crate::utils::{new_topic_uuid, ValveCommandManager};
+use std::collections::HashMap;
+use std::error::Error;
+use tokio::sync::broadcast;
+use tokio::time::{Duration, Instant};
+
+// Reads and writes of the GATT characteristics of the BLE devices, e.g. to
+// control locks and lights or to set the advertising interval of a sensor.
+// The command
+//
+// {
+//   "command": {
+//     "command_type": "ble_gatt_command",
+//     "value": {
+//       "request_id": "set-interval-1",
+//       "mac_address": "a4:c1:38:00:00:01",
+//       "operation": "write",
+//       "service_uuid": "1f10",
+//       "characteristic_uuid": "1f1f",
+//       "value": "0a00"
+//     }
+//   }
+// }
+//
+// is sent to the esp32 receiving the device best, chosen like the scanner of
+// the radiator valves, which connects to the device and runs the operation.
+// The operation is "read", "write" or "write_no_response", the uuids are 16
+// bit or 128 bit, the value is hex and the request_id is generated when
+// missing. The esp32 reports a gatt_result property,
+//
+// { "request_id": "set-interval-1", "success": true, "value": "0a00" }
+//
+// with an "error" on failure, "connect_failed" when the esp32 can't connect
+// to the device. Failed and timed out reads are sent again through the next
+// scanner, while writes are sent again only when the connection failed, as
+// a write without a result may have been applied anyway. Then the bridge
+// publishes the volatile message
+//
+// {
+//   "ble_gatt_result": {
+//     "request_id": "set-interval-1",
+//     "mac_address": "a4:c1:38:00:00:01",
+//     "operation": "write",
+//     "characteristic_uuid": "1f1f",
+//     "scanner": "a0:b7:65:00:00:01",
+//     "attempts": 1,
+//     "success": true,
+//     "value": "0a00",
+//     "error": null
+//   }
+// }
+
+// connecting to a device takes a few seconds
+const COMMAND_TIMEOUT_SECS: u64 = 15;
+
+const MAX_ATTEMPTS: usize = 3;
+
+// error of the esp32 when the device doesn't accept the connection
+const CONNECT_FAILED: &str = "connect_failed";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GattOperation {
+    Read,
+    Write,
+    WriteNoResponse,
+}
+
+impl GattOperation {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(GattOperation::Read),
+            "write" => Some(GattOperation::Write),
+            "write_no_response" => Some(GattOperation::WriteNoResponse),
+            _ => None,
+        }
+    }
+
+    // a write can be sent again only when it has surely not been applied
+    fn can_resend(&self, error: &str) -> bool {
+        *self == GattOperation::Read || error == CONNECT_FAILED
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            GattOperation::Read => "read",
+            GattOperation::Write => "write",
+            GattOperation::WriteNoResponse => "write_no_response",
+        }
+    }
+}
+
+// 16 bit uuids like "2a19" or 128 bit ones with dashes
+fn parse_uuid(uuid: &str) -> Option<String> {
+    let uuid = uuid.to_lowercase();
+
+    let valid = match uuid.len() {
+        4 => uuid.chars().all(|c| c.is_ascii_hexdigit()),
+        36 => uuid.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        }),
+        _ => false,
+    };
+
+    if valid {
+        Some(uuid)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GattCommand {
+    pub request_id: String,
+    pub mac_address: String,
+    pub operation: GattOperation,
+    pub service_uuid: String,
+    pub characteristic_uuid: String,
+    // hex, only for the writes
+    pub value: Option<String>,
+}
+
+impl GattCommand {
+    pub fn from_value(value: &serde_json::Value) -> Result<Self, Box<dyn Error>> {
+        let mac_address = value["mac_address"]
+            .as_str()
+            .ok_or("err_mac_address")?
+            .parse::<MacAddress>()?
+            .to_string();
+
+        let operation = value["operation"]
+            .as_str()
+            .and_then(GattOperation::from_name)
+            .ok_or("err_operation")?;
+
+        let service_uuid = value["service_uuid"]
+            .as_str()
+            .and_then(parse_uuid)
+            .ok_or("err_service_uuid")?;
+
+        let characteristic_uuid = value["characteristic_uuid"]
+            .as_str()
+            .and_then(parse_uuid)
+            .ok_or("err_characteristic_uuid")?;
+
+        let data = match operation {
+            GattOperation::Read => None,
+            GattOperation::Write | GattOperation::WriteNoResponse => {
+                let data = value["value"].as_str().ok_or("err_value")?.to_lowercase();
+                hex::decode(&data).map_err(|_| "err_value")?;
+                Some(data)
+            }
+        };
+
+        let request_id = value["request_id"]
+            .as_str()
+            .map(|id| id.to_owned())
+            .unwrap_or_else(new_topic_uuid);
+
+        Ok(GattCommand {
+            request_id,
+            mac_address,
+            operation,
+            service_uuid,
+            characteristic_uuid,
+            value: data,
+        })
+    }
+
+    fn shelly_action(&self) -> serde_json::Value {
+        let action_payload = serde_json::json!({
+            "request_id": self.request_id,
+            "mac_address": self.mac_address,
+            "operation": self.operation.name(),
+            "service_uuid": self.service_uuid,
+            "characteristic_uuid": self.characteristic_uuid,
+            "value": self.value
+        });
+
+        serde_json::json!({
+            "shelly_action": {
+                "input": {
+                    "action": {
+                        "action_name": "ble_gatt_command",
+                        "action_payload": action_payload.to_string(),
+                    },
+                },
+            }
+        })
+    }
+}
+
+struct PendingCommand {
+    command: GattCommand,
+    scanner: String,
+    attempts: usize,
+    sent: Instant,
+}
+
+fn gatt_result(
+    command: &GattCommand,
+    scanner: Option<&str>,
+    attempts: usize,
+    result: Result<&serde_json::Value, &str>,
+) -> serde_json::Value {
+    let (success, value, error) = match result {
+        Ok(value) => (true, value.clone(), serde_json::Value::Null),
+        Err(error) => (false, serde_json::Value::Null, serde_json::json!(error)),
+    };
+
+    serde_json::json!({
+        "ble_gatt_result": {
+            "request_id": command.request_id,
+            "mac_address": command.mac_address,
+            "operation": command.operation.name(),
+            "characteristic_uuid": command.characteristic_uuid,
+            "scanner": scanner,
+            "attempts": attempts,
+            "success": success,
+            "value": value,
+            "error": error
+        }
+    })
+}
+
+// the scanners of the devices are ranked by the ValveCommandManager, which
+// records the results of the commands too
+pub struct GattCommandManager {
+    pending: HashMap<String, PendingCommand>,
+}
+
+impl GattCommandManager {
+    pub fn new() -> Self {
+        GattCommandManager {
+            pending: HashMap::new(),
+        }
+    }
+
+    // the result to publish when the command can't be sent
+    pub fn send(
+        &mut self,
+        value: &serde_json::Value,
+        scanners: &ValveCommandManager,
+        command_tx: &broadcast::Sender<ESP32CommandMessage>,
+    ) -> Option<serde_json::Value> {
+        let command = match GattCommand::from_value(value) {
+            Ok(command) => command,
+            Err(e) => {
+                log::warn!("invalid ble_gatt_command: {}", e);
+
+                return Some(serde_json::json!({
+                    "ble_gatt_result": {
+                        "request_id": value["request_id"],
+                        "mac_address": value["mac_address"],
+                        "success": false,
+                        "error": e.to_string()
+                    }
+                }));
+            }
+        };
+
+        if self.pending.contains_key(&command.request_id) {
+            log::warn!("ble_gatt_command {} already running", command.request_id);
+            return None;
+        }
+
+        match scanners.get_best_actuator_for_valve(&command.mac_address) {
+            Some(scanner) => {
+                self.dispatch(command, scanner, 1, command_tx, Instant::now());
+                None
+            }
+            None => Some(gatt_result(&command, None, 0, Err("no_scanner"))),
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        command: GattCommand,
+        scanner: String,
+        attempts: usize,
+        command_tx: &broadcast::Sender<ESP32CommandMessage>,
+        now: Instant,
+    ) {
+        log::debug!(
+            "BLE GATT {} {} {} THROUGH {}",
+            command.operation.name(),
+            command.mac_address,
+            command.characteristic_uuid,
+            scanner
+        );
+
+        let cmd = ESP32CommandMessage {
+            command_type: ESP32CommandType::Gatt,
+            mac_address: command.mac_address.clone(),
+            payload: command.shelly_action(),
+            actuator_mac_address: scanner.clone(),
+        };
+
+        let _ret = command_tx.send(cmd);
+
+        self.pending.insert(
+            command.request_id.clone(),
+            PendingCommand {
+                command,
+                scanner,
+                attempts,
+                sent: now,
+            },
+        );
+    }
+
+    // a failed attempt goes to the next scanner when it can be sent again,
+    // the result is published after the last one
+    fn retry(
+        &mut self,
+        pending: PendingCommand,
+        error: &str,
+        scanners: &mut ValveCommandManager,
+        command_tx: &broadcast::Sender<ESP32CommandMessage>,
+        now: Instant,
+    ) -> Option<serde_json::Value> {
+        let mac_address = &pending.command.mac_address;
+
+        scanners.record_result(mac_address, &pending.scanner, false);
+
+        if pending.attempts < MAX_ATTEMPTS && pending.command.operation.can_resend(error) {
+            if let Some(scanner) =
+                scanners.get_actuator_for_retry(mac_address, Some(&pending.scanner))
+            {
+                self.dispatch(
+                    pending.command,
+                    scanner,
+                    pending.attempts + 1,
+                    command_tx,
+                    now,
+                );
+                return None;
+            }
+        }
+
+        Some(gatt_result(
+            &pending.command,
+            Some(&pending.scanner),
+            pending.attempts,
+            Err(error),
+        ))
+    }
+
+    // results of the timed out commands
+    pub fn check(
+        &mut self,
+        scanners: &mut ValveCommandManager,
+        command_tx: &broadcast::Sender<ESP32CommandMessage>,
+    ) -> Vec<serde_json::Value> {
+        self.check_at(scanners, command_tx, Instant::now())
+    }
+
+    fn check_at(
+        &mut self,
+        scanners: &mut ValveCommandManager,
+        command_tx: &broadcast::Sender<ESP32CommandMessage>,
+        now: Instant,
+    ) -> Vec<serde_json::Value> {
+        let timeout = Duration::from_secs(COMMAND_TIMEOUT_SECS);
+
+        let timed_out: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.saturating_duration_since(pending.sent) >= timeout)
+            .map(|(request_id, _)| request_id.clone())
+            .collect();
+
+        let mut results = vec![];
+
+        for request_id in timed_out {
+            if let Some(pending) = self.pending.remove(&request_id) {
+                results.extend(self.retry(pending, "timeout", scanners, command_tx, now));
+            }
+        }
+
+        results
+    }
+
+    // gatt_result reported by an esp32, the result to publish if any
+    pub fn handle_result(
+        &mut self,
+        result: &serde_json::Value,
+        scanners: &mut ValveCommandManager,
+        command_tx: &broadcast::Sender<ESP32CommandMessage>,
+    ) -> Option<serde_json::Value> {
+        let request_id = result["request_id"].as_str()?;
+
+        // results of commands already timed out are dropped
+        let pending = self.pending.remove(request_id)?;
+
+        if result["success"].as_bool().unwrap_or(false) {
+            scanners.record_result(&pending.command.mac_address, &pending.scanner, true);
+
+            return Some(gatt_result(
+                &pending.command,
+                Some(&pending.scanner),
+                pending.attempts,
+                Ok(&result["value"]),
+            ));
+        }
+
+        let error = result["error"].as_str().unwrap_or("gatt_error").to_owned();
+
+        self.retry(pending, &error, scanners, command_tx, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::beacon;
+
+    const DEVICE: &str = "a4:c1:38:00:00:01";
+    const SCANNER_1: &str = "bb:bb:bb:bb:bb:01";
+    const SCANNER_2: &str = "bb:bb:bb:bb:bb:02";
+
+    fn write_command() -> serde_json::Value {
+        serde_json::json!({
+            "request_id": "r1",
+            "mac_address": "A4:C1:38:00:00:01",
+            "operation": "write",
+            "service_uuid": "1F10",
+            "characteristic_uuid": "ebe0ccc1-7a0a-4b0c-8a1a-6ff2997da3a6",
+            "value": "0A00"
+        })
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let command = GattCommand::from_value(&write_command()).unwrap();
+        assert_eq!(command.mac_address, DEVICE);
+        assert_eq!(command.service_uuid, "1f10");
+        assert_eq!(command.value.as_deref(), Some("0a00"));
+
+        let mut read = write_command();
+        read["operation"] = serde_json::json!("read");
+        read.as_object_mut().unwrap().remove("value");
+        assert_eq!(GattCommand::from_value(&read).unwrap().value, None);
+
+        let error = |field: &str, v: serde_json::Value| {
+            let mut command = write_command();
+            command[field] = v;
+            GattCommand::from_value(&command).unwrap_err().to_string()
+        };
+
+        assert_eq!(
+            error("mac_address", serde_json::json!("a4:c1")),
+            "err_mac_address"
+        );
+        assert_eq!(
+            error("operation", serde_json::json!("notify")),
+            "err_operation"
+        );
+        assert_eq!(
+            error("service_uuid", serde_json::json!("1f1")),
+            "err_service_uuid"
+        );
+        assert_eq!(error("value", serde_json::json!("0g")), "err_value");
+    }
+
+    #[test]
+    fn test_routing_and_retries() {
+        let (command_tx, mut command_rx) = broadcast::channel::<ESP32CommandMessage>(16);
+        let mut scanners = ValveCommandManager::new();
+        let mut manager = GattCommandManager::new();
+
+        // no scanner receives the device
+        let result = manager
+            .send(&write_command(), &scanners, &command_tx)
+            .unwrap();
+        assert_eq!(result["ble_gatt_result"]["error"], "no_scanner");
+
+        // advertisements of the device, not registered in the dht
+        scanners.observe(&beacon(SCANNER_1, "A4C138000001", "AgEG", -60));
+        scanners.observe(&beacon(SCANNER_2, "A4C138000001", "AgEG", -80));
+
+        assert!(manager
+            .send(&write_command(), &scanners, &command_tx)
+            .is_none());
+        assert_eq!(
+            command_rx.try_recv().unwrap().actuator_mac_address,
+            SCANNER_1
+        );
+
+        // the failed attempt goes to the other scanner
+        let failure =
+            serde_json::json!({ "request_id": "r1", "success": false, "error": "connect_failed" });
+        assert!(manager
+            .handle_result(&failure, &mut scanners, &command_tx)
+            .is_none());
+        assert_eq!(
+            command_rx.try_recv().unwrap().actuator_mac_address,
+            SCANNER_2
+        );
+
+        let success = serde_json::json!({ "request_id": "r1", "success": true, "value": "0a00" });
+        let result = manager
+            .handle_result(&success, &mut scanners, &command_tx)
+            .unwrap();
+        assert_eq!(result["ble_gatt_result"]["scanner"], SCANNER_2);
+        assert_eq!(result["ble_gatt_result"]["attempts"], 2);
+        assert_eq!(result["ble_gatt_result"]["success"], true);
+
+        // late results are dropped
+        assert!(manager
+            .handle_result(&success, &mut scanners, &command_tx)
+            .is_none());
+
+        // a timed out write is not sent again
+        manager.send(&write_command(), &scanners, &command_tx);
+        command_rx.try_recv().unwrap();
+        let now = Instant::now() + Duration::from_secs(COMMAND_TIMEOUT_SECS);
+        let results = manager.check_at(&mut scanners, &command_tx, now);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["ble_gatt_result"]["success"], false);
+        assert_eq!(results[0]["ble_gatt_result"]["error"], "timeout");
+        assert_eq!(results[0]["ble_gatt_result"]["attempts"], 1);
+        assert!(command_rx.try_recv().is_err());
+        assert!(manager.pending.is_empty());
+
+        // neither is a write failed for another reason
+        manager.send(&write_command(), &scanners, &command_tx);
+        command_rx.try_recv().unwrap();
+        let failure =
+            serde_json::json!({ "request_id": "r1", "success": false, "error": "gatt_error" });
+        let result = manager
+            .handle_result(&failure, &mut scanners, &command_tx)
+            .unwrap();
+        assert_eq!(result["ble_gatt_result"]["error"], "gatt_error");
+
+        // a read timed out on every attempt
+        let mut read = write_command();
+        read["operation"] = serde_json::json!("read");
+        manager.send(&read, &scanners, &command_tx);
+        let mut now = Instant::now();
+        let mut results = vec![];
+        for _ in 0..MAX_ATTEMPTS {
+            now += Duration::from_secs(COMMAND_TIMEOUT_SECS);
+            results.extend(manager.check_at(&mut scanners, &command_tx, now));
+        }
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["ble_gatt_result"]["error"], "timeout");
+        assert_eq!(results[0]["ble_gatt_result"]["attempts"], MAX_ATTEMPTS);
+        assert!(manager.pending.is_empty());
+    }
+}
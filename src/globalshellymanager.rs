@@ -1,3 +1,4 @@
+use crate::macaddress::same_mac_address;
 use crate::{ShellyDiscoveryResult, ShellyManager};
 use futures::{stream::FuturesUnordered, StreamExt};
 use std::error::Error;
@@ -30,7 +31,6 @@ impl GlobalShellyManager {
 
         let shelly_m = ShellyManager::new(
             &shelly_disc_result.ip_address,
-            &shelly_disc_result.topic_name,
             &shelly_disc_result.mac_address,
             &shelly_disc_result.mdns_name,
             &user_login,
@@ -59,16 +59,58 @@ impl GlobalShellyManager {
         mac_address: &str,
         action_payload: &serde_json::Value,
     ) -> Result<String, Box<dyn Error>> {
+        let mut found = false;
         for shelly in self.shelly_list.iter_mut() {
-            if shelly.mac_address == mac_address {
+            if same_mac_address(&shelly.mac_address, mac_address) {
                 shelly.send_action(action_payload).await;
+                found = true;
                 //println!("DOMO: SHELLY_ACTION_SENT");
             }
         }
 
+        if found {
+            return Ok(mac_address.to_owned());
+        }
+
         Err("shelly not found".into())
     }
 
+    // sends the actions to the shellies concurrently, actions for the same
+    // shelly are sent in order. Returns for each action if it has been sent
+    pub async fn send_actions(&mut self, actions: &[(String, serde_json::Value)]) -> Vec<bool> {
+        let mut sent = vec![false; actions.len()];
+
+        let mut futures = FuturesUnordered::new();
+
+        for shelly in self.shelly_list.iter_mut() {
+            let indexes: Vec<usize> = actions
+                .iter()
+                .enumerate()
+                .filter(|(_, (mac_address, _))| same_mac_address(mac_address, &shelly.mac_address))
+                .map(|(idx, _)| idx)
+                .collect();
+
+            if indexes.is_empty() {
+                continue;
+            }
+
+            futures.push(async move {
+                for idx in indexes.iter() {
+                    shelly.send_action(&actions[*idx].1).await;
+                }
+                indexes
+            });
+        }
+
+        while let Some(indexes) = futures.next().await {
+            for idx in indexes {
+                sent[idx] = true;
+            }
+        }
+
+        sent
+    }
+
     pub async fn sleep_long(&self) -> Result<serde_json::Value, Box<dyn Error>> {
         tokio::time::sleep(Duration::from_secs(3600)).await;
         Err("sleep long".into())
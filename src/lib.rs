@@ -1,3 +1,8 @@
+// the BLE parsers are also built as a library for the fuzz targets
+pub mod bleutils;
+pub mod macaddress;
+pub mod messages;
+
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
@@ -0,0 +1,149 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+// Mac addresses reach the bridge in several formats: "aa:bb:cc:dd:ee:ff" in
+// the topics, "AABBCCDDEEFF" from the esp32 and in the mdns names of the
+// shellies, and in reversed byte order inside the BLE advertisements. They
+// are parsed into a MacAddress, whose canonical format is lowercase with
+// colons, and compared as such, so that the case and the format of the
+// topics don't matter.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidMacAddress;
+
+impl fmt::Display for InvalidMacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "err_mac_address")
+    }
+}
+
+impl Error for InvalidMacAddress {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddress([u8; 6]);
+
+impl MacAddress {
+    // the byte order of the mac addresses inside the advertisements
+    pub fn from_reversed_bytes(bytes: &[u8]) -> Result<Self, InvalidMacAddress> {
+        let mut bytes: [u8; 6] = bytes.try_into().map_err(|_| InvalidMacAddress)?;
+        bytes.reverse();
+
+        Ok(MacAddress(bytes))
+    }
+
+    pub fn bytes(&self) -> [u8; 6] {
+        self.0
+    }
+
+    pub fn reversed_bytes(&self) -> [u8; 6] {
+        let mut bytes = self.0;
+        bytes.reverse();
+        bytes
+    }
+
+    // same address as the string, whatever its format
+    pub fn matches(&self, mac_address: &str) -> bool {
+        mac_address.parse::<MacAddress>() == Ok(*self)
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = InvalidMacAddress;
+
+    // with or without ':' or '-' separators, in any case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex: String = match s.len() {
+            12 => s.to_owned(),
+            17 => {
+                let separator = s.as_bytes()[2];
+
+                if separator != b':' && separator != b'-' {
+                    return Err(InvalidMacAddress);
+                }
+
+                let mut hex = String::with_capacity(12);
+
+                for (i, c) in s.char_indices() {
+                    if i % 3 == 2 {
+                        if c as u32 != separator as u32 {
+                            return Err(InvalidMacAddress);
+                        }
+                    } else {
+                        hex.push(c);
+                    }
+                }
+
+                hex
+            }
+            _ => return Err(InvalidMacAddress),
+        };
+
+        let bytes = hex::decode(hex).map_err(|_| InvalidMacAddress)?;
+
+        Ok(MacAddress(bytes.try_into().map_err(|_| InvalidMacAddress)?))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5]
+        )
+    }
+}
+
+// comparison of two mac addresses of any format, false when either is not a
+// mac address
+pub fn same_mac_address(a: &str, b: &str) -> bool {
+    match a.parse::<MacAddress>() {
+        Ok(a) => a.matches(b),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mac = MacAddress([0xa4, 0xc1, 0x38, 0x0a, 0x1b, 0xff]);
+
+        for s in [
+            "a4:c1:38:0a:1b:ff",
+            "A4:C1:38:0A:1B:FF",
+            "a4-c1-38-0a-1b-ff",
+            "A4C1380A1BFF",
+            "a4c1380a1bff",
+        ] {
+            assert_eq!(s.parse::<MacAddress>(), Ok(mac), "{}", s);
+        }
+
+        assert_eq!(mac.to_string(), "a4:c1:38:0a:1b:ff");
+        assert_eq!(
+            MacAddress::from_reversed_bytes(&[0xff, 0x1b, 0x0a, 0x38, 0xc1, 0xa4]),
+            Ok(mac)
+        );
+        assert_eq!(mac.reversed_bytes(), [0xff, 0x1b, 0x0a, 0x38, 0xc1, 0xa4]);
+
+        for s in [
+            "",
+            "a4:c1:38:0a:1b",
+            "a4:c1:38-0a:1b:ff",
+            "a4:c1:38:0a:1b:fg",
+            "a4c1380a1bf",
+            "a4:c1:38:0a:1b:ff:00",
+            "aa:bb:cc:dd:ee:f\u{e9}",
+        ] {
+            assert_eq!(s.parse::<MacAddress>(), Err(InvalidMacAddress), "{}", s);
+        }
+
+        assert!(same_mac_address("A4C1380A1BFF", "a4:c1:38:0a:1b:ff"));
+        assert!(!same_mac_address("a4:c1:38:0a:1b:fe", "a4:c1:38:0a:1b:ff"));
+        assert!(!same_mac_address("", ""));
+    }
+}
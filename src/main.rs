@@ -1,9 +1,25 @@
-use crate::bleutils::ContactStatus;
-use crate::dhtmanager::{DHTCommand, DHTManager};
+use crate::alerts::BleHealthMonitor;
+use crate::bleutils::dedup::BeaconDeduplicator;
+use crate::bleutils::registry::BleDecoderRegistry;
+use crate::bleutils::replay::{CounterCheck, ReplayTracker};
+use crate::bleutils::{DecodedAdvertisement, ValveReport};
+use crate::climate::ClimateController;
+use crate::dhtmanager::{DHTCommand, DHTManager, SceneCommand};
+use crate::forwarding::BleForwarder;
+use crate::gate::{GateManager, GATE_TOPIC_NAME};
+use crate::gatt::GattCommandManager;
 use crate::globalshellymanager::GlobalShellyManager;
+use crate::macaddress::{same_mac_address, MacAddress};
 use crate::messages::{AuthCredMessage, BleBeaconMessage, ESP32CommandMessage, ESP32CommandType};
+use crate::onboarding::BleOnboarding;
+use crate::presence::PresenceManager;
+use crate::publish_policy::PublishFilter;
+use crate::rules::RulesEngine;
+use crate::scheduler::{Scheduler, SCHEDULE_TOPIC_NAME};
 use crate::shellymanager::ShellyManager;
-use crate::utils::{ValveCommandManager, ValveData};
+use crate::shutter::ShutterManager;
+use crate::transitions::TransitionManager;
+use crate::utils::{new_topic_uuid, ValveCommandManager, ValveData};
 use crate::wssmanager::WssManager;
 use clap::Parser;
 use futures_util::{pin_mut, stream::StreamExt};
@@ -17,12 +33,28 @@ use std::net::Ipv4Addr;
 use std::time::Duration;
 use tokio::time::Interval;
 
+mod alerts;
+mod bindings;
 mod bleutils;
+mod climate;
+mod color;
 mod command_parser;
 mod dhtmanager;
+mod dimmer;
+mod forwarding;
+mod gate;
+mod gatt;
 mod globalshellymanager;
+mod macaddress;
 mod messages;
+mod onboarding;
+mod presence;
+mod publish_policy;
+mod rules;
+mod scheduler;
 mod shellymanager;
+mod shutter;
+mod transitions;
 mod utils;
 mod wssmanager;
 
@@ -35,6 +67,20 @@ pub struct ShellyDiscoveryResult {
     pub mdns_name: String,
 }
 
+// everything needed to deliver the commands to the actuators
+pub struct ActuatorManagers {
+    pub shelly_manager: GlobalShellyManager,
+    pub wss_mgr: WssManager,
+    pub valve_command_manager: ValveCommandManager,
+    pub shelly_plus_actuators: Vec<String>,
+    pub shutter_manager: ShutterManager,
+    pub gate_manager: GateManager,
+    pub transition_manager: TransitionManager,
+    pub ble_onboarding: BleOnboarding,
+    pub publish_filter: PublishFilter,
+    pub gatt_command_manager: GattCommandManager,
+}
+
 struct PingManager {
     ping_timer: Interval,
 }
@@ -60,6 +106,18 @@ struct DomoWotBridge {
     /// node_id
     #[arg(short, long, default_value_t = 1)]
     pub node_id: u8,
+
+    /// latitude used to compute sunrise and sunset for the scheduled commands
+    #[arg(long)]
+    pub latitude: Option<f64>,
+
+    /// longitude used to compute sunrise and sunset for the scheduled commands
+    #[arg(long)]
+    pub longitude: Option<f64>,
+
+    /// local port of the websocket stream of the raw BLE advertisements
+    #[arg(long)]
+    pub ble_forwarding_port: Option<u16>,
 }
 
 #[derive(Parser, Debug, Serialize, Deserialize)]
@@ -84,19 +142,54 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let mut check_radiator_valve_commands = PingManager::new(20);
 
-    let mut shelly_plus_actuators = vec![];
+    let mut rules_engine = RulesEngine::new();
 
-    let mut valve_command_manager = ValveCommandManager::new();
+    let mut check_scheduled_commands = PingManager::new(10);
 
-    let mut shelly_manager = GlobalShellyManager::new().await;
+    let mut scheduler = Scheduler::new(opt.latitude, opt.longitude);
 
-    let mut dht_manager = dhtmanager::DHTManager::new(opt.cache).await?;
+    let mut check_climate_zones = PingManager::new(30);
 
-    dht_manager.build_actuators_index().await?;
+    let mut climate_controller = ClimateController::new();
+
+    let ble_decoders = BleDecoderRegistry::default();
+
+    let mut replay_tracker = ReplayTracker::new();
+
+    let mut ble_dedup = BeaconDeduplicator::new();
+
+    let mut check_presence = PingManager::new(10);
+
+    let mut presence_manager = PresenceManager::new();
+
+    let mut check_ble_health = PingManager::new(60);
+
+    let mut ble_health_monitor = BleHealthMonitor::new();
+
+    let mut check_ble_forwarding = PingManager::new(10);
+
+    let mut check_gatt_commands = PingManager::new(5);
+
+    let mut ble_forwarder = BleForwarder::new(opt.ble_forwarding_port);
 
+    let mut dht_manager = dhtmanager::DHTManager::new(opt.cache).await?;
 
+    dht_manager.build_actuators_index().await?;
 
-    let mut wss_mgr = WssManager::new(5000).await;
+    dht_manager.build_input_bindings_index();
+
+    let mut managers = ActuatorManagers {
+        shelly_manager: GlobalShellyManager::new().await,
+        wss_mgr: WssManager::new(5000).await,
+        valve_command_manager: ValveCommandManager::new(),
+        shelly_plus_actuators: vec![],
+        shutter_manager: ShutterManager::new(),
+        gate_manager: GateManager::new(),
+        transition_manager: TransitionManager::new(),
+        ble_onboarding: BleOnboarding::new(),
+        publish_filter: PublishFilter::new(),
+        gatt_command_manager: GattCommandManager::new(),
+    };
 
     let stream = mdns::discover::interface(
         SERVICE_NAME,
@@ -111,7 +204,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     loop {
         counter += 1;
         tokio::select! {
-            Some(auth_cred_message) = wss_mgr.rx_auth_cred.recv() => {
+            Some(auth_cred_message) = managers.wss_mgr.rx_auth_cred.recv() => {
                     //println!("Received auth cred from esp32");
                     let ret = handle_cred_message(auth_cred_message, &mut dht_manager).await;
                     if let Ok(m) = ret {
@@ -121,31 +214,49 @@ async fn main() -> Result<(), Box<dyn Error>> {
                                 let topic = topic.as_str().unwrap().to_owned();
                                 if topic == "shelly_1plus" || topic == "shelly_1pm_plus" || topic == "shelly_2pm_plus" {
                                     println!("Shelly plus {}, {} connected" , topic, mac_address);
-                                    shelly_plus_actuators.push(mac_address.as_str().unwrap().to_owned());
+                                    managers.shelly_plus_actuators.push(mac_address.as_str().unwrap().to_owned());
                                 }
                             }
                         }
                     }
             },
 
-            esp32_actuator_update = wss_mgr.channel_of_actuator_updates_rx.recv() => {
+            esp32_actuator_update = managers.wss_mgr.channel_of_actuator_updates_rx.recv() => {
                 //println!("Received esp32 actuator update");
                 if let Ok(msg) = esp32_actuator_update {
-                    handle_shelly_message(msg, &mut dht_manager).await;
+                    handle_shelly_message(msg, &mut dht_manager, &mut managers).await;
                 }
             }
             // listener for ble beacons adv
 
-            ble_update = wss_mgr.channel_of_updates_rx.recv() => {
+            ble_update = managers.wss_mgr.channel_of_updates_rx.recv() => {
 
                 println!("Received ble beacon update");
 
                 if let Ok(msg) = ble_update {
-                    handle_ble_update_message(msg, &mut dht_manager, &mut valve_command_manager).await;
+                    presence_manager.observe(&msg.mac_address, &msg.actuator, msg.rssi);
+
+                    ble_forwarder.forward(&mut dht_manager, &msg).await;
+
+                    // every scanner receiving a device competes to send its
+                    // commands, registered or not
+                    managers.valve_command_manager.observe(&msg);
+
+                    if ble_dedup.observe(&msg) {
+                        handle_ble_update_message(msg, &mut dht_manager, &mut managers.ble_onboarding, &ble_decoders, &mut replay_tracker, &mut managers.publish_filter).await;
+                    }
                 }
 
             },
 
+            gatt_result = managers.wss_mgr.channel_of_gatt_results_rx.recv() => {
+                if let Ok(result) = gatt_result {
+                    if let Some(result) = managers.gatt_command_manager.handle_result(&result, &mut managers.valve_command_manager, &managers.wss_mgr.command_channel_tx) {
+                        dht_manager.publish_volatile(result).await;
+                    }
+                }
+            },
+
             // mdns await
             res = stream.next() =>  {
 
@@ -175,7 +286,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
                                                 if let Some(user) = user_login_str {
                                                     if let Some(password) = user_password_str {
-                                                        shelly_manager.insert_shelly(shelly, user.to_owned(), password.to_owned()).await;
+                                                        managers.shelly_manager.insert_shelly(shelly, user.to_owned(), password.to_owned()).await;
                                                     }
                                                 }
                                             }
@@ -192,57 +303,62 @@ async fn main() -> Result<(), Box<dyn Error>> {
             },
             _ = check_radiator_valve_commands.wait_ping_timer() => {
                 //println!("RADIATOR VALVE QUEUE CHECK");
-                if !valve_command_manager.valve_commands.is_empty() && !shelly_plus_actuators.is_empty() {
+                if !managers.valve_command_manager.valve_commands.is_empty() && !managers.shelly_plus_actuators.is_empty() {
 
                     let mut to_remove = vec![];
                     let valves = dht_manager.cache.get_topic_name("domo_ble_valve").unwrap();
 
-                    let valve_commands = valve_command_manager.valve_commands.clone();
+                    let valve_commands = managers.valve_command_manager.valve_commands.clone();
 
                     for (key, val) in valve_commands {
                             let mut ok = false;
+                            let mut valve_value = &serde_json::Value::Null;
                                 for valve in valves.as_array().unwrap() {
                                     if let Some(value) = valve.get("value") {
                                         if let Some(mac_address) = value.get("mac_address") {
                                             let mac = mac_address.as_str().unwrap();
-                                            if mac == key {
-                                                if let Some(status) = value.get("status") {
-                                                   let status = status.as_bool().unwrap();
-                                                    //println!("Status: {} ", status);
-                                                    //println!("Desired state: {}", val.desired_state);
-
-                                                    if let Some(desired_state) = val.desired_state.get("desired_state") {
-                                                        let desired_state = desired_state.as_bool().unwrap();
-                                                        if status == desired_state {
-                                                            //println!("Removing valve command from queue");
-                                                            to_remove.push(key.clone());
-                                                            ok = true;
-                                                            break;
-                                                        }
-                                                    }
+                                            if !same_mac_address(mac, &key) {
+                                                continue;
+                                            }
+                                            valve_value = value;
+                                            if val.is_confirmed(value) {
+                                                //println!("Removing valve command from queue");
+                                                if let Some(act_mac) = &val.actuator_mac_address {
+                                                    managers.valve_command_manager.record_result(&key, act_mac, true);
                                                 }
+                                                to_remove.push(key.clone());
+                                                ok = true;
+                                                break;
                                             }
                                         }
                                     }
                                 }
                         if ok {
                             continue;
+                        } else if !val.is_attempt_expired(valve_value) {
+                            // the valve has not reported since the last attempt yet
+                            continue;
                         } else if val.attempts < 100 {
 
-                            if let Some(next_act_mac) = valve_command_manager.get_best_actuator_for_valve(&key) {
+                            // the previous attempt was not confirmed, the retry goes to another scanner if any
+                            if let Some(prev_act_mac) = &val.actuator_mac_address {
+                                managers.valve_command_manager.record_result(&key, prev_act_mac, false);
+                            }
+
+                            if let Some(next_act_mac) = managers.valve_command_manager.get_actuator_for_retry(&key, val.actuator_mac_address.as_deref()) {
                                 //println!("RE-SEND VALVE COMMAND TO {}", next_act_mac.clone());
                                 let cmd = ESP32CommandMessage {
                                         command_type: ESP32CommandType::Valve,
                                         mac_address: key.to_string(),
                                         payload: val.desired_state.clone(),
-                                        actuator_mac_address: next_act_mac
+                                        actuator_mac_address: next_act_mac.clone()
                                 };
 
-                                let _ret = wss_mgr.command_channel_tx.send(cmd);
+                                let _ret = managers.wss_mgr.command_channel_tx.send(cmd);
 
                                 let mut val = val.clone();
-                                val.attempts += 1;
-                                valve_command_manager.valve_commands.insert(key, val);
+                                val.retry(next_act_mac);
+                                managers.valve_command_manager.valve_commands.insert(key, val);
                             }
 
                         }
@@ -253,16 +369,58 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     }
 
                     for r in to_remove {
-                        valve_command_manager.remove(&r);
+                        managers.valve_command_manager.remove(&r);
                     }
 
                 }
             },
+            _ = check_scheduled_commands.wait_ping_timer() => {
+                let commands = scheduler.check(&mut dht_manager).await;
+
+                execute_volatile_commands(
+                    commands,
+                    &mut dht_manager,
+                    &mut managers,
+                )
+                .await;
+            },
+            _ = check_presence.wait_ping_timer() => {
+                presence_manager.check(&mut dht_manager).await;
+            },
+
+            _ = check_ble_health.wait_ping_timer() => {
+                ble_health_monitor.check(&mut dht_manager).await;
+            },
+
+            _ = check_gatt_commands.wait_ping_timer() => {
+                let results = managers.gatt_command_manager.check(&mut managers.valve_command_manager, &managers.wss_mgr.command_channel_tx);
+
+                managers.valve_command_manager.remove_stale_devices();
+
+                for result in results {
+                    dht_manager.publish_volatile(result).await;
+                }
+            },
+
+            _ = check_ble_forwarding.wait_ping_timer() => {
+                ble_forwarder.refresh(&dht_manager);
+            },
+
+            _ = check_climate_zones.wait_ping_timer() => {
+                let commands = climate_controller.check(&mut dht_manager).await;
+
+                execute_volatile_commands(
+                    commands,
+                    &mut dht_manager,
+                    &mut managers,
+                )
+                .await;
+            },
             _ = ping_mgr.wait_ping_timer() => {
                 println!("PING_TIMER {}", counter);
 
-                shelly_manager.send_ping().await;
-                shelly_manager.check_if_reconnect_needed().await;
+                managers.shelly_manager.send_ping().await;
+                managers.shelly_manager.check_if_reconnect_needed().await;
 
                 let cmd = ESP32CommandMessage {
                                                 command_type: ESP32CommandType::Ping,
@@ -271,7 +429,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                                                 payload: json!({})
                                         };
 
-                let _ret = wss_mgr.command_channel_tx.send(cmd);
+                let _ret = managers.wss_mgr.command_channel_tx.send(cmd);
 
             },
             _ = check_shelly_mode.wait_ping_timer() => {
@@ -280,9 +438,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 if let Ok(actuator_connections) = dht_manager.cache.get_topic_name("domo_actuator_connection") {
                     let actuator_connections = actuator_connections.as_array().unwrap();
 
-                    check_shelly_esp8266_mode(actuator_connections, &mut shelly_manager, &mut dht_manager).await;
+                    check_shelly_esp8266_mode(actuator_connections, &mut managers.shelly_manager, &mut dht_manager).await;
 
-                    check_shelly_esp32_mode(actuator_connections, &shelly_plus_actuators, &mut dht_manager, &mut wss_mgr).await;
+                    check_shelly_esp32_mode(actuator_connections, &managers.shelly_plus_actuators, &mut dht_manager, &mut managers.wss_mgr).await;
 
                 }
             },
@@ -290,82 +448,314 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
                 if let Ok(cmd) = command {
                         //println!("Received command from dht {}", get_epoch_ms());
-                        match cmd {
-                            DHTCommand::ActuatorCommand(value) => {
+                        handle_dht_command(
+                            cmd,
+                            &mut dht_manager,
+                            &mut managers,
+                        )
+                        .await;
+                    }
+            },
+
+            shelly_message = managers.shelly_manager.wait_for_shelly_message() => {
+
+                if let Ok(message) = shelly_message {
+                        handle_shelly_message(message, &mut dht_manager, &mut managers).await;
+                }
+            }
 
-                                //println!("Received actuator command");
+            _ = managers.shutter_manager.wait_for_next_deadline() => {
+                for value in managers.shutter_manager.process_deadlines() {
+                    send_actuator_command(value, &mut dht_manager, &mut managers).await;
+                }
+            }
 
-                                if let Some(mac_address) = value.get("mac_address") {
-                                    let mac_string = mac_address.as_str().unwrap();
-                                    let cmd = ESP32CommandMessage {
-                                        command_type: ESP32CommandType::Actuator,
-                                        mac_address: mac_string.to_owned(),
-                                        payload: value.clone(),
-                                        actuator_mac_address: String::from("")
-                                    };
+            _ = managers.transition_manager.wait_for_next_step() => {
+                for value in managers.transition_manager.process_steps() {
+                    send_actuator_command(value, &mut dht_manager, &mut managers).await;
+                }
+            }
 
-                                    let _ret = wss_mgr.command_channel_tx.send(cmd);
+            _ = managers.gate_manager.wait_for_next_deadline() => {
+                for value in managers.gate_manager.process_pulses() {
+                    send_actuator_command(value, &mut dht_manager, &mut managers).await;
+                }
 
-                                }
+                let commands = managers.gate_manager.process_auto_close();
+                execute_volatile_commands(commands, &mut dht_manager, &mut managers).await;
+            }
 
-                                handle_shelly_command(value, &mut dht_manager, &mut shelly_manager).await;
-                            }
-                            DHTCommand::ValveCommand(value) => {
+        }
 
-                                if !shelly_plus_actuators.is_empty() {
-                                    if let Some(mac_address) = value.get("mac_address") {
+        publish_shutter_positions(&mut dht_manager, &mut managers).await;
 
-                                        //println!("Valve command {}", value);
+        for update in dht_manager.take_topic_updates() {
+            let mut commands = managers.gate_manager.handle_topic_update(&update);
 
-                                        let mac_string = mac_address.as_str().unwrap();
+            commands.extend(rules_engine.evaluate(&dht_manager, &update));
 
-                                        if let Some(best_act) = valve_command_manager.get_best_actuator_for_valve(mac_string) {
+            execute_volatile_commands(commands, &mut dht_manager, &mut managers).await;
+        }
+    }
+}
 
-                                            let vd = ValveData {
-                                                desired_state: value.clone(),
-                                                attempts: 1
-                                            };
+// executes commands generated inside the bridge, they follow the same path of
+// the volatile commands received from the dht
+async fn execute_volatile_commands(
+    commands: Vec<serde_json::Value>,
+    dht_manager: &mut DHTManager,
+    managers: &mut ActuatorManagers,
+) {
+    for command in commands {
+        if let Ok(cmd) = dht_manager.handle_volatile_command(command).await {
+            handle_dht_command(cmd, dht_manager, managers).await;
+        }
+    }
+}
 
-                                            valve_command_manager.insert(mac_string, vd);
+async fn handle_dht_command(
+    cmd: DHTCommand,
+    dht_manager: &mut DHTManager,
+    managers: &mut ActuatorManagers,
+) {
+    match cmd {
+        DHTCommand::ActuatorCommand(value) => {
+            //println!("Received actuator command");
+            send_actuator_command(value, dht_manager, managers).await;
+        }
+        DHTCommand::ValveCommand(value) => {
+            send_valve_command(value, managers);
+        }
+        DHTCommand::SceneCommand(scene) => {
+            handle_scene_command(scene, dht_manager, managers).await;
+        }
+        DHTCommand::ShutterCommand(command) => {
+            for value in managers.shutter_manager.handle_command(command) {
+                send_actuator_command(value, dht_manager, managers).await;
+            }
+        }
+        DHTCommand::GateCommand(command) => {
+            for value in managers.gate_manager.handle_command(command) {
+                send_actuator_command(value, dht_manager, managers).await;
+            }
+        }
+        DHTCommand::TransitionCommand(command) => {
+            for value in managers.transition_manager.start(command) {
+                send_actuator_command(value, dht_manager, managers).await;
+            }
+        }
+        DHTCommand::ScheduleCommand(value) => match scheduler::scheduled_command_topic(&value) {
+            Ok(topic) => {
+                let topic_uuid = new_topic_uuid();
+                log::info!("DOMO: NEW SCHEDULED COMMAND {}", topic_uuid);
+                dht_manager
+                    .write_topic(SCHEDULE_TOPIC_NAME, &topic_uuid, &topic)
+                    .await;
+            }
+            Err(e) => {
+                log::warn!("invalid schedule_command: {}", e);
+            }
+        },
+        DHTCommand::BleOnboardingCommand(value) => {
+            managers
+                .ble_onboarding
+                .handle_command(dht_manager, &value)
+                .await;
+        }
+        DHTCommand::BleAdoptCommand(value) => {
+            managers.ble_onboarding.adopt(dht_manager, &value).await;
+        }
+        DHTCommand::BleGattCommand(value) => {
+            let result = managers.gatt_command_manager.send(
+                &value,
+                &managers.valve_command_manager,
+                &managers.wss_mgr.command_channel_tx,
+            );
+
+            if let Some(result) = result {
+                dht_manager.publish_volatile(result).await;
+            }
+        }
+    }
+}
 
-                                            let cmd = ESP32CommandMessage {
-                                                command_type: ESP32CommandType::Valve,
-                                                mac_address: mac_string.to_owned(),
-                                                payload: value,
-                                                actuator_mac_address: best_act.clone()
-                                            };
+fn send_valve_command(value: serde_json::Value, managers: &mut ActuatorManagers) -> bool {
+    let valve_command_manager = &mut managers.valve_command_manager;
 
-                                            //println!("SENDING VALVE COMMAND TO {} ", best_act);
+    if !managers.shelly_plus_actuators.is_empty() {
+        if let Some(mac_address) = value.get("mac_address") {
+            //println!("Valve command {}", value);
 
-                                            let _ret = wss_mgr.command_channel_tx.send(cmd);
-                                        } else {
-                                            //println!("NO ACTUATOR for {} ", mac_string);
+            let mac_string = mac_address.as_str().unwrap();
 
-                                            let vd = ValveData {
-                                                desired_state: value.clone(),
-                                                attempts: 0
-                                            };
+            if let Some(best_act) = valve_command_manager.get_best_actuator_for_valve(mac_string) {
+                let mut vd = ValveData::new(value.clone(), 1);
+                vd.actuator_mac_address = Some(best_act.clone());
 
-                                            valve_command_manager.insert(mac_string, vd);
+                valve_command_manager.insert(mac_string, vd);
 
-                                        }
+                let cmd = ESP32CommandMessage {
+                    command_type: ESP32CommandType::Valve,
+                    mac_address: mac_string.to_owned(),
+                    payload: value,
+                    actuator_mac_address: best_act.clone(),
+                };
 
-                                    }
-                                }
-                            }
-                        }
-                    }
-            },
+                //println!("SENDING VALVE COMMAND TO {} ", best_act);
 
-            shelly_message = shelly_manager.wait_for_shelly_message() => {
+                let _ret = managers.wss_mgr.command_channel_tx.send(cmd);
+            } else {
+                //println!("NO ACTUATOR for {} ", mac_string);
 
-                if let Ok(message) = shelly_message {
-                        handle_shelly_message(message, &mut dht_manager).await;
+                let vd = ValveData::new(value.clone(), 0);
+
+                valve_command_manager.insert(mac_string, vd);
+            }
+
+            return true;
+        }
+    }
+
+    false
+}
+
+// all the commands of the scene are dispatched at once, then the result for
+// each target is published as a volatile message
+async fn handle_scene_command(
+    scene: SceneCommand,
+    dht_manager: &mut DHTManager,
+    managers: &mut ActuatorManagers,
+) {
+    let mut results = vec![];
+    let mut esp8266_actions = vec![];
+    let mut esp8266_results = vec![];
+
+    for target in scene.targets {
+        let result = match target.command {
+            Ok(DHTCommand::ActuatorCommand(value)) => {
+                let mac_address = value["mac_address"].as_str().unwrap_or_default().to_owned();
+
+                if managers
+                    .shelly_plus_actuators
+                    .iter()
+                    .any(|actuator| same_mac_address(actuator, &mac_address))
+                {
+                    let cmd = ESP32CommandMessage {
+                        command_type: ESP32CommandType::Actuator,
+                        mac_address,
+                        payload: value,
+                        actuator_mac_address: String::from(""),
+                    };
+
+                    let _ret = managers.wss_mgr.command_channel_tx.send(cmd);
+                    String::from("sent")
+                } else if let Some(message) = build_shelly_request(&value) {
+                    esp8266_results.push(results.len());
+                    esp8266_actions.push((mac_address, message));
+                    String::from("actuator_not_connected")
+                } else {
+                    String::from("not_able_to_parse_command")
                 }
             }
+            Ok(DHTCommand::ValveCommand(value)) => {
+                if send_valve_command(value, managers) {
+                    String::from("queued")
+                } else {
+                    String::from("actuator_not_connected")
+                }
+            }
+            Ok(DHTCommand::ShutterCommand(command)) => {
+                for value in managers.shutter_manager.handle_command(command) {
+                    send_actuator_command(value, dht_manager, managers).await;
+                }
+                String::from("sent")
+            }
+            Ok(DHTCommand::GateCommand(command)) => {
+                for value in managers.gate_manager.handle_command(command) {
+                    send_actuator_command(value, dht_manager, managers).await;
+                }
+                String::from("sent")
+            }
+            Ok(DHTCommand::TransitionCommand(command)) => {
+                for value in managers.transition_manager.start(command) {
+                    send_actuator_command(value, dht_manager, managers).await;
+                }
+                String::from("sent")
+            }
+            Ok(_) => String::from("unsupported_command"),
+            Err(e) => e,
+        };
+
+        results.push(serde_json::json!({
+            "topic_uuid": target.topic_uuid,
+            "result": result
+        }));
+    }
+
+    let sent = managers.shelly_manager.send_actions(&esp8266_actions).await;
 
+    for (idx, sent) in esp8266_results.into_iter().zip(sent) {
+        if sent {
+            results[idx]["result"] = serde_json::Value::String(String::from("sent"));
         }
     }
+
+    log::info!(
+        "DOMO: SCENE {} EXECUTED {}",
+        scene.scene_uuid,
+        get_epoch_ms()
+    );
+
+    dht_manager
+        .publish_volatile(serde_json::json!({
+            "scene_result": {
+                "scene_uuid": scene.scene_uuid,
+                "results": results
+            }
+        }))
+        .await;
+}
+
+// the estimated positions are written back to the shutter topics
+async fn publish_shutter_positions(dht_manager: &mut DHTManager, managers: &mut ActuatorManagers) {
+    for update in managers.shutter_manager.take_position_updates() {
+        let mut value = match dht_manager
+            .cache
+            .get_topic_uuid(&update.topic_name, &update.topic_uuid)
+        {
+            Ok(topic) => topic["value"].clone(),
+            Err(_) => continue,
+        };
+
+        value["shutter_position"] = serde_json::json!(update.position.round());
+        value["tilt_position"] = serde_json::json!(update.tilt.round());
+
+        dht_manager
+            .write_topic(&update.topic_name, &update.topic_uuid, &value)
+            .await;
+    }
+}
+
+// actuator commands are sent both to the esp32 websocket connections and to
+// the esp8266 shellies, the one owning the mac address will execute them
+async fn send_actuator_command(
+    value: serde_json::Value,
+    dht_manager: &mut DHTManager,
+    managers: &mut ActuatorManagers,
+) {
+    if let Some(mac_address) = value.get("mac_address") {
+        let mac_string = mac_address.as_str().unwrap();
+        let cmd = ESP32CommandMessage {
+            command_type: ESP32CommandType::Actuator,
+            mac_address: mac_string.to_owned(),
+            payload: value.clone(),
+            actuator_mac_address: String::from(""),
+        };
+
+        let _ret = managers.wss_mgr.command_channel_tx.send(cmd);
+    }
+
+    handle_shelly_command(value, dht_manager, &mut managers.shelly_manager).await;
 }
 
 async fn handle_cred_message(
@@ -390,7 +780,11 @@ async fn handle_cred_message(
     }
 }
 
-async fn handle_shelly_message(shelly_message: serde_json::Value, dht_manager: &mut DHTManager) {
+async fn handle_shelly_message(
+    shelly_message: serde_json::Value,
+    dht_manager: &mut DHTManager,
+    managers: &mut ActuatorManagers,
+) {
     println!("Received shelly message {}", get_epoch_ms());
 
     if let Some(message_type) = shelly_message.get("messageType") {
@@ -402,26 +796,33 @@ async fn handle_shelly_message(shelly_message: serde_json::Value, dht_manager: &
                     if let Ok(status_result) =
                         serde_json::from_str::<serde_json::Value>(status_string)
                     {
-                        let mac_address =
-                            status_result.get("mac_address").unwrap().as_str().unwrap();
-
-                        let mac_address_with_points = mac_address[0..2].to_owned()
-                            + ":"
-                            + &mac_address[2..4]
-                            + ":"
-                            + &mac_address[4..6]
-                            + ":"
-                            + &mac_address[6..8]
-                            + ":"
-                            + &mac_address[8..10]
-                            + ":"
-                            + &mac_address[10..12];
+                        let mac_address = match status_result["mac_address"]
+                            .as_str()
+                            .map(|mac_address| mac_address.parse::<MacAddress>())
+                        {
+                            Some(Ok(mac_address)) => mac_address,
+                            _ => {
+                                log::warn!("shelly status without mac_address {}", status_string);
+                                return;
+                            }
+                        };
+
+                        // direct input bindings are handled before any dht write
+                        dht_manager
+                            .input_bindings
+                            .update_output_states(&mac_address.to_string(), &status_result);
+
+                        let binding_commands = dht_manager
+                            .input_bindings
+                            .get_commands(&mac_address.to_string(), &status_result);
+
+                        for command in binding_commands {
+                            send_actuator_command(command, dht_manager, managers).await;
+                        }
 
                         let topic_name = status_result.get("topic_name").unwrap().as_str().unwrap();
 
-                        if let Ok(topic) =
-                            dht_manager.get_topic(topic_name, &mac_address_with_points)
-                        {
+                        if let Ok(topic) = dht_manager.get_topic(topic_name, &mac_address) {
                             let mut new_status = status_result.clone();
 
                             if let Some(value) = topic.get("value") {
@@ -450,6 +851,10 @@ async fn handle_shelly_message(shelly_message: serde_json::Value, dht_manager: &
                                                         );
                                                 }
 
+                                                if let Some(policy) = value.get("publish_policy") {
+                                                    new_status["publish_policy"] = policy.clone();
+                                                }
+
 
                                                 new_status["user_login"] =
                                                     serde_json::Value::String(
@@ -467,20 +872,31 @@ async fn handle_shelly_message(shelly_message: serde_json::Value, dht_manager: &
 
                                                 new_status["id"] = id.to_owned();
 
+                                                let now = sifis_dht::utils::get_epoch_ms() as u64;
+
                                                 new_status["last_update_timestamp"] =
-                                                    serde_json::Value::Number(Number::from(
-                                                        sifis_dht::utils::get_epoch_ms() as u64,
-                                                    ));
+                                                    serde_json::Value::Number(Number::from(now));
 
                                                 let topic_uuid =
                                                     topic["topic_uuid"].as_str().unwrap();
-                                                dht_manager
-                                                    .write_topic(
-                                                        topic_name,
-                                                        topic_uuid,
-                                                        &new_status,
-                                                    )
-                                                    .await;
+
+                                                // the actuator connections still get every
+                                                // update, they accumulate the energy
+                                                if managers.publish_filter.check(
+                                                    topic_name,
+                                                    topic_uuid,
+                                                    value,
+                                                    &mut new_status,
+                                                    now,
+                                                ) {
+                                                    dht_manager
+                                                        .write_topic(
+                                                            topic_name,
+                                                            topic_uuid,
+                                                            &new_status,
+                                                        )
+                                                        .await;
+                                                }
 
                                                 let _ret = update_actuator_connection(
                                                     dht_manager,
@@ -602,20 +1018,24 @@ async fn get_topic_from_actuator_topic(
 
             let rgbw_status: serde_json::Value = serde_json::from_str(rgbw_status_value_string)?;
 
-            let _val = 0;
-            if channel_number == 1 {
-                source_topic["value"]["status"] = rgbw_status["r"].clone();
-            }
-            if channel_number == 2 {
-                source_topic["value"]["status"] = rgbw_status["g"].clone();
-            }
+            let channel = match channel_number {
+                2 => "g",
+                3 => "b",
+                4 => "w",
+                _ => "r",
+            };
 
-            if channel_number == 3 {
-                source_topic["value"]["status"] = rgbw_status["b"].clone();
+            // the levels of the dimmable light go from 0 to 100
+            if let Some(value) = rgbw_status[channel].as_f64() {
+                source_topic["value"]["status"] =
+                    serde_json::json!(dimmer::rgbw_channel_to_level(value));
             }
+        }
 
-            if channel_number == 4 {
-                source_topic["value"]["status"] = rgbw_status["w"].clone();
+        // restored when the light is turned on without a level
+        if let Some(level) = source_topic["value"]["status"].as_f64() {
+            if level > 0.0 {
+                source_topic["value"]["last_level"] = serde_json::json!(level);
             }
         }
     }
@@ -696,8 +1116,22 @@ async fn get_topic_from_actuator_topic(
             actuator_topic["output".to_owned() + channel_number_str].clone();
     }
 
-    if source_topic_name == "domo_roller_shutter" || source_topic_name == "domo_garage_gate" {
+    if source_topic_name == GATE_TOPIC_NAME {
+        gate::update_gate_inputs(&mut source_topic["value"], actuator_topic);
+
+        if !gate::is_impulse_mode(&source_topic["value"]) {
+            source_topic["value"]["shutter_status"] = actuator_topic["shutter_status"].clone();
+        }
+    }
+
+    if source_topic_name == "domo_roller_shutter" {
         source_topic["value"]["shutter_status"] = actuator_topic["shutter_status"].clone();
+
+        // only some firmwares report the position, otherwise it is estimated
+        // by the shutter manager
+        if !actuator_topic["shutter_position"].is_null() {
+            source_topic["value"]["shutter_position"] = actuator_topic["shutter_position"].clone();
+        }
     }
 
     if source_topic_name == "domo_pir_sensor"
@@ -746,25 +1180,29 @@ async fn update_actuator_connection(
     actuator_topic: &serde_json::Value,
 ) -> Result<(), Box<dyn Error>> {
 
-    dht_manager.update_actuator_connections(&topic_name, &topic_uuid, actuator_topic).await;
+    dht_manager.update_actuator_connections(topic_name, topic_uuid, actuator_topic).await;
 
     Ok(())
 }
 
+fn build_shelly_request(shelly_command: &serde_json::Value) -> Option<serde_json::Value> {
+    let shelly_action_payload = shelly_command.get("shelly_action")?;
+
+    let shelly_action = serde_json::json!({ "shelly_action": shelly_action_payload });
+
+    Some(serde_json::json!({
+        "messageType": "requestAction",
+        "data": shelly_action
+    }))
+}
+
 async fn handle_shelly_command(
     shelly_command: serde_json::Value,
     _dht_manager: &mut DHTManager,
     shelly_manager: &mut GlobalShellyManager,
 ) {
     if let Some(mac_address) = shelly_command.get("mac_address") {
-        if let Some(shelly_action_payload) = shelly_command.get("shelly_action") {
-            let shelly_action = serde_json::json!({ "shelly_action": shelly_action_payload });
-
-            let message = serde_json::json!({
-                "messageType": "requestAction",
-                "data": shelly_action
-            });
-
+        if let Some(message) = build_shelly_request(&shelly_command) {
             let mac_address_str = mac_address.as_str().unwrap();
 
             //println!("DOMO: SENDING ACTION {}", get_epoch_ms());
@@ -793,54 +1231,39 @@ pub fn get_shelly_discovery_result(record: &Record) -> Option<ShellyDiscoveryRes
         return None;
     }
 
+    let ip_address = match record.kind {
+        RecordKind::A(addr) => addr.to_string(),
+        RecordKind::AAAA(addr) => addr.to_string(),
+        _ => return None,
+    };
+
+    // <topic name>-<mac address>.local, e.g. shelly_1-98CDAC1F0A7C.local
     let record_name = record.name.replace(".local", "");
+    let (topic_name, mac_address) = record_name.split_once('-')?;
 
-    match record.kind {
-        RecordKind::A(addr) => {
-            let name_parts: Vec<&str> = record_name.split('-').collect();
-            let topic_name = name_parts[0];
-            let mac_address = name_parts[1].to_owned();
-
-            let mac_address_with_points = mac_address[0..2].to_owned()
-                + ":"
-                + &mac_address[2..4]
-                + ":"
-                + &mac_address[4..6]
-                + ":"
-                + &mac_address[6..8]
-                + ":"
-                + &mac_address[8..10]
-                + ":"
-                + &mac_address[10..12];
-
-            let res = ShellyDiscoveryResult {
-                ip_address: addr.to_string(),
-                topic_name: topic_name.to_string(),
-                mac_address: mac_address_with_points,
-                mdns_name: record.name.to_owned(),
-            };
-            Some(res)
-        }
-        RecordKind::AAAA(addr) => {
-            let name_parts: Vec<&str> = record.name.split('-').collect();
-            let topic_name = name_parts[0];
-            let mac_address = name_parts[1];
-            let res = ShellyDiscoveryResult {
-                ip_address: addr.to_string(),
-                topic_name: topic_name.to_string(),
-                mac_address: mac_address.to_string(),
-                mdns_name: record.name.to_owned(),
-            };
-            Some(res)
+    let mac_address = match mac_address.parse::<MacAddress>() {
+        Ok(mac_address) => mac_address,
+        Err(_) => {
+            log::warn!("mdns record {} without mac address", record.name);
+            return None;
         }
-        _ => None,
-    }
+    };
+
+    Some(ShellyDiscoveryResult {
+        ip_address,
+        topic_name: topic_name.to_string(),
+        mac_address: mac_address.to_string(),
+        mdns_name: record.name.to_owned(),
+    })
 }
 
 async fn handle_ble_update_message(
     message: BleBeaconMessage,
     dht_manager: &mut DHTManager,
-    valve_command_manager: &mut ValveCommandManager,
+    ble_onboarding: &mut BleOnboarding,
+    ble_decoders: &BleDecoderRegistry,
+    replay_tracker: &mut ReplayTracker,
+    publish_filter: &mut PublishFilter,
 ) {
     let ret = dht_manager
         .get_actuator_from_mac_address(&message.mac_address)
@@ -849,180 +1272,143 @@ async fn handle_ble_update_message(
     if let Ok(topic) = ret {
         let topic_name = topic["topic_name"].as_str().unwrap();
 
-        if topic_name == "domo_ble_thermometer" {
-            //println!("THERMO UPDATE {}", message.payload);
-
-            if let Ok(bytes) = base64::decode(&message.payload) {
-                use hex::ToHex;
-                let beacon_adv_string = bytes.encode_hex::<String>();
-                //println!("BEACON THERMO ADV from {}: {}", message.mac_address, beacon_adv_string);
-
-                handle_ble_thermometer_update(
-                    dht_manager,
-                    &message.mac_address,
-                    &beacon_adv_string,
-                    &topic,
-                )
-                .await;
-            }
-        }
-
-        if topic_name == "domo_ble_contact" {
-            //println!("CONTACT UPDATE {}", message.payload);
+        if let Ok(bytes) = base64::decode(&message.payload) {
+            let value_of_topic = &topic["value"];
+            let mac_address = value_of_topic["mac_address"].as_str().unwrap_or_default();
+            let token = value_of_topic["token"].as_str();
 
-            if let Ok(bytes) = base64::decode(&message.payload) {
-                use hex::ToHex;
-                let beacon_adv_string = bytes.encode_hex::<String>();
-                //println!("BEACON CONTACT ADV from {}: {}", message.mac_address, beacon_adv_string);
-
-                handle_ble_contact_update(
-                    dht_manager,
-                    &message.mac_address,
-                    &beacon_adv_string,
-                    &message.rssi,
-                    &topic,
-                )
-                .await;
+            match ble_decoders.decode(topic_name, mac_address, &bytes, token) {
+                Some(Ok(ret)) => {
+                    handle_ble_readings_update(
+                        dht_manager,
+                        &topic,
+                        &ret,
+                        replay_tracker,
+                        publish_filter,
+                    )
+                    .await;
+                    return;
+                }
+                Some(Err(e)) => {
+                    log::debug!("advertisement from {}: {}", mac_address, e);
+                    return;
+                }
+                None => {}
             }
         }
 
         if topic_name == "domo_ble_valve" {
-            if message.payload == "0" || message.payload == "1" {
-                handle_ble_valve_update(
-                    dht_manager,
-                    &message.mac_address,
-                    &message.payload,
-                    &topic,
-                )
-                .await;
-            } else {
-                // update best actuator to use for valve depending on rssi
-                valve_command_manager.update_best_actuator(
-                    &message.mac_address,
-                    &message.actuator,
-                    message.rssi,
-                );
+            if let Some(report) = bleutils::parse_valve_report(&message.payload) {
+                handle_ble_valve_update(dht_manager, &message.mac_address, &report, &topic).await;
             }
         }
+    } else {
+        ble_onboarding.observe(dht_manager, &message).await;
     }
 }
 
-async fn handle_ble_thermometer_update(
+// writes the readings decoded from an advertisement into the topic of the
+// device, the same advertisement is usually repeated several times
+async fn handle_ble_readings_update(
     dht_manager: &mut DHTManager,
-    _mac_address: &str,
-    message: &str,
     topic: &serde_json::Value,
+    decoded: &DecodedAdvertisement,
+    replay_tracker: &mut ReplayTracker,
+    publish_filter: &mut PublishFilter,
 ) {
+    let topic_name = topic["topic_name"].as_str().unwrap();
     let topic_uuid = topic["topic_uuid"].as_str().unwrap();
     let value_of_topic = &topic["value"];
-    let token = value_of_topic["token"].as_str().unwrap();
-    let id = value_of_topic["id"].as_u64().unwrap();
-    let mac_address = value_of_topic["mac_address"].as_str().unwrap();
-    let name = value_of_topic["name"].as_str().unwrap();
-    let area_name = value_of_topic["area_name"].as_str().unwrap();
 
-    let ret = bleutils::parse_atc(mac_address, message, token);
-
-    if let Ok(m) = ret {
-        //println!("DECRITTATO {} {} {}", m.temperature, m.humidity, m.battery);
-        let value = serde_json::json!({
-            "temperature": m.temperature,
-            "humidity": m.humidity,
-            "battery":  m.battery,
-            "token": token,
-            "mac_address": mac_address,
-            "id": id,
-            "last_update_timestamp": serde_json::Value::Number(Number::from(sifis_dht::utils::get_epoch_ms() as u64)),
-            "name": name,
-            "area_name": area_name
-        });
+    let readings = &decoded.readings;
 
-        dht_manager
-            .write_topic("domo_ble_thermometer", topic_uuid, &value)
-            .await;
+    if readings.is_empty() {
+        return;
     }
-}
 
-async fn handle_ble_contact_update(
-    dht_manager: &mut DHTManager,
-    _mac_address: &str,
-    message: &str,
-    rssi: &i64,
-    topic: &serde_json::Value,
-) {
-    if message.len() >= 58 {
-        println!("MESSAGE: {}", message);
-        let topic_uuid = topic["topic_uuid"].as_str().unwrap();
-        let value_of_topic = &topic["value"];
-        let token = value_of_topic["token"].as_str().unwrap();
-        let id = value_of_topic["id"].as_u64().unwrap();
-        let mac_address = value_of_topic["mac_address"].as_str().unwrap();
-        let area_name = value_of_topic["area_name"].as_str().unwrap();
-
-        let len_hex_value = "1d";
-        let rssi_i = *rssi as i8;
-        let rssi_hex = format!("{:02x}", rssi_i);
-
-        let rssi_hex = rssi_hex.as_str();
-
-        let data = len_hex_value.to_owned() + message + rssi_hex;
-
-        //println!("mac {} token {} payload {}", mac_address, token, message);
-
-        let ret = bleutils::parse_contact_sensor(mac_address, &data, token);
-        if let Ok(m) = ret {
-            let val = u64::from(m.state != ContactStatus::Open);
-            //println!("Value_of_topic {}", value_of_topic);
-            if let Some(val_in_topic) = value_of_topic.get("status") {
-                //println!("{}", val_in_topic);
-                let val_in_topic = val_in_topic.as_u64().unwrap();
-                //println!("val {}, value_of_topic {}", val, val_in_topic);
-                if val != val_in_topic {
-                    let value = serde_json::json!({
-                    "status": val,
-                    "token": token,
-                    "last_update_timestamp": serde_json::Value::Number(Number::from(sifis_dht::utils::get_epoch_ms() as u64)),
-                    "mac_address": mac_address,
-                        "id": id,
-                        "area_name": area_name
-                     });
-
-                    dht_manager
-                        .write_topic("domo_ble_contact", topic_uuid, &value)
-                        .await;
-                    let _ret = update_actuator_connection(
-                        dht_manager,
-                        "domo_ble_contact",
-                        topic_uuid,
-                        &value,
-                    )
-                    .await;
+    let mac_address = value_of_topic["mac_address"].as_str().unwrap_or_default();
+
+    // the frame counters of the encrypted formats must go forward, the
+    // packet ids of the plain ones only identify the repetitions
+    let counter = match (decoded.counter, decoded.packet_id) {
+        (Some(counter), _) => {
+            let last_counter =
+                replay_tracker.last_counter(mac_address, value_of_topic["counter"].as_u64());
+
+            match counter.check(last_counter) {
+                CounterCheck::New => replay_tracker.accept(mac_address, counter.value),
+                CounterCheck::Duplicate => return,
+                CounterCheck::Stale => {
+                    let attempts = replay_tracker.record(mac_address);
+                    log::warn!(
+                        "replayed advertisement from {}, counter {} ({} attempts, {} in total)",
+                        mac_address,
+                        counter.value,
+                        attempts,
+                        replay_tracker.total()
+                    );
+                    return;
                 }
-            } else {
-                let value = serde_json::json!({
-                "status": val,
-                "token": token,
-                "mac_address": mac_address,
-                "last_update_timestamp": serde_json::Value::Number(Number::from(sifis_dht::utils::get_epoch_ms() as u64)),
-                "id": id,
-                "area_name": area_name
-                });
+            }
 
-                dht_manager
-                    .write_topic("domo_ble_contact", topic_uuid, &value)
-                    .await;
-                let _ret =
-                    update_actuator_connection(dht_manager, "domo_ble_contact", topic_uuid, &value)
-                        .await;
+            Some(("counter", counter.value))
+        }
+        (None, Some(packet_id)) => {
+            if value_of_topic["packet_id"].as_u64() == Some(packet_id) {
+                return;
             }
+
+            Some(("packet_id", packet_id))
         }
+        (None, None) => None,
+    };
+
+    let mut value = value_of_topic.clone();
+
+    // button events are only reported in the update where they happen
+    if let Some(v) = value.as_object_mut() {
+        v.remove("button_event");
+    }
+
+    bleutils::write_readings(&mut value, readings);
+
+    let replay_attempts = replay_tracker.attempts(mac_address);
+    if replay_attempts > 0 {
+        value["replay_attempts"] = serde_json::json!(replay_attempts);
+    }
+
+    // a stale sensor is reporting again
+    if value["stale"].as_bool() == Some(true) {
+        value["stale"] = serde_json::json!(false);
+    }
+
+    if let Some((field, counter)) = counter {
+        value[field] = serde_json::json!(counter);
+    }
+
+    let now = sifis_dht::utils::get_epoch_ms() as u64;
+
+    if !publish_filter.check(topic_name, topic_uuid, value_of_topic, &mut value, now) {
+        return;
+    }
+
+    value["last_update_timestamp"] = serde_json::Value::Number(Number::from(now));
+
+    let status_changed = value["status"] != value_of_topic["status"];
+
+    dht_manager
+        .write_topic(topic_name, topic_uuid, &value)
+        .await;
+
+    if status_changed {
+        let _ret = update_actuator_connection(dht_manager, topic_name, topic_uuid, &value).await;
     }
 }
 
 async fn handle_ble_valve_update(
     dht_manager: &mut DHTManager,
     _mac_address: &str,
-    message: &String,
+    report: &ValveReport,
     topic: &serde_json::Value,
 ) {
     let topic_uuid = topic["topic_uuid"].as_str().unwrap();
@@ -1032,11 +1418,8 @@ async fn handle_ble_valve_update(
     let area_name = value_of_topic["area_name"].as_str().unwrap();
     let id = value_of_topic["id"].as_u64().unwrap();
 
-
-    let value: bool = message == "1";
-
-    let value = serde_json::json!(
-    {   "status": value,
+    let mut value = serde_json::json!(
+    {   "status": report.status,
         "mac_address": mac_address,
         "id": id,
         "last_update_timestamp": serde_json::Value::Number(Number::from(sifis_dht::utils::get_epoch_ms() as u64)),
@@ -1044,12 +1427,37 @@ async fn handle_ble_valve_update(
         "area_name": area_name
     });
 
+    // the position of the legacy reports is unknown, the last one would
+    // confirm the commands regardless of the status
+    if let Some(position) = report.position {
+        value["position"] = serde_json::json!(position);
+    }
+
+    // the last known values are kept when the valve doesn't report them
+    for (field, reported) in [
+        (
+            "temperature",
+            report.temperature.map(|t| serde_json::json!(t)),
+        ),
+        ("battery", report.battery.map(|b| serde_json::json!(b))),
+    ] {
+        match reported {
+            Some(reported) => value[field] = reported,
+            None => {
+                if let Some(previous) = value_of_topic.get(field) {
+                    value[field] = previous.clone();
+                }
+            }
+        }
+    }
+
     dht_manager
         .write_topic("domo_ble_valve", topic_uuid, &value)
         .await;
 }
 
 async fn calculate_mode(
+    dht_manager: &DHTManager,
     act_connections: &Vec<serde_json::Value>,
     act_topic_name: &str,
     act_topic_uuid: &str,
@@ -1096,18 +1504,32 @@ async fn calculate_mode(
                             let target_topic_name = target_topic_name.as_str().unwrap();
                             let target_topic_uuid = target_topic_uuid.as_str().unwrap();
                             let source_topic_name = source_topic_name.as_str().unwrap();
-                            let _source_topic_uuid = source_topic_uuid.as_str().unwrap();
+                            let source_topic_uuid = source_topic_uuid.as_str().unwrap();
 
                             if target_topic_uuid == act_topic_uuid
                                 && target_topic_name == act_topic_name
                             {
                                 if (target_topic_name == "shelly_25"
                                     || target_topic_name == "shelly_2pm_plus")
-                                    && (source_topic_name == "domo_roller_shutter"
-                                        || source_topic_name == "domo_garage_gate")
+                                    && source_topic_name == "domo_roller_shutter"
                                 {
                                     return 1; // SHUTTER
                                 }
+                                if (target_topic_name == "shelly_25"
+                                    || target_topic_name == "shelly_2pm_plus")
+                                    && source_topic_name == GATE_TOPIC_NAME
+                                {
+                                    // impulse gates are driven by a relay pulse
+                                    let impulse_mode = dht_manager
+                                        .cache
+                                        .get_topic_uuid(GATE_TOPIC_NAME, source_topic_uuid)
+                                        .map(|t| gate::is_impulse_mode(&t["value"]))
+                                        .unwrap_or(false);
+
+                                    if !impulse_mode {
+                                        return 1; // SHUTTER
+                                    }
+                                }
                                 if target_topic_name == "shelly_rgbw"
                                     && source_topic_name == "domo_rgbw_light"
                                 {
@@ -1140,8 +1562,13 @@ async fn check_shelly_esp8266_mode(
                     let mode = mode.as_u64().unwrap();
                     let act_topic_name = topic_of_act["topic_name"].as_str().unwrap();
                     let act_topic_uuid = topic_of_act["topic_uuid"].as_str().unwrap();
-                    let desired_mode =
-                        calculate_mode(actuator_connections, act_topic_name, act_topic_uuid).await;
+                    let desired_mode = calculate_mode(
+                        dht_manager,
+                        actuator_connections,
+                        act_topic_name,
+                        act_topic_uuid,
+                    )
+                    .await;
 
                     let mut inverted = false;
                     if let Some(inv) = value.get("inverted") {
@@ -1203,8 +1630,13 @@ async fn check_shelly_esp32_mode(
                     let mode = mode.as_u64().unwrap();
                     let act_topic_name = topic_of_act["topic_name"].as_str().unwrap();
                     let act_topic_uuid = topic_of_act["topic_uuid"].as_str().unwrap();
-                    let desired_mode =
-                        calculate_mode(actuator_connections, act_topic_name, act_topic_uuid).await;
+                    let desired_mode = calculate_mode(
+                        dht_manager,
+                        actuator_connections,
+                        act_topic_name,
+                        act_topic_uuid,
+                    )
+                    .await;
 
                     let mut inverted = false;
                     if let Some(inv) = value.get("inverted") {
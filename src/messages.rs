@@ -1,3 +1,4 @@
+use crate::macaddress::{InvalidMacAddress, MacAddress};
 use serde::Serialize;
 
 use tokio::sync::oneshot;
@@ -15,6 +16,7 @@ pub struct AuthCredMessage {
 pub enum ESP32CommandType {
     Actuator,
     Valve,
+    Gatt,
     Ping,
 }
 #[derive(Debug, Clone, Serialize)]
@@ -34,7 +36,9 @@ pub struct BleBeaconMessage {
 }
 
 impl BleBeaconMessage {
-    pub fn from(socket_string: &str, actuator: &str) -> Self {
+    // "<mac address> <payload> <rssi>" as sent by the esp32 scanners, the
+    // mac addresses are stored in canonical format
+    pub fn from(socket_string: &str, actuator: &str) -> Result<Self, InvalidMacAddress> {
         let split = socket_string.split(' ');
         let mut mac_address = String::from("");
         let mut payload = String::from("");
@@ -42,7 +46,7 @@ impl BleBeaconMessage {
 
         for (count, part) in split.enumerate() {
             if count == 0 {
-                mac_address = part.to_string();
+                mac_address = part.parse::<MacAddress>()?.to_string();
             }
             if count == 1 {
                 payload = part.to_string();
@@ -57,23 +61,22 @@ impl BleBeaconMessage {
             }
         }
 
-        let act_address_with_points = actuator[0..2].to_owned()
-            + ":"
-            + &actuator[2..4]
-            + ":"
-            + &actuator[4..6]
-            + ":"
-            + &actuator[6..8]
-            + ":"
-            + &actuator[8..10]
-            + ":"
-            + &actuator[10..12];
-
-        BleBeaconMessage {
-            actuator: act_address_with_points,
+        Ok(BleBeaconMessage {
+            actuator: actuator.parse::<MacAddress>()?.to_string(),
             mac_address,
             payload,
             rssi,
-        }
+        })
+    }
+}
+
+// advertisement received by a scanner in the tests, the payload is base64
+#[cfg(test)]
+pub fn beacon(scanner: &str, mac_address: &str, payload: &str, rssi: i64) -> BleBeaconMessage {
+    BleBeaconMessage {
+        actuator: scanner.to_owned(),
+        mac_address: mac_address.to_owned(),
+        payload: payload.to_owned(),
+        rssi,
     }
 }
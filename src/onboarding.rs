@@ -0,0 +1,450 @@
+use crate::bleutils::error::BleError;
+use crate::bleutils::registry::{BleDecoderRegistry, SENSOR_TOPIC_NAMES};
+use crate::bleutils::{self, BleReading};
+use crate::dhtmanager::DHTManager;
+use crate::macaddress::MacAddress;
+use crate::messages::BleBeaconMessage;
+use crate::utils::new_topic_uuid;
+use std::collections::HashMap;
+use std::error::Error;
+use tokio::time::{Duration, Instant};
+
+// Onboarding of the BLE sensors. The onboarding mode is started with the
+// volatile command
+//
+// {
+//   "command": {
+//     "command_type": "ble_onboarding_command",
+//     "value": { "enabled": true, "duration_secs": 600 }
+//   }
+// }
+//
+// and, while it is on, the advertisements of the devices without a topic are
+// classified by the BLE decoders. Every device in a known format is written in
+// a domo_ble_discovered_device topic, whose uuid is the mac address, e.g.
+//
+// {
+//   "mac_address": "54:48:e6:8f:80:a5",
+//   "format": "bthome",
+//   "encrypted": true,
+//   "suggested_topic_name": "domo_ble_sensor",
+//   "scanner": "a0:b7:65:00:00:01",
+//   "rssi": -71,
+//   "payload": "AgEGEhbS/EGkcmbJX3MAESIzeCNyFA==",
+//   "last_seen_timestamp": 1700000000000
+// }
+//
+// with the readings of the unencrypted ones. A discovered device is adopted
+// with
+//
+// {
+//   "command": {
+//     "command_type": "ble_adopt_command",
+//     "value": {
+//       "mac_address": "54:48:e6:8f:80:a5",
+//       "token": "231d39c1d7cc1ab1aee224cd096db932",
+//       "topic_name": "domo_ble_thermometer",
+//       "name": "living room thermometer"
+//     }
+//   }
+// }
+//
+// The key of an encrypted device is checked against its last advertisement
+// and, when topic_name is missing, the topic is chosen from the readings. The
+// bridge writes the topic of the device, deletes the discovered one and
+// publishes a ble_adopt_result. The discovered devices are deleted when the
+// onboarding mode ends.
+
+pub const DISCOVERED_DEVICE_TOPIC_NAME: &str = "domo_ble_discovered_device";
+
+const DEFAULT_ONBOARDING_SECS: u64 = 600;
+
+// the discovered devices are written again at most this often
+const REFRESH_INTERVAL_SECS: u64 = 30;
+
+// in crowded places the onboarding doesn't flood the dht
+const MAX_DISCOVERED_DEVICES: usize = 100;
+
+struct DiscoveredDevice {
+    format: &'static str,
+    encrypted: bool,
+    suggested_topic_name: &'static str,
+    readings: Vec<BleReading>,
+    // last advertisement, used to check the key on adoption
+    adv: Vec<u8>,
+    published: Option<Instant>,
+}
+
+fn suggested_topic_name(format: &str, readings: &[BleReading]) -> &'static str {
+    let has = |f: fn(&BleReading) -> bool| readings.iter().any(f);
+
+    if format == "atc" || has(|r| matches!(r, BleReading::Temperature(_))) {
+        "domo_ble_thermometer"
+    } else if has(|r| matches!(r, BleReading::Door(_))) {
+        "domo_ble_contact"
+    } else if has(|r| matches!(r, BleReading::Motion(_))) {
+        "domo_ble_motion"
+    } else if has(|r| matches!(r, BleReading::Button(_))) {
+        "domo_ble_button"
+    } else {
+        "domo_ble_sensor"
+    }
+}
+
+pub struct BleOnboarding {
+    until: Option<Instant>,
+    devices: HashMap<MacAddress, DiscoveredDevice>,
+    decoders: BleDecoderRegistry,
+}
+
+impl BleOnboarding {
+    pub fn new() -> Self {
+        BleOnboarding {
+            until: None,
+            devices: HashMap::new(),
+            decoders: BleDecoderRegistry::default(),
+        }
+    }
+
+    fn is_active(&self, now: Instant) -> bool {
+        matches!(self.until, Some(until) if now < until)
+    }
+
+    pub async fn handle_command(
+        &mut self,
+        dht_manager: &mut DHTManager,
+        command: &serde_json::Value,
+    ) {
+        if command["enabled"].as_bool().unwrap_or(true) {
+            let duration_secs = command["duration_secs"]
+                .as_u64()
+                .unwrap_or(DEFAULT_ONBOARDING_SECS);
+
+            log::info!("BLE ONBOARDING STARTED FOR {} SECS", duration_secs);
+
+            self.until = Some(Instant::now() + Duration::from_secs(duration_secs));
+        } else {
+            self.stop(dht_manager).await;
+        }
+    }
+
+    async fn stop(&mut self, dht_manager: &mut DHTManager) {
+        log::info!("BLE ONBOARDING STOPPED");
+
+        self.until = None;
+
+        for (mac_address, device) in self.devices.drain() {
+            if device.published.is_some() {
+                dht_manager
+                    .delete_topic(DISCOVERED_DEVICE_TOPIC_NAME, &mac_address.to_string())
+                    .await;
+            }
+        }
+    }
+
+    // called for the advertisements of the devices without a topic
+    pub async fn observe(&mut self, dht_manager: &mut DHTManager, message: &BleBeaconMessage) {
+        let now = Instant::now();
+
+        if self.until.is_some() && !self.is_active(now) {
+            self.stop(dht_manager).await;
+            return;
+        }
+
+        // the topics are named after the canonical mac address of the value
+        if let Some(value) = self.observe_at(message, now) {
+            let topic_uuid = value["mac_address"].as_str().unwrap_or_default();
+
+            dht_manager
+                .write_topic(DISCOVERED_DEVICE_TOPIC_NAME, topic_uuid, &value)
+                .await;
+        }
+    }
+
+    // the value of the discovered device topic, when it has to be written
+    fn observe_at(
+        &mut self,
+        message: &BleBeaconMessage,
+        now: Instant,
+    ) -> Option<serde_json::Value> {
+        if !self.is_active(now) {
+            return None;
+        }
+
+        let key = message.mac_address.parse::<MacAddress>().ok()?;
+
+        if !self.devices.contains_key(&key) && self.devices.len() >= MAX_DISCOVERED_DEVICES {
+            return None;
+        }
+
+        let adv = base64::decode(&message.payload).ok()?;
+
+        // only the devices in the formats of the decoders can be adopted
+        let decoder = self.decoders.find_any(&adv)?;
+
+        let (encrypted, readings) = match decoder.decode(&key.to_string(), &adv, None) {
+            Ok(decoded) => (false, decoded.readings),
+            Err(BleError::MissingToken(_)) => (true, vec![]),
+            Err(_) => return None,
+        };
+
+        let suggested = suggested_topic_name(decoder.name(), &readings);
+
+        let published = self.devices.get(&key).and_then(|device| {
+            if device.suggested_topic_name == suggested {
+                device.published
+            } else {
+                None
+            }
+        });
+
+        let refresh = match published {
+            Some(published) => {
+                now.saturating_duration_since(published)
+                    >= Duration::from_secs(REFRESH_INTERVAL_SECS)
+            }
+            None => true,
+        };
+
+        let device = DiscoveredDevice {
+            format: decoder.name(),
+            encrypted,
+            suggested_topic_name: suggested,
+            readings,
+            adv,
+            published: if refresh { Some(now) } else { published },
+        };
+
+        let value = if refresh {
+            if published.is_none() {
+                log::debug!(
+                    "BLE DEVICE DISCOVERED {} {} {}",
+                    key,
+                    device.format,
+                    device.suggested_topic_name
+                );
+            }
+
+            let mut value = serde_json::json!({
+                "mac_address": key.to_string(),
+                "format": device.format,
+                "encrypted": device.encrypted,
+                "suggested_topic_name": device.suggested_topic_name,
+                "scanner": message.actuator,
+                "rssi": message.rssi,
+                "payload": message.payload,
+                "last_seen_timestamp": sifis_dht::utils::get_epoch_ms() as u64
+            });
+
+            bleutils::write_readings(&mut value, &device.readings);
+
+            Some(value)
+        } else {
+            None
+        };
+
+        self.devices.insert(key, device);
+
+        value
+    }
+
+    pub async fn adopt(&mut self, dht_manager: &mut DHTManager, command: &serde_json::Value) {
+        let result = match self.adoption(command) {
+            Ok((key, topic_name, value)) => {
+                let topic_uuid = new_topic_uuid();
+
+                log::info!(
+                    "BLE DEVICE {} ADOPTED AS {} {}",
+                    key,
+                    topic_name,
+                    topic_uuid
+                );
+
+                dht_manager
+                    .write_topic(topic_name, &topic_uuid, &value)
+                    .await;
+                dht_manager
+                    .delete_topic(DISCOVERED_DEVICE_TOPIC_NAME, &key.to_string())
+                    .await;
+
+                self.devices.remove(&key);
+
+                serde_json::json!({
+                    "mac_address": value["mac_address"],
+                    "topic_name": topic_name,
+                    "topic_uuid": topic_uuid,
+                    "result": "adopted"
+                })
+            }
+            Err(e) => {
+                log::warn!("invalid ble_adopt_command: {}", e);
+
+                serde_json::json!({
+                    "mac_address": command["mac_address"],
+                    "result": e.to_string()
+                })
+            }
+        };
+
+        dht_manager
+            .publish_volatile(serde_json::json!({ "ble_adopt_result": result }))
+            .await;
+    }
+
+    // key of the discovered device, topic name and value of the new topic
+    fn adoption(
+        &self,
+        command: &serde_json::Value,
+    ) -> Result<(MacAddress, &'static str, serde_json::Value), Box<dyn Error>> {
+        let key = command["mac_address"]
+            .as_str()
+            .ok_or("err_mac_address")?
+            .parse::<MacAddress>()?;
+
+        let device = self.devices.get(&key).ok_or("err_device_not_discovered")?;
+
+        let token = command["token"].as_str();
+
+        if device.encrypted && token.is_none() {
+            return Err("err_missing_token".into());
+        }
+
+        let decoder = self
+            .decoders
+            .find_any(&device.adv)
+            .ok_or("err_unknown_format")?;
+
+        // a wrong key doesn't decrypt the last advertisement
+        let decoded = decoder.decode(&key.to_string(), &device.adv, token)?;
+
+        let topic_name = match command["topic_name"].as_str() {
+            Some(topic_name) => SENSOR_TOPIC_NAMES
+                .into_iter()
+                .find(|name| *name == topic_name)
+                .ok_or("err_topic_name")?,
+            None => suggested_topic_name(device.format, &decoded.readings),
+        };
+
+        let mut value = serde_json::json!({ "mac_address": key.to_string() });
+
+        if let Some(token) = token {
+            value["token"] = serde_json::json!(token);
+        }
+
+        for field in ["name", "area_name"] {
+            if let Some(v) = command[field].as_str() {
+                value[field] = serde_json::json!(v);
+            }
+        }
+
+        Ok((key, topic_name, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::beacon;
+
+    const SCANNER: &str = "a0:b7:65:00:00:01";
+
+    fn payload(adv: &str) -> String {
+        base64::encode(hex::decode(adv).unwrap())
+    }
+
+    fn active_onboarding(now: Instant) -> BleOnboarding {
+        let mut onboarding = BleOnboarding::new();
+        onboarding.until = Some(now + Duration::from_secs(DEFAULT_ONBOARDING_SECS));
+        onboarding
+    }
+
+    #[test]
+    fn test_discovery() {
+        let now = Instant::now();
+        let ruuvi = beacon(
+            SCANNER,
+            "CB:B8:33:4C:88:4F",
+            &payload("0201061bff99040512fc5394c37c0004fffc040cac364200cdcbb8334c884f"),
+            -70,
+        );
+
+        let mut onboarding = BleOnboarding::new();
+        assert!(onboarding.observe_at(&ruuvi, now).is_none());
+
+        let mut onboarding = active_onboarding(now);
+        let value = onboarding.observe_at(&ruuvi, now).unwrap();
+        assert_eq!(value["mac_address"], "cb:b8:33:4c:88:4f");
+        assert_eq!(value["format"], "ruuvi");
+        assert_eq!(value["encrypted"], false);
+        assert_eq!(value["suggested_topic_name"], "domo_ble_thermometer");
+        assert_eq!(value["temperature"], 24.3);
+
+        // written again only after the refresh interval
+        assert!(onboarding.observe_at(&ruuvi, now).is_none());
+        assert!(onboarding
+            .observe_at(&ruuvi, now + Duration::from_secs(REFRESH_INTERVAL_SECS))
+            .is_some());
+
+        // unknown formats are ignored
+        let unknown = beacon(
+            SCANNER,
+            "00:11:22:33:44:55",
+            &payload("0201060303aafe"),
+            -70,
+        );
+        assert!(onboarding.observe_at(&unknown, now).is_none());
+        assert_eq!(onboarding.devices.len(), 1);
+    }
+
+    #[test]
+    fn test_adoption() {
+        let now = Instant::now();
+        let mac_address = "54:48:e6:8f:80:a5";
+        let key = "231d39c1d7cc1ab1aee224cd096db932";
+
+        let mut onboarding = active_onboarding(now);
+        let value = onboarding
+            .observe_at(
+                &beacon(
+                    SCANNER,
+                    mac_address,
+                    &payload("0201061216d2fc41a47266c95f730011223378237214"),
+                    -70,
+                ),
+                now,
+            )
+            .unwrap();
+        assert_eq!(value["format"], "bthome");
+        assert_eq!(value["encrypted"], true);
+        assert_eq!(value["suggested_topic_name"], "domo_ble_sensor");
+
+        let command = |token: Option<&str>| {
+            serde_json::json!({
+                "mac_address": "54:48:E6:8F:80:A5",
+                "token": token,
+                "name": "living room"
+            })
+        };
+
+        assert!(onboarding.adoption(&command(None)).is_err());
+        assert!(onboarding
+            .adoption(&command(Some("00112233445566778899aabbccddeeff")))
+            .is_err());
+
+        let (device, topic_name, value) = onboarding.adoption(&command(Some(key))).unwrap();
+        assert_eq!(device.to_string(), mac_address);
+        assert_eq!(topic_name, "domo_ble_thermometer");
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "mac_address": mac_address,
+                "token": key,
+                "name": "living room"
+            })
+        );
+
+        let mut invalid = command(Some(key));
+        invalid["topic_name"] = serde_json::json!("domo_ble_valve");
+        assert!(onboarding.adoption(&invalid).is_err());
+    }
+}
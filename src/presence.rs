@@ -0,0 +1,283 @@
+use crate::dhtmanager::DHTManager;
+use crate::macaddress::MacAddress;
+use crate::utils::RssiEma;
+use std::collections::{HashMap, HashSet};
+use tokio::time::{Duration, Instant};
+
+// BLE presence tags (phones, keyfobs, wristbands) are stored in the DHT as
+// domo_ble_presence_tag topics, e.g.
+//
+// {
+//   "name": "alice keyfob",
+//   "mac_address": "c4:7c:8d:6a:12:34",
+//   "away_timeout_secs": 300,
+//   "min_rssi": -90
+// }
+//
+// Every scanner receiving the advertisements of a tag keeps a smoothed RSSI
+// of it, the tag is in the area_name of the nearest scanner, which has to be
+// stronger by ROOM_HYSTERESIS_DB to take over from the current one. The
+// bridge writes back "presence" ("home" or "away"), "area_name", "scanner",
+// "rssi" and "last_seen_timestamp" when the presence or the room change.
+
+pub const PRESENCE_TAG_TOPIC_NAME: &str = "domo_ble_presence_tag";
+
+const ROOM_HYSTERESIS_DB: f64 = 5.0;
+
+// scanners not receiving the tag for this time are ignored for the room
+const SCANNER_TIMEOUT_SECS: u64 = 60;
+
+const DEFAULT_AWAY_TIMEOUT_SECS: u64 = 300;
+
+const DEFAULT_MIN_RSSI: f64 = -100.0;
+
+struct TagState {
+    scanners: HashMap<String, RssiEma>,
+    room_scanner: Option<String>,
+    last_seen: Option<Instant>,
+    min_rssi: f64,
+}
+
+impl TagState {
+    fn new() -> Self {
+        TagState {
+            scanners: HashMap::new(),
+            room_scanner: None,
+            last_seen: None,
+            min_rssi: DEFAULT_MIN_RSSI,
+        }
+    }
+
+    fn observe(&mut self, scanner: &str, rssi: i64, now: Instant) {
+        self.scanners
+            .entry(scanner.to_lowercase())
+            .and_modify(|ema| ema.update(rssi, now))
+            .or_insert_with(|| RssiEma::new(rssi, now));
+
+        self.last_seen = Some(now);
+    }
+
+    // nearest scanner and its smoothed RSSI
+    fn update_room(&mut self, now: Instant) -> Option<(String, f64)> {
+        let timeout = Duration::from_secs(SCANNER_TIMEOUT_SECS);
+        let min_rssi = self.min_rssi;
+
+        self.scanners
+            .retain(|_, ema| now.saturating_duration_since(ema.last_update) < timeout);
+
+        let candidates: Vec<(&String, f64)> = self
+            .scanners
+            .iter()
+            .filter(|(_, ema)| ema.value >= min_rssi)
+            .map(|(scanner, ema)| (scanner, ema.value))
+            .collect();
+
+        let best = candidates
+            .iter()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(scanner, rssi)| ((*scanner).clone(), *rssi));
+
+        let current = self.room_scanner.as_ref().and_then(|room| {
+            candidates
+                .iter()
+                .find(|(scanner, _)| *scanner == room)
+                .map(|(scanner, rssi)| ((*scanner).clone(), *rssi))
+        });
+
+        let room = match (current, best) {
+            (Some(current), Some(best)) if best.1 < current.1 + ROOM_HYSTERESIS_DB => Some(current),
+            (_, best) => best,
+        };
+
+        self.room_scanner = room.as_ref().map(|(scanner, _)| scanner.clone());
+
+        room
+    }
+
+    fn is_home(&self, away_timeout: Duration, now: Instant) -> bool {
+        match self.last_seen {
+            Some(last_seen) => now.saturating_duration_since(last_seen) < away_timeout,
+            None => false,
+        }
+    }
+}
+
+pub struct PresenceManager {
+    tags: HashMap<MacAddress, TagState>,
+}
+
+impl PresenceManager {
+    pub fn new() -> Self {
+        PresenceManager {
+            tags: HashMap::new(),
+        }
+    }
+
+    // called for every advertisement received by every scanner, the ones of
+    // devices that are not presence tags are ignored
+    pub fn observe(&mut self, mac_address: &str, scanner: &str, rssi: i64) {
+        self.observe_at(mac_address, scanner, rssi, Instant::now());
+    }
+
+    fn observe_at(&mut self, mac_address: &str, scanner: &str, rssi: i64, now: Instant) {
+        let tag = mac_address
+            .parse::<MacAddress>()
+            .ok()
+            .and_then(|mac_address| self.tags.get_mut(&mac_address));
+
+        if let Some(tag) = tag {
+            tag.observe(scanner, rssi, now);
+        }
+    }
+
+    pub async fn check(&mut self, dht_manager: &mut DHTManager) {
+        let topics = match dht_manager.cache.get_topic_name(PRESENCE_TAG_TOPIC_NAME) {
+            Ok(topics) => topics,
+            Err(_) => return,
+        };
+
+        let topics = match topics.as_array() {
+            Some(topics) => topics.to_owned(),
+            None => return,
+        };
+
+        let now = Instant::now();
+        let mut configured = HashSet::new();
+
+        for topic in topics {
+            let topic_uuid = match topic["topic_uuid"].as_str() {
+                Some(uuid) => uuid,
+                None => continue,
+            };
+
+            let value = &topic["value"];
+
+            let mac_address = match value["mac_address"].as_str() {
+                Some(mac_address) => match mac_address.parse::<MacAddress>() {
+                    Ok(mac_address) => mac_address,
+                    Err(_) => {
+                        log::warn!("presence tag {} with invalid mac_address", topic_uuid);
+                        continue;
+                    }
+                },
+                None => {
+                    log::warn!("presence tag {} without mac_address", topic_uuid);
+                    continue;
+                }
+            };
+
+            let away_timeout = Duration::from_secs(
+                value["away_timeout_secs"]
+                    .as_u64()
+                    .unwrap_or(DEFAULT_AWAY_TIMEOUT_SECS),
+            );
+
+            let tag = self.tags.entry(mac_address).or_insert_with(TagState::new);
+
+            tag.min_rssi = value["min_rssi"].as_f64().unwrap_or(DEFAULT_MIN_RSSI);
+
+            let home = tag.is_home(away_timeout, now);
+            let room = if home { tag.update_room(now) } else { None };
+
+            let last_seen_ms = tag
+                .last_seen
+                .map(|last_seen| now.saturating_duration_since(last_seen).as_millis() as u64);
+
+            configured.insert(mac_address);
+
+            let area_name = match &room {
+                Some((scanner, _)) => {
+                    match dht_manager.get_actuator_from_mac_address(scanner).await {
+                        Ok(scanner_topic) => scanner_topic["value"]["area_name"].clone(),
+                        Err(_) => serde_json::Value::Null,
+                    }
+                }
+                None => serde_json::Value::Null,
+            };
+
+            let presence = if home { "home" } else { "away" };
+            let scanner = room.as_ref().map(|(scanner, _)| scanner.clone());
+
+            if value["presence"].as_str() == Some(presence)
+                && value["area_name"] == area_name
+                && value["scanner"].as_str() == scanner.as_deref()
+            {
+                continue;
+            }
+
+            log::info!(
+                "PRESENCE TAG {} {} {}",
+                value["name"].as_str().unwrap_or(topic_uuid),
+                presence,
+                area_name
+            );
+
+            let mut new_value = value.clone();
+            new_value["presence"] = serde_json::json!(presence);
+            new_value["area_name"] = area_name;
+            new_value["scanner"] = serde_json::json!(scanner);
+            new_value["rssi"] = serde_json::json!(room.map(|(_, rssi)| rssi.round()));
+
+            if let Some(last_seen_ms) = last_seen_ms {
+                new_value["last_seen_timestamp"] = serde_json::json!(
+                    (sifis_dht::utils::get_epoch_ms() as u64).saturating_sub(last_seen_ms)
+                );
+            }
+
+            dht_manager
+                .write_topic(PRESENCE_TAG_TOPIC_NAME, topic_uuid, &new_value)
+                .await;
+        }
+
+        self.tags
+            .retain(|mac_address, _| configured.contains(mac_address));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_room_hysteresis() {
+        let start = Instant::now();
+        let mut tag = TagState::new();
+
+        tag.observe("aa:aa:aa:aa:aa:01", -60, start);
+        tag.observe("aa:aa:aa:aa:aa:02", -70, start);
+        assert_eq!(tag.update_room(start).unwrap().0, "aa:aa:aa:aa:aa:01");
+
+        // slightly stronger is not enough to change room
+        tag.observe("aa:aa:aa:aa:aa:02", -50, start);
+        assert_eq!(tag.update_room(start).unwrap().0, "aa:aa:aa:aa:aa:01");
+
+        for _ in 0..10 {
+            tag.observe("aa:aa:aa:aa:aa:02", -45, start);
+        }
+        assert_eq!(tag.update_room(start).unwrap().0, "aa:aa:aa:aa:aa:02");
+
+        // the scanners stop receiving the tag
+        let later = start + Duration::from_secs(SCANNER_TIMEOUT_SECS + 1);
+        assert!(tag.update_room(later).is_none());
+    }
+
+    #[test]
+    fn test_presence() {
+        let start = Instant::now();
+        let mut manager = PresenceManager::new();
+
+        // configured in another format than the one of the scanners
+        let mac_address: MacAddress = "C47C8D6A1234".parse().unwrap();
+        manager.tags.insert(mac_address, TagState::new());
+
+        manager.observe_at("c4:7c:8d:6a:12:34", "aa:aa:aa:aa:aa:01", -80, start);
+        manager.observe_at("c4:7c:8d:00:00:00", "aa:aa:aa:aa:aa:01", -80, start);
+        manager.observe_at("c4:7c:8d", "aa:aa:aa:aa:aa:01", -80, start);
+        assert_eq!(manager.tags.len(), 1);
+
+        let tag = &manager.tags[&mac_address];
+        let timeout = Duration::from_secs(DEFAULT_AWAY_TIMEOUT_SECS);
+        assert!(tag.is_home(timeout, start + Duration::from_secs(10)));
+        assert!(!tag.is_home(timeout, start + timeout));
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+
+// Publish policy of the values written by the bridge for the BLE sensors and
+// the Shelly actuators, so that the dht isn't flooded by updates that don't
+// carry any news. For each numeric field with a deadband a change is
+// published only when larger than the deadband and at least
+// min_interval_secs after the last write; the changes of the other fields are
+// published immediately. Without changes the value is written again after
+// heartbeat_secs, to show the device is alive. A field jumping by more than
+// its max_jump is an outlier and discarded, unless the next value confirms
+// it.
+//
+// The defaults of each topic type are extended by the "publish_policy" field
+// of the topic, e.g.
+//
+// {
+//   "publish_policy": {
+//     "deadband": { "temperature": 0.2 },
+//     "max_jump": { "temperature": 5.0 },
+//     "min_interval_secs": 30,
+//     "heartbeat_secs": 600
+//   }
+// }
+//
+// The deadbands apply to the nested fields too, by name; a name also matches
+// the fields followed by a channel number, e.g. "power" matches "power1".
+
+// fields changing at every write, not a change of the value
+const IGNORED_FIELDS: [&str; 4] = [
+    "last_update_timestamp",
+    "counter",
+    "packet_id",
+    "updated_properties",
+];
+
+// fields only present in the update where the event happens
+const EVENT_FIELDS: [&str; 1] = ["button_event"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishPolicy {
+    pub deadband: HashMap<String, f64>,
+    pub max_jump: HashMap<String, f64>,
+    pub min_interval_secs: u64,
+    // 0 for no heartbeat
+    pub heartbeat_secs: u64,
+}
+
+fn to_map(fields: &[(&str, f64)]) -> HashMap<String, f64> {
+    fields
+        .iter()
+        .map(|(field, value)| (field.to_string(), *value))
+        .collect()
+}
+
+impl PublishPolicy {
+    // a topic type without a policy publishes every change
+    pub fn none() -> Self {
+        PublishPolicy {
+            deadband: HashMap::new(),
+            max_jump: HashMap::new(),
+            min_interval_secs: 0,
+            heartbeat_secs: 0,
+        }
+    }
+
+    fn defaults(topic_name: &str) -> Self {
+        if topic_name.starts_with("domo_ble_") && topic_name != "domo_ble_valve" {
+            PublishPolicy {
+                deadband: to_map(&[
+                    ("temperature", 0.1),
+                    ("humidity", 1.0),
+                    ("pressure", 0.5),
+                    ("illuminance", 10.0),
+                    ("battery", 2.0),
+                    ("battery_voltage", 0.05),
+                ]),
+                max_jump: to_map(&[("temperature", 10.0), ("humidity", 30.0)]),
+                min_interval_secs: 10,
+                heartbeat_secs: 300,
+            }
+        } else if topic_name.starts_with("shelly_") {
+            PublishPolicy {
+                deadband: to_map(&[
+                    ("power", 5.0),
+                    ("active_power", 5.0),
+                    ("apparent_power", 5.0),
+                    ("energy", 10.0),
+                    ("voltage", 2.0),
+                    ("current", 0.05),
+                ]),
+                max_jump: HashMap::new(),
+                min_interval_secs: 5,
+                heartbeat_secs: 300,
+            }
+        } else {
+            PublishPolicy::none()
+        }
+    }
+
+    pub fn for_topic(topic_name: &str, value: &serde_json::Value) -> Self {
+        let mut policy = PublishPolicy::defaults(topic_name);
+
+        let custom = &value["publish_policy"];
+
+        if custom.is_null() {
+            return policy;
+        }
+
+        if !custom.is_object() {
+            log::warn!("invalid publish_policy of {}: {}", topic_name, custom);
+            return policy;
+        }
+
+        for (field, map) in [
+            ("deadband", &mut policy.deadband),
+            ("max_jump", &mut policy.max_jump),
+        ] {
+            if let Some(custom) = custom[field].as_object() {
+                for (name, v) in custom {
+                    match v.as_f64() {
+                        Some(v) => {
+                            map.insert(name.to_owned(), v);
+                        }
+                        None => log::warn!("invalid publish_policy {} of {}", field, name),
+                    }
+                }
+            }
+        }
+
+        if let Some(secs) = custom["min_interval_secs"].as_u64() {
+            policy.min_interval_secs = secs;
+        }
+
+        if let Some(secs) = custom["heartbeat_secs"].as_u64() {
+            policy.heartbeat_secs = secs;
+        }
+
+        policy
+    }
+
+    fn lookup<'a>(map: &'a HashMap<String, f64>, name: &str) -> Option<&'a f64> {
+        map.get(name)
+            .or_else(|| map.get(name.trim_end_matches(|c: char| c.is_ascii_digit())))
+    }
+}
+
+// leaves of a json value with their path and name
+fn leaves<'a>(
+    value: &'a serde_json::Value,
+    path: String,
+    name: &'a str,
+    out: &mut Vec<(String, &'a str, &'a serde_json::Value)>,
+) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (field, v) in fields {
+                let path = if path.is_empty() {
+                    field.to_owned()
+                } else {
+                    path.clone() + "." + field
+                };
+                leaves(v, path, field, out);
+            }
+        }
+        _ => out.push((path, name, value)),
+    }
+}
+
+fn get_path<'a>(value: &'a serde_json::Value, path: &str) -> &'a serde_json::Value {
+    path.split('.').fold(value, |v, field| &v[field])
+}
+
+pub struct PublishFilter {
+    // values discarded as outliers, by topic uuid and field
+    outliers: HashMap<(String, String), f64>,
+}
+
+impl PublishFilter {
+    pub fn new() -> Self {
+        PublishFilter {
+            outliers: HashMap::new(),
+        }
+    }
+
+    // whether new_value has to be written over old_value, the outliers in
+    // new_value are replaced by the old values
+    pub fn check(
+        &mut self,
+        topic_name: &str,
+        topic_uuid: &str,
+        old_value: &serde_json::Value,
+        new_value: &mut serde_json::Value,
+        now_ms: u64,
+    ) -> bool {
+        let policy = PublishPolicy::for_topic(topic_name, old_value);
+
+        if old_value.is_null() {
+            return true;
+        }
+
+        self.reject_outliers(topic_uuid, &policy, old_value, new_value);
+
+        if EVENT_FIELDS
+            .iter()
+            .any(|field| !new_value[*field].is_null())
+        {
+            return true;
+        }
+
+        let mut new_leaves = vec![];
+        leaves(new_value, String::new(), "", &mut new_leaves);
+
+        let mut immediate = false;
+        let mut significant = false;
+
+        for (path, name, new) in new_leaves {
+            let top_level = path.split('.').next().unwrap_or_default();
+            if IGNORED_FIELDS.contains(&top_level) {
+                continue;
+            }
+
+            let old = get_path(old_value, &path);
+
+            if old == new {
+                continue;
+            }
+
+            match (
+                PublishPolicy::lookup(&policy.deadband, name),
+                old.as_f64(),
+                new.as_f64(),
+            ) {
+                (Some(deadband), Some(old), Some(new)) => {
+                    if (new - old).abs() > *deadband {
+                        significant = true;
+                    }
+                }
+                _ => immediate = true,
+            }
+        }
+
+        // fields removed
+        if let (Some(old), Some(new)) = (old_value.as_object(), new_value.as_object()) {
+            if old
+                .keys()
+                .any(|field| !new.contains_key(field) && !IGNORED_FIELDS.contains(&field.as_str()))
+            {
+                immediate = true;
+            }
+        }
+
+        let elapsed_ms =
+            now_ms.saturating_sub(old_value["last_update_timestamp"].as_u64().unwrap_or(0));
+
+        immediate
+            || (significant && elapsed_ms >= policy.min_interval_secs * 1000)
+            || (policy.heartbeat_secs > 0 && elapsed_ms >= policy.heartbeat_secs * 1000)
+    }
+
+    fn reject_outliers(
+        &mut self,
+        topic_uuid: &str,
+        policy: &PublishPolicy,
+        old_value: &serde_json::Value,
+        new_value: &mut serde_json::Value,
+    ) {
+        for (field, max_jump) in &policy.max_jump {
+            let key = (topic_uuid.to_owned(), field.to_owned());
+
+            let (old, new) = match (old_value[field].as_f64(), new_value[field].as_f64()) {
+                (Some(old), Some(new)) => (old, new),
+                _ => {
+                    self.outliers.remove(&key);
+                    continue;
+                }
+            };
+
+            if (new - old).abs() <= *max_jump {
+                self.outliers.remove(&key);
+                continue;
+            }
+
+            // a second value close to the discarded one confirms the jump
+            if let Some(outlier) = self.outliers.remove(&key) {
+                if (new - outlier).abs() <= *max_jump {
+                    continue;
+                }
+            }
+
+            log::warn!(
+                "outlier {} of {} discarded: {} after {}",
+                field,
+                topic_uuid,
+                new,
+                old
+            );
+
+            self.outliers.insert(key, new);
+            new_value[field] = old_value[field].clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ble_policy() {
+        let mut filter = PublishFilter::new();
+        let old = serde_json::json!({
+            "temperature": 20.0,
+            "humidity": 50.0,
+            "counter": 10,
+            "last_update_timestamp": 1_000_000
+        });
+
+        let check = |filter: &mut PublishFilter, new: serde_json::Value, now: u64| {
+            let mut new = new;
+            filter.check("domo_ble_thermometer", "t", &old, &mut new, now)
+        };
+
+        // inside the deadband, until the heartbeat
+        let same = serde_json::json!({ "temperature": 20.05, "humidity": 50.0, "counter": 11 });
+        assert!(!check(&mut filter, same.clone(), 1_060_000));
+        assert!(check(&mut filter, same, 1_300_000));
+
+        // out of the deadband, after the minimum interval
+        let changed = serde_json::json!({ "temperature": 20.3, "humidity": 50.0, "counter": 11 });
+        assert!(!check(&mut filter, changed.clone(), 1_005_000));
+        assert!(check(&mut filter, changed, 1_010_000));
+
+        // the other fields are published immediately
+        let door = serde_json::json!({ "temperature": 20.0, "humidity": 50.0, "stale": false });
+        assert!(check(&mut filter, door, 1_001_000));
+
+        let button =
+            serde_json::json!({ "temperature": 20.0, "humidity": 50.0, "button_event": "press" });
+        assert!(check(&mut filter, button, 1_001_000));
+    }
+
+    #[test]
+    fn test_outliers() {
+        let mut filter = PublishFilter::new();
+        let old = serde_json::json!({ "temperature": 20.0, "last_update_timestamp": 0 });
+
+        let mut new = serde_json::json!({ "temperature": 85.0 });
+        filter.check("domo_ble_thermometer", "t", &old, &mut new, 60_000);
+        assert_eq!(new["temperature"], 20.0);
+
+        // confirmed by the next value
+        let mut new = serde_json::json!({ "temperature": 84.5 });
+        assert!(filter.check("domo_ble_thermometer", "t", &old, &mut new, 60_000));
+        assert_eq!(new["temperature"], 84.5);
+    }
+
+    #[test]
+    fn test_shelly_policy() {
+        let mut filter = PublishFilter::new();
+        let old = serde_json::json!({
+            "status1": true,
+            "power1": 100.0,
+            "power_data": { "channel1": { "active_power": 100.0, "energy": 1.0 } },
+            "updated_properties": ["power1"],
+            "last_update_timestamp": 0
+        });
+
+        let mut new = serde_json::json!({
+            "status1": true,
+            "power1": 102.0,
+            "power_data": { "channel1": { "active_power": 103.0, "energy": 2.0 } },
+            "updated_properties": ["power_data"]
+        });
+        assert!(!filter.check("shelly_1pm", "s", &old, &mut new, 60_000));
+
+        new["status1"] = serde_json::json!(false);
+        assert!(filter.check("shelly_1pm", "s", &old, &mut new, 1000));
+
+        let policy = PublishPolicy::for_topic(
+            "shelly_1pm",
+            &serde_json::json!({ "publish_policy": { "deadband": { "power": 1.0 }, "heartbeat_secs": 0 } }),
+        );
+        assert_eq!(policy.deadband["power"], 1.0);
+        assert_eq!(policy.heartbeat_secs, 0);
+        assert_eq!(
+            PublishPolicy::for_topic("domo_light", &old),
+            PublishPolicy::none()
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use crate::dhtmanager::{DHTManager, TopicUpdate};
+use chrono::{Datelike, Local, Timelike};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+// Automation rules are stored in the DHT as domo_automation_rule topics, e.g.
+//
+// {
+//   "name": "hallway light on motion",
+//   "enabled": true,
+//   "trigger": { "topic_name": "domo_pir_sensor", "topic_uuid": "...", "field": "status", "value": true },
+//   "conditions": [
+//     { "condition_type": "time", "from": "18:00", "to": "06:30", "weekdays": [1, 2, 3, 4, 5] },
+//     { "condition_type": "topic", "topic_name": "domo_light", "topic_uuid": "...", "field": "status", "operator": "eq", "value": false }
+//   ],
+//   "actions": [
+//     { "command_type": "turn_command", "value": { "topic_uuid": "...", "desired_state": true } }
+//   ],
+//   "cooldown_secs": 5
+// }
+//
+// The actions use the same format of the volatile commands handled by command_parser.
+//
+// A rule is triggered only by the topics written by this bridge, i.e. by the
+// devices it receives; the updates coming from the other nodes of the DHT
+// are not evaluated, otherwise every bridge would fire the same rule. The
+// conditions are checked against the cache, so they may refer to any topic.
+
+pub const RULE_TOPIC_NAME: &str = "domo_automation_rule";
+
+#[derive(Debug, Deserialize)]
+pub struct RuleTrigger {
+    pub topic_name: String,
+    pub topic_uuid: String,
+    pub field: String,
+    pub value: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "condition_type", rename_all = "snake_case")]
+pub enum RuleCondition {
+    Time {
+        from: Option<String>,
+        to: Option<String>,
+        weekdays: Option<Vec<u32>>,
+    },
+    Topic {
+        topic_name: String,
+        topic_uuid: String,
+        field: String,
+        #[serde(default = "default_operator")]
+        operator: String,
+        value: serde_json::Value,
+    },
+}
+
+fn default_operator() -> String {
+    String::from("eq")
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Rule {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub trigger: RuleTrigger,
+    #[serde(default)]
+    pub conditions: Vec<RuleCondition>,
+    pub actions: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub cooldown_secs: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+pub struct RulesEngine {
+    last_fired: HashMap<String, SystemTime>,
+}
+
+impl RulesEngine {
+    pub fn new() -> Self {
+        RulesEngine {
+            last_fired: HashMap::new(),
+        }
+    }
+
+    // returns the volatile commands to execute as a consequence of the update
+    pub fn evaluate(
+        &mut self,
+        dht_manager: &DHTManager,
+        update: &TopicUpdate,
+    ) -> Vec<serde_json::Value> {
+        let mut commands = vec![];
+
+        let rules = match dht_manager.cache.get_topic_name(RULE_TOPIC_NAME) {
+            Ok(rules) => rules,
+            Err(_) => return commands,
+        };
+
+        let rules = match rules.as_array() {
+            Some(rules) => rules,
+            None => return commands,
+        };
+
+        for rule_topic in rules {
+            let rule_uuid = match rule_topic["topic_uuid"].as_str() {
+                Some(uuid) => uuid,
+                None => continue,
+            };
+
+            let rule = match serde_json::from_value::<Rule>(rule_topic["value"].clone()) {
+                Ok(rule) => rule,
+                Err(e) => {
+                    log::warn!("invalid automation rule {}: {}", rule_uuid, e);
+                    continue;
+                }
+            };
+
+            if !rule.enabled || !is_triggered(&rule.trigger, update) {
+                continue;
+            }
+
+            if let Some(last) = self.last_fired.get(rule_uuid) {
+                if let Ok(elapsed) = last.elapsed() {
+                    if elapsed.as_secs() < rule.cooldown_secs {
+                        continue;
+                    }
+                }
+            }
+
+            let conditions_ok = rule
+                .conditions
+                .iter()
+                .all(|c| check_condition(dht_manager, c));
+
+            if !conditions_ok {
+                continue;
+            }
+
+            log::info!("AUTOMATION RULE {} {} fired", rule_uuid, rule.name);
+
+            self.last_fired
+                .insert(rule_uuid.to_owned(), SystemTime::now());
+
+            for action in rule.actions {
+                commands.push(serde_json::json!({ "command": action }));
+            }
+        }
+
+        commands
+    }
+}
+
+fn is_triggered(trigger: &RuleTrigger, update: &TopicUpdate) -> bool {
+    if trigger.topic_name != update.topic_name || trigger.topic_uuid != update.topic_uuid {
+        return false;
+    }
+
+    let new_field = &update.new_value[&trigger.field];
+
+    if new_field.is_null() || values_match(new_field, &update.old_value[&trigger.field]) {
+        return false;
+    }
+
+    match &trigger.value {
+        Some(value) => values_match(new_field, value),
+        None => true,
+    }
+}
+
+fn check_condition(dht_manager: &DHTManager, condition: &RuleCondition) -> bool {
+    match condition {
+        RuleCondition::Time { from, to, weekdays } => {
+            let now = Local::now();
+
+            if let Some(weekdays) = weekdays {
+                if !weekdays.contains(&now.weekday().number_from_monday()) {
+                    return false;
+                }
+            }
+
+            let now_minutes = now.hour() * 60 + now.minute();
+
+            let from = from.as_deref().and_then(parse_time_of_day);
+            let to = to.as_deref().and_then(parse_time_of_day);
+
+            match (from, to) {
+                (Some(from), Some(to)) => time_in_range(now_minutes, from, to),
+                (Some(from), None) => now_minutes >= from,
+                (None, Some(to)) => now_minutes < to,
+                (None, None) => true,
+            }
+        }
+        RuleCondition::Topic {
+            topic_name,
+            topic_uuid,
+            field,
+            operator,
+            value,
+        } => {
+            if let Ok(topic) = dht_manager.cache.get_topic_uuid(topic_name, topic_uuid) {
+                compare(&topic["value"][field], operator, value)
+            } else {
+                false
+            }
+        }
+    }
+}
+
+// "HH:MM" -> minutes from midnight
+pub fn parse_time_of_day(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let hours: u32 = hours.trim().parse().ok()?;
+    let minutes: u32 = minutes.trim().parse().ok()?;
+
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+
+    Some(hours * 60 + minutes)
+}
+
+// the range may cross midnight, e.g. from 22:00 to 06:00
+pub fn time_in_range(now: u32, from: u32, to: u32) -> bool {
+    if from <= to {
+        now >= from && now < to
+    } else {
+        now >= from || now < to
+    }
+}
+
+fn as_number(value: &serde_json::Value) -> Option<f64> {
+    if let Some(b) = value.as_bool() {
+        return Some(if b { 1.0 } else { 0.0 });
+    }
+    value.as_f64()
+}
+
+// shelly inputs are reported as booleans while the ble contacts use 0/1
+pub fn values_match(a: &serde_json::Value, b: &serde_json::Value) -> bool {
+    if a == b {
+        return true;
+    }
+
+    match (as_number(a), as_number(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+pub fn compare(current: &serde_json::Value, operator: &str, value: &serde_json::Value) -> bool {
+    match operator {
+        "eq" => values_match(current, value),
+        "ne" => !values_match(current, value),
+        _ => {
+            let (current, value) = match (as_number(current), as_number(value)) {
+                (Some(c), Some(v)) => (c, v),
+                _ => return false,
+            };
+
+            match operator {
+                "gt" => current > value,
+                "ge" => current >= value,
+                "lt" => current < value,
+                "le" => current <= value,
+                _ => false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_range() {
+        let from = parse_time_of_day("22:00").unwrap();
+        let to = parse_time_of_day("06:30").unwrap();
+
+        assert!(time_in_range(23 * 60, from, to));
+        assert!(time_in_range(6 * 60, from, to));
+        assert!(!time_in_range(12 * 60, from, to));
+        assert!(time_in_range(12 * 60, 8 * 60, 18 * 60));
+        assert!(parse_time_of_day("25:00").is_none());
+    }
+
+    #[test]
+    fn test_compare() {
+        assert!(compare(
+            &serde_json::json!(true),
+            "eq",
+            &serde_json::json!(1)
+        ));
+        assert!(compare(
+            &serde_json::json!(0),
+            "ne",
+            &serde_json::json!(true)
+        ));
+        assert!(compare(
+            &serde_json::json!(21.5),
+            "gt",
+            &serde_json::json!(20)
+        ));
+        assert!(!compare(
+            &serde_json::json!("on"),
+            "gt",
+            &serde_json::json!(20)
+        ));
+    }
+
+    #[test]
+    fn test_trigger() {
+        let trigger = RuleTrigger {
+            topic_name: String::from("domo_pir_sensor"),
+            topic_uuid: String::from("pir"),
+            field: String::from("status"),
+            value: Some(serde_json::json!(true)),
+        };
+
+        let mut update = TopicUpdate {
+            topic_name: String::from("domo_pir_sensor"),
+            topic_uuid: String::from("pir"),
+            old_value: serde_json::json!({ "status": false }),
+            new_value: serde_json::json!({ "status": true }),
+        };
+
+        assert!(is_triggered(&trigger, &update));
+
+        update.old_value = serde_json::json!({ "status": true });
+        assert!(!is_triggered(&trigger, &update));
+
+        update.old_value = serde_json::json!({ "status": true });
+        update.new_value = serde_json::json!({ "status": false });
+        assert!(!is_triggered(&trigger, &update));
+    }
+}
@@ -0,0 +1,423 @@
+use crate::dhtmanager::DHTManager;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Timelike, Utc};
+use serde::Deserialize;
+use std::error::Error;
+
+// Scheduled commands are stored in the DHT as domo_scheduled_command topics,
+// so that they survive the restart of the bridge, e.g.
+//
+// {
+//   "name": "close shutters at sunset",
+//   "enabled": true,
+//   "schedule": { "schedule_type": "sun", "event": "sunset", "offset_minutes": -15 },
+//   "command": { "command_type": "shutter_command", "value": { "topic_uuid": "...", "shutter_command": "down" } }
+// }
+//
+// schedule_type can be "delay" (one shot, "fire_at" epoch ms), "cron"
+// ("expression" with minute hour day-of-month month day-of-week) or "sun".
+// The bridge writes back last_fire_timestamp after every execution and
+// removes the delayed commands once executed.
+
+pub const SCHEDULE_TOPIC_NAME: &str = "domo_scheduled_command";
+
+// commands missed for less than this, e.g. during a restart, are still executed
+const GRACE_PERIOD_SECS: i64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SunEvent {
+    Sunrise,
+    Sunset,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "schedule_type", rename_all = "snake_case")]
+pub enum Schedule {
+    Delay {
+        fire_at: i64,
+    },
+    Cron {
+        expression: String,
+    },
+    Sun {
+        event: SunEvent,
+        #[serde(default)]
+        offset_minutes: i64,
+    },
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduledCommand {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub schedule: Schedule,
+    pub command: serde_json::Value,
+    pub last_fire_timestamp: Option<i64>,
+}
+
+pub struct Scheduler {
+    // latitude, longitude used for the sun related schedules
+    coordinates: Option<(f64, f64)>,
+}
+
+impl Scheduler {
+    pub fn new(latitude: Option<f64>, longitude: Option<f64>) -> Self {
+        let coordinates = match (latitude, longitude) {
+            (Some(lat), Some(lon)) => Some((lat, lon)),
+            _ => None,
+        };
+
+        Scheduler { coordinates }
+    }
+
+    // returns the volatile commands that have to be executed now
+    pub async fn check(&mut self, dht_manager: &mut DHTManager) -> Vec<serde_json::Value> {
+        let mut commands = vec![];
+
+        let topics = match dht_manager.cache.get_topic_name(SCHEDULE_TOPIC_NAME) {
+            Ok(topics) => topics,
+            Err(_) => return commands,
+        };
+
+        let topics = match topics.as_array() {
+            Some(topics) => topics.to_owned(),
+            None => return commands,
+        };
+
+        let now = Local::now();
+
+        for topic in topics {
+            let topic_uuid = match topic["topic_uuid"].as_str() {
+                Some(uuid) => uuid,
+                None => continue,
+            };
+
+            let scheduled = match serde_json::from_value::<ScheduledCommand>(topic["value"].clone())
+            {
+                Ok(s) => s,
+                Err(e) => {
+                    log::warn!("invalid scheduled command {}: {}", topic_uuid, e);
+                    continue;
+                }
+            };
+
+            if !scheduled.enabled {
+                continue;
+            }
+
+            let fire = match self.should_fire(&scheduled, now) {
+                Ok(fire) => fire,
+                Err(e) => {
+                    log::warn!("scheduled command {}: {}", topic_uuid, e);
+                    continue;
+                }
+            };
+
+            if !fire {
+                continue;
+            }
+
+            log::info!("SCHEDULED COMMAND {} {} fired", topic_uuid, scheduled.name);
+
+            commands.push(serde_json::json!({ "command": scheduled.command }));
+
+            if let Schedule::Delay { .. } = scheduled.schedule {
+                dht_manager
+                    .delete_topic(SCHEDULE_TOPIC_NAME, topic_uuid)
+                    .await;
+            } else {
+                let mut value = topic["value"].clone();
+                value["last_fire_timestamp"] = serde_json::json!(now.timestamp_millis());
+                dht_manager
+                    .write_topic(SCHEDULE_TOPIC_NAME, topic_uuid, &value)
+                    .await;
+            }
+        }
+
+        commands
+    }
+
+    fn should_fire(
+        &self,
+        scheduled: &ScheduledCommand,
+        now: DateTime<Local>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let last_fire = scheduled
+            .last_fire_timestamp
+            .and_then(|t| Local.timestamp_millis_opt(t).single());
+
+        match &scheduled.schedule {
+            Schedule::Delay { fire_at } => Ok(now.timestamp_millis() >= *fire_at),
+            Schedule::Cron { expression } => {
+                let cron = CronExpression::parse(expression)?;
+
+                let mut from = now - Duration::seconds(GRACE_PERIOD_SECS);
+                if let Some(last_fire) = last_fire {
+                    if last_fire > from {
+                        from = last_fire;
+                    }
+                }
+
+                // check every minute in (from, now]
+                let mut minute = truncate_to_minute(now);
+                while minute > from {
+                    if cron.matches(&minute) {
+                        return Ok(last_fire
+                            .map(|l| truncate_to_minute(l) < minute)
+                            .unwrap_or(true));
+                    }
+                    minute -= Duration::minutes(1);
+                }
+
+                Ok(false)
+            }
+            Schedule::Sun {
+                event,
+                offset_minutes,
+            } => {
+                let (latitude, longitude) = self.coordinates.ok_or("coordinates not configured")?;
+
+                let event_time = sun_event_time(now.date_naive(), latitude, longitude, *event)
+                    .ok_or("no sun event for today")?
+                    .with_timezone(&Local)
+                    + Duration::minutes(*offset_minutes);
+
+                if now < event_time || (now - event_time).num_seconds() > GRACE_PERIOD_SECS {
+                    return Ok(false);
+                }
+
+                Ok(last_fire.map(|l| l < event_time).unwrap_or(true))
+            }
+        }
+    }
+}
+
+fn truncate_to_minute(time: DateTime<Local>) -> DateTime<Local> {
+    time - Duration::seconds(time.second() as i64) - Duration::nanoseconds(time.nanosecond() as i64)
+}
+
+// builds the topic of a schedule_command volatile command, that contains either
+// "delay_secs" or a "schedule" together with the "command" to execute
+pub fn scheduled_command_topic(
+    value: &serde_json::Value,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    let command = value.get("command").ok_or("err_command")?;
+    let name = value["name"].as_str().unwrap_or_default();
+
+    let schedule = if let Some(delay_secs) = value["delay_secs"].as_u64() {
+        let fire_at = Local::now().timestamp_millis() + (delay_secs as i64) * 1000;
+        serde_json::json!({ "schedule_type": "delay", "fire_at": fire_at })
+    } else {
+        let schedule = value.get("schedule").ok_or("err_schedule")?;
+        // validate it before storing it
+        if let Schedule::Cron { expression } = serde_json::from_value(schedule.to_owned())? {
+            CronExpression::parse(&expression)?;
+        }
+        schedule.to_owned()
+    };
+
+    Ok(serde_json::json!({
+        "name": name,
+        "enabled": true,
+        "schedule": schedule,
+        "command": command
+    }))
+}
+
+pub struct CronExpression {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+fn parse_cron_field(field: &str, min: u64, max: u64) -> Result<u64, Box<dyn Error>> {
+    let mut mask = 0_u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>()?),
+            None => (part, 1),
+        };
+
+        if step == 0 {
+            return Err("invalid cron step".into());
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (start.parse::<u64>()?, end.parse::<u64>()?)
+        } else {
+            let value = range.parse::<u64>()?;
+            // "5/15" means from 5 to the end of the range
+            if part.contains('/') {
+                (value, max)
+            } else {
+                (value, value)
+            }
+        };
+
+        if start < min || end > max || start > end {
+            return Err("cron value out of range".into());
+        }
+
+        let mut value = start;
+        while value <= end {
+            mask |= 1 << value;
+            value += step;
+        }
+    }
+
+    Ok(mask)
+}
+
+impl CronExpression {
+    pub fn parse(expression: &str) -> Result<CronExpression, Box<dyn Error>> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+
+        if fields.len() != 5 {
+            return Err("cron expression must have 5 fields".into());
+        }
+
+        let mut days_of_week = parse_cron_field(fields[4], 0, 7)?;
+
+        // both 0 and 7 are sunday
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+
+        Ok(CronExpression {
+            minutes: parse_cron_field(fields[0], 0, 59)?,
+            hours: parse_cron_field(fields[1], 0, 23)?,
+            days_of_month: parse_cron_field(fields[2], 1, 31)?,
+            months: parse_cron_field(fields[3], 1, 12)?,
+            days_of_week,
+            any_day_of_month: fields[2] == "*",
+            any_day_of_week: fields[4] == "*",
+        })
+    }
+
+    pub fn matches<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let bit = |mask: u64, value: u32| mask & (1 << value) != 0;
+
+        if !bit(self.minutes, time.minute())
+            || !bit(self.hours, time.hour())
+            || !bit(self.months, time.month())
+        {
+            return false;
+        }
+
+        let dom = bit(self.days_of_month, time.day());
+        let dow = bit(self.days_of_week, time.weekday().num_days_from_sunday());
+
+        // as in cron, when both the days are restricted either can match
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (true, false) => dow,
+            (false, true) => dom,
+            (false, false) => dom || dow,
+        }
+    }
+}
+
+// sunrise equation, returns None when the sun doesn't rise or set on the day
+pub fn sun_event_time(
+    date: NaiveDate,
+    latitude: f64,
+    longitude: f64,
+    event: SunEvent,
+) -> Option<DateTime<Utc>> {
+    let days_since_unix_epoch = (date - NaiveDate::from_ymd_opt(1970, 1, 1)?).num_days() as f64;
+    let julian_date = 2440587.5 + days_since_unix_epoch;
+
+    let n = (julian_date - 2451545.0 + 0.0008).ceil();
+    let mean_solar_time = n - longitude / 360.0;
+
+    let mean_anomaly = (357.5291 + 0.98560028 * mean_solar_time) % 360.0;
+    let m = mean_anomaly.to_radians();
+
+    let center = 1.9148 * m.sin() + 0.02 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+    let ecliptic_longitude = ((mean_anomaly + center + 180.0 + 102.9372) % 360.0).to_radians();
+
+    let transit =
+        2451545.0 + mean_solar_time + 0.0053 * m.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+    let sin_declination = ecliptic_longitude.sin() * 23.4397_f64.to_radians().sin();
+    let cos_declination = sin_declination.asin().cos();
+
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = ((-0.833_f64).to_radians().sin() - latitude.sin() * sin_declination)
+        / (latitude.cos() * cos_declination);
+
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+
+    let julian_event = match event {
+        SunEvent::Sunrise => transit - hour_angle / 360.0,
+        SunEvent::Sunset => transit + hour_angle / 360.0,
+    };
+
+    let unix_secs = (julian_event - 2440587.5) * 86400.0;
+
+    Utc.timestamp_opt(unix_secs as i64, 0).single()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cron_expression() {
+        let cron = CronExpression::parse("30 7 * * 1-5").unwrap();
+
+        // 2023-06-21 is a wednesday
+        let time = Utc.with_ymd_and_hms(2023, 6, 21, 7, 30, 0).unwrap();
+        assert!(cron.matches(&time));
+
+        let time = Utc.with_ymd_and_hms(2023, 6, 24, 7, 30, 0).unwrap();
+        assert!(!cron.matches(&time));
+
+        let cron = CronExpression::parse("*/15 * * * *").unwrap();
+        let time = Utc.with_ymd_and_hms(2023, 6, 24, 12, 45, 0).unwrap();
+        assert!(cron.matches(&time));
+        let time = Utc.with_ymd_and_hms(2023, 6, 24, 12, 46, 0).unwrap();
+        assert!(!cron.matches(&time));
+
+        let cron = CronExpression::parse("0 12 * * 7").unwrap();
+        let time = Utc.with_ymd_and_hms(2023, 6, 25, 12, 0, 0).unwrap();
+        assert!(cron.matches(&time));
+
+        assert!(CronExpression::parse("61 * * * *").is_err());
+        assert!(CronExpression::parse("* * *").is_err());
+    }
+
+    #[test]
+    fn test_sun_event_time() {
+        // Pisa, winter solstice: sunrise 07:48 CET, sunset 16:44 CET
+        let date = NaiveDate::from_ymd_opt(2023, 12, 21).unwrap();
+
+        let sunrise = sun_event_time(date, 43.72, 10.40, SunEvent::Sunrise).unwrap();
+        let expected = Utc.with_ymd_and_hms(2023, 12, 21, 6, 48, 0).unwrap();
+        assert!((sunrise - expected).num_minutes().abs() <= 3);
+
+        let sunset = sun_event_time(date, 43.72, 10.40, SunEvent::Sunset).unwrap();
+        let expected = Utc.with_ymd_and_hms(2023, 12, 21, 15, 44, 0).unwrap();
+        assert!((sunset - expected).num_minutes().abs() <= 3);
+
+        // polar night
+        assert!(sun_event_time(date, 78.22, 15.65, SunEvent::Sunrise).is_none());
+    }
+}
@@ -117,15 +117,14 @@ impl ShellyManager {
 
     pub async fn new(
         ip: &str,
-        topic_name: &str,
         mac_address: &String,
         mdns_name: &str,
         user_login: &str,
         user_password: &str,
     ) -> Result<ShellyManager, Box<dyn Error>> {
-        let mac = mac_address.replace(':', "");
-        let mac = mac.as_str();
-        let url = "wss://".to_owned() + mdns_name + "/things/" + topic_name + "-" + mac;
+        // the thing name is the mdns name, the case of its mac address matters
+        let thing_name = mdns_name.trim_end_matches(".local");
+        let url = "wss://".to_owned() + mdns_name + "/things/" + thing_name;
 
         let (write_shelly, read_shelly) =
             ShellyManager::connect_to_shelly(ip, &url, user_login, user_password).await?;
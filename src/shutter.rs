@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+use std::error::Error;
+use tokio::time::{Duration, Instant};
+
+// Positions are percentages, 100 is fully open and 0 is fully closed.
+//
+// The travel times are configured in the domo_roller_shutter topic with
+// "open_time_ms", "close_time_ms" and, for venetian blinds, "tilt_time_ms".
+// When the firmware can't position the shutter by itself ("native_positioning"
+// not set in the topic) the bridge estimates the position from the travel
+// times and stops the motor when the target is reached.
+//
+// The travel times are not measured by the bridge, they have to be timed
+// when the shutter is installed. The "home" command drives the shutter to
+// the upper end stop, so that the estimated position is known again.
+
+// extra time given to full travels, so that the end stop is surely reached
+const FULL_TRAVEL_MARGIN: f64 = 1.1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutterAction {
+    Up,
+    Down,
+    Stop,
+    GoToPosition(f64),
+    Tilt(f64),
+    Home,
+}
+
+impl ShutterAction {
+    pub fn parse(value: &serde_json::Value) -> Result<ShutterAction, Box<dyn Error>> {
+        let shutter_command = value
+            .get("shutter_command")
+            .and_then(|c| c.as_str())
+            .ok_or("err_shutter_command")?;
+
+        let percentage = |field: &str| -> Result<f64, Box<dyn Error>> {
+            let v = value
+                .get(field)
+                .and_then(|v| v.as_f64())
+                .ok_or("err_".to_owned() + field)?;
+
+            if !(0.0..=100.0).contains(&v) {
+                return Err(("err_".to_owned() + field).into());
+            }
+
+            Ok(v)
+        };
+
+        match shutter_command {
+            "up" => Ok(ShutterAction::Up),
+            "down" => Ok(ShutterAction::Down),
+            "stop" => Ok(ShutterAction::Stop),
+            "go_to_position" => Ok(ShutterAction::GoToPosition(percentage("position")?)),
+            "set_tilt" => Ok(ShutterAction::Tilt(percentage("tilt")?)),
+            "home" => Ok(ShutterAction::Home),
+            _ => Err("unknown_shutter_command".into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShutterCalibration {
+    pub open_time_ms: u64,
+    pub close_time_ms: u64,
+    pub tilt_time_ms: u64,
+}
+
+impl ShutterCalibration {
+    pub fn from_topic(value: &serde_json::Value) -> Option<ShutterCalibration> {
+        let open_time_ms = value["open_time_ms"].as_u64().filter(|t| *t > 0)?;
+        let close_time_ms = value["close_time_ms"]
+            .as_u64()
+            .filter(|t| *t > 0)
+            .unwrap_or(open_time_ms);
+        let tilt_time_ms = value["tilt_time_ms"].as_u64().unwrap_or(0);
+
+        Some(ShutterCalibration {
+            open_time_ms,
+            close_time_ms,
+            tilt_time_ms,
+        })
+    }
+}
+
+pub struct ShutterCommand {
+    pub topic_name: String,
+    pub topic_uuid: String,
+    pub mac_address: serde_json::Value,
+    pub action: ShutterAction,
+    pub calibration: Option<ShutterCalibration>,
+    pub native_positioning: bool,
+    // last position stored in the topic
+    pub position: Option<f64>,
+    pub tilt: Option<f64>,
+}
+
+pub struct ShutterPosition {
+    pub topic_name: String,
+    pub topic_uuid: String,
+    pub position: f64,
+    pub tilt: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Direction {
+    Up,
+    Down,
+}
+
+struct Movement {
+    direction: Direction,
+    started: Instant,
+    start_position: f64,
+    start_tilt: f64,
+    deadline: Instant,
+    // false when the motor is stopped by the end stop
+    send_stop: bool,
+    tilt_only: bool,
+}
+
+struct ShutterState {
+    topic_name: String,
+    mac_address: serde_json::Value,
+    calibration: Option<ShutterCalibration>,
+    position: f64,
+    tilt: f64,
+    movement: Option<Movement>,
+}
+
+pub fn set_shutter_command(
+    mac_address: &serde_json::Value,
+    shutter_command: u64,
+) -> serde_json::Value {
+    let action_payload = serde_json::json!({
+        "shutter_command": shutter_command,
+    });
+
+    serde_json::json!({
+        "mac_address": mac_address,
+        "shelly_action": {
+          "input": {
+            "action": {
+              "action_name": "set_shutter",
+              "action_payload": action_payload.to_string(),
+            },
+          },
+        }
+    })
+}
+
+fn set_shutter_position_command(
+    mac_address: &serde_json::Value,
+    position: f64,
+) -> serde_json::Value {
+    let action_payload = serde_json::json!({
+        "shutter_position": position.round() as u64,
+    });
+
+    serde_json::json!({
+        "mac_address": mac_address,
+        "shelly_action": {
+          "input": {
+            "action": {
+              "action_name": "set_shutter_position",
+              "action_payload": action_payload.to_string(),
+            },
+          },
+        }
+    })
+}
+
+fn direction_command(mac_address: &serde_json::Value, direction: Direction) -> serde_json::Value {
+    match direction {
+        Direction::Up => set_shutter_command(mac_address, 0),
+        Direction::Down => set_shutter_command(mac_address, 1),
+    }
+}
+
+fn travel_duration(percentage: f64, time_ms: u64) -> Duration {
+    Duration::from_millis((percentage.abs() / 100.0 * time_ms as f64) as u64)
+}
+
+fn moved_percentage(elapsed: Duration, time_ms: u64) -> f64 {
+    if time_ms == 0 {
+        return 100.0;
+    }
+    elapsed.as_millis() as f64 / time_ms as f64 * 100.0
+}
+
+impl ShutterState {
+    fn travel_time_ms(&self, direction: Direction) -> u64 {
+        match (self.calibration, direction) {
+            (Some(c), Direction::Up) => c.open_time_ms,
+            (Some(c), Direction::Down) => c.close_time_ms,
+            (None, _) => 0,
+        }
+    }
+
+    fn estimate(&self, now: Instant) -> (f64, f64) {
+        let movement = match &self.movement {
+            Some(m) => m,
+            None => return (self.position, self.tilt),
+        };
+
+        let calibration = match self.calibration {
+            Some(c) => c,
+            None => return (self.position, self.tilt),
+        };
+
+        let elapsed = now.saturating_duration_since(movement.started);
+        let sign = match movement.direction {
+            Direction::Up => 1.0,
+            Direction::Down => -1.0,
+        };
+
+        let tilt = if calibration.tilt_time_ms > 0 {
+            movement.start_tilt + sign * moved_percentage(elapsed, calibration.tilt_time_ms)
+        } else {
+            movement.start_tilt
+        };
+
+        let position = if movement.tilt_only {
+            movement.start_position
+        } else {
+            movement.start_position
+                + sign * moved_percentage(elapsed, self.travel_time_ms(movement.direction))
+        };
+
+        (position.clamp(0.0, 100.0), tilt.clamp(0.0, 100.0))
+    }
+}
+
+pub struct ShutterManager {
+    shutters: HashMap<String, ShutterState>,
+    position_updates: Vec<ShutterPosition>,
+}
+
+impl ShutterManager {
+    pub fn new() -> Self {
+        ShutterManager {
+            shutters: HashMap::new(),
+            position_updates: vec![],
+        }
+    }
+
+    // returns the actuator commands to send
+    pub fn handle_command(&mut self, command: ShutterCommand) -> Vec<serde_json::Value> {
+        self.handle_command_at(command, Instant::now())
+    }
+
+    fn handle_command_at(
+        &mut self,
+        command: ShutterCommand,
+        now: Instant,
+    ) -> Vec<serde_json::Value> {
+        let state = self
+            .shutters
+            .entry(command.topic_uuid.clone())
+            .or_insert_with(|| ShutterState {
+                topic_name: command.topic_name.clone(),
+                mac_address: command.mac_address.clone(),
+                calibration: command.calibration,
+                position: command.position.unwrap_or(100.0),
+                tilt: command.tilt.unwrap_or(100.0),
+                movement: None,
+            });
+
+        state.mac_address = command.mac_address.clone();
+        state.calibration = command.calibration;
+
+        if command.native_positioning {
+            if let Some(position) = command.position {
+                state.position = position;
+            }
+        }
+
+        // the ongoing movement is interrupted by any new command
+        let interrupted = state.movement.is_some();
+        if interrupted {
+            let (position, tilt) = state.estimate(now);
+            state.position = position;
+            state.tilt = tilt;
+            state.movement = None;
+        }
+
+        let mut commands = vec![];
+
+        match command.action {
+            ShutterAction::Stop => {
+                commands.push(set_shutter_command(&state.mac_address, 2));
+            }
+            ShutterAction::Up | ShutterAction::Down | ShutterAction::Home => {
+                let direction = if command.action == ShutterAction::Down {
+                    Direction::Down
+                } else {
+                    Direction::Up
+                };
+
+                // homing drives the shutter against the upper end stop from
+                // whatever position it is
+                if command.action == ShutterAction::Home {
+                    state.position = 0.0;
+                    state.tilt = 0.0;
+                }
+
+                commands.push(direction_command(&state.mac_address, direction));
+
+                if state.calibration.is_some() {
+                    let full_travel = travel_duration(100.0, state.travel_time_ms(direction))
+                        .mul_f64(FULL_TRAVEL_MARGIN);
+
+                    state.movement = Some(Movement {
+                        direction,
+                        started: now,
+                        start_position: state.position,
+                        start_tilt: state.tilt,
+                        deadline: now + full_travel,
+                        send_stop: false,
+                        tilt_only: false,
+                    });
+                }
+            }
+            ShutterAction::GoToPosition(target) => {
+                if command.native_positioning {
+                    commands.push(set_shutter_position_command(&state.mac_address, target));
+                    state.position = target;
+                } else if state.calibration.is_some() {
+                    let delta = target - state.position;
+
+                    if delta.abs() >= 1.0 {
+                        let direction = if delta > 0.0 {
+                            Direction::Up
+                        } else {
+                            Direction::Down
+                        };
+
+                        let travel_time_ms = state.travel_time_ms(direction);
+
+                        // full travels are left to the end stops, this also
+                        // recovers the errors of the estimation
+                        let full_travel = target <= 0.0 || target >= 100.0;
+
+                        let duration = if full_travel {
+                            travel_duration(100.0, travel_time_ms).mul_f64(FULL_TRAVEL_MARGIN)
+                        } else {
+                            travel_duration(delta, travel_time_ms)
+                        };
+
+                        commands.push(direction_command(&state.mac_address, direction));
+
+                        state.movement = Some(Movement {
+                            direction,
+                            started: now,
+                            start_position: state.position,
+                            start_tilt: state.tilt,
+                            deadline: now + duration,
+                            send_stop: !full_travel,
+                            tilt_only: false,
+                        });
+                    }
+                }
+            }
+            ShutterAction::Tilt(target) => {
+                if let Some(calibration) = state.calibration {
+                    let delta = target - state.tilt;
+
+                    if calibration.tilt_time_ms > 0 && delta.abs() >= 1.0 {
+                        let direction = if delta > 0.0 {
+                            Direction::Up
+                        } else {
+                            Direction::Down
+                        };
+
+                        commands.push(direction_command(&state.mac_address, direction));
+
+                        state.movement = Some(Movement {
+                            direction,
+                            started: now,
+                            start_position: state.position,
+                            start_tilt: state.tilt,
+                            deadline: now + travel_duration(delta, calibration.tilt_time_ms),
+                            send_stop: true,
+                            tilt_only: true,
+                        });
+                    }
+                }
+            }
+        }
+
+        if interrupted || state.movement.is_none() {
+            self.position_updates.push(ShutterPosition {
+                topic_name: state.topic_name.clone(),
+                topic_uuid: command.topic_uuid,
+                position: state.position,
+                tilt: state.tilt,
+            });
+        }
+
+        commands
+    }
+
+    // completes the movements whose deadline has expired, returns the stop
+    // commands to send
+    pub fn process_deadlines(&mut self) -> Vec<serde_json::Value> {
+        self.process_deadlines_at(Instant::now())
+    }
+
+    fn process_deadlines_at(&mut self, now: Instant) -> Vec<serde_json::Value> {
+        let mut commands = vec![];
+
+        for (topic_uuid, state) in self.shutters.iter_mut() {
+            let expired = match &state.movement {
+                Some(m) => m.deadline <= now,
+                None => false,
+            };
+
+            if !expired {
+                continue;
+            }
+
+            let (position, tilt) = state.estimate(now);
+            let movement = state.movement.take().unwrap();
+
+            if movement.send_stop {
+                commands.push(set_shutter_command(&state.mac_address, 2));
+                state.position = position;
+                state.tilt = tilt;
+            } else {
+                // the end stop has been reached
+                match movement.direction {
+                    Direction::Up => {
+                        state.position = 100.0;
+                        state.tilt = 100.0;
+                    }
+                    Direction::Down => {
+                        state.position = 0.0;
+                        state.tilt = 0.0;
+                    }
+                }
+            }
+
+            self.position_updates.push(ShutterPosition {
+                topic_name: state.topic_name.clone(),
+                topic_uuid: topic_uuid.clone(),
+                position: state.position,
+                tilt: state.tilt,
+            });
+        }
+
+        commands
+    }
+
+    pub async fn wait_for_next_deadline(&self) {
+        let next_deadline = self
+            .shutters
+            .values()
+            .filter_map(|s| s.movement.as_ref().map(|m| m.deadline))
+            .min();
+
+        match next_deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => tokio::time::sleep(Duration::from_secs(3600)).await,
+        }
+    }
+
+    pub fn take_position_updates(&mut self) -> Vec<ShutterPosition> {
+        std::mem::take(&mut self.position_updates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shutter_command(action: ShutterAction) -> ShutterCommand {
+        ShutterCommand {
+            topic_name: String::from("domo_roller_shutter"),
+            topic_uuid: String::from("shutter"),
+            mac_address: serde_json::json!("aa:bb:cc:dd:ee:ff"),
+            action,
+            calibration: Some(ShutterCalibration {
+                open_time_ms: 20000,
+                close_time_ms: 18000,
+                tilt_time_ms: 1000,
+            }),
+            native_positioning: false,
+            position: Some(100.0),
+            tilt: Some(100.0),
+        }
+    }
+
+    #[test]
+    fn test_parse_shutter_action() {
+        let value = serde_json::json!({ "shutter_command": "go_to_position", "position": 50 });
+        assert_eq!(
+            ShutterAction::parse(&value).unwrap(),
+            ShutterAction::GoToPosition(50.0)
+        );
+
+        let value = serde_json::json!({ "shutter_command": "go_to_position", "position": 150 });
+        assert!(ShutterAction::parse(&value).is_err());
+
+        let value = serde_json::json!({ "shutter_command": "open" });
+        assert!(ShutterAction::parse(&value).is_err());
+    }
+
+    #[test]
+    fn test_go_to_position() {
+        let mut manager = ShutterManager::new();
+        let start = Instant::now();
+
+        let commands =
+            manager.handle_command_at(shutter_command(ShutterAction::GoToPosition(50.0)), start);
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].to_string().contains("shutter_command\\\":1"));
+
+        // 50% of 18 seconds
+        assert!(manager
+            .process_deadlines_at(start + Duration::from_millis(8999))
+            .is_empty());
+
+        let commands = manager.process_deadlines_at(start + Duration::from_millis(9000));
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].to_string().contains("shutter_command\\\":2"));
+
+        let updates = manager.take_position_updates();
+        assert_eq!(updates.len(), 1);
+        assert!((updates[0].position - 50.0).abs() < 0.1);
+        assert_eq!(updates[0].tilt, 0.0);
+    }
+
+    #[test]
+    fn test_home() {
+        let mut manager = ShutterManager::new();
+        let start = Instant::now();
+
+        let mut command = shutter_command(ShutterAction::Home);
+        command.position = Some(100.0);
+
+        let commands = manager.handle_command_at(command, start);
+        assert!(commands[0].to_string().contains("shutter_command\\\":0"));
+
+        // a full travel from the bottom, whatever the stored position
+        assert!(manager
+            .process_deadlines_at(start + Duration::from_millis(21999))
+            .is_empty());
+
+        let commands = manager.process_deadlines_at(start + Duration::from_millis(22000));
+        assert!(commands.is_empty());
+
+        let updates = manager.take_position_updates();
+        assert_eq!(updates[0].position, 100.0);
+    }
+
+    #[test]
+    fn test_stop_estimation() {
+        let mut manager = ShutterManager::new();
+        let start = Instant::now();
+
+        manager.handle_command_at(shutter_command(ShutterAction::Down), start);
+
+        let commands = manager.handle_command_at(
+            shutter_command(ShutterAction::Stop),
+            start + Duration::from_millis(4500),
+        );
+        assert_eq!(commands.len(), 1);
+
+        let updates = manager.take_position_updates();
+        assert!((updates[0].position - 75.0).abs() < 0.1);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::command_parser;
+use crate::dimmer;
+use crate::macaddress::MacAddress;
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+// Bridge side fading for the firmwares without native transitions: the
+// intermediate levels are sent to the actuator every STEP_MS until the
+// target is reached. A new command for the same actuator replaces the
+// ongoing transition, starting from the level reached so far.
+//
+// The duration of the dim_command and rgbw_command transitions is given by
+// "transition_ms" in the value of the command, next to the topic_uuid, e.g.
+//
+// { "topic_uuid": "...", "desired_state": { "hex": "#ff8000" }, "transition_ms": 2000 }
+
+const STEP_MS: u64 = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransitionKind {
+    Rgbw,
+    Dimmer,
+    // channel of the shelly_rgbw, driven with the 0-100 dimmer levels
+    LedDimmer(String),
+}
+
+impl TransitionKind {
+    pub fn command(
+        &self,
+        mac_address: &serde_json::Value,
+        levels: &[f64],
+        transition_ms: Option<u64>,
+    ) -> serde_json::Value {
+        let level = |i: usize| levels.get(i).copied().unwrap_or(0.0).round() as u64;
+
+        match self {
+            TransitionKind::Rgbw => command_parser::set_rgbw_command(
+                mac_address,
+                [level(0), level(1), level(2), level(3)],
+                transition_ms,
+            ),
+            TransitionKind::Dimmer => {
+                command_parser::set_dimmer_command(mac_address, level(0), transition_ms)
+            }
+            TransitionKind::LedDimmer(channel) => command_parser::set_led_dimmer_command(
+                mac_address,
+                channel,
+                dimmer::level_to_rgbw_channel(levels.first().copied().unwrap_or(0.0)) as u64,
+                transition_ms,
+            ),
+        }
+    }
+}
+
+pub struct TransitionCommand {
+    pub mac_address: serde_json::Value,
+    pub kind: TransitionKind,
+    pub from: Vec<f64>,
+    pub to: Vec<f64>,
+    pub duration_ms: u64,
+}
+
+struct Transition {
+    command: TransitionCommand,
+    started: Instant,
+    next_step: Instant,
+}
+
+impl Transition {
+    fn levels(&self, now: Instant) -> Vec<f64> {
+        let duration = Duration::from_millis(self.command.duration_ms);
+        let elapsed = now.saturating_duration_since(self.started);
+
+        let progress = if duration.is_zero() || elapsed >= duration {
+            1.0
+        } else {
+            elapsed.as_secs_f64() / duration.as_secs_f64()
+        };
+
+        self.command
+            .from
+            .iter()
+            .zip(self.command.to.iter())
+            .map(|(from, to)| from + (to - from) * progress)
+            .collect()
+    }
+
+    fn end(&self) -> Instant {
+        self.started + Duration::from_millis(self.command.duration_ms)
+    }
+}
+
+// the mac address in its canonical format, whatever the format of the topic
+fn transition_key(mac_address: &serde_json::Value, kind: &TransitionKind) -> String {
+    let mac_address = mac_address.as_str().unwrap_or_default();
+
+    let mac_address = match mac_address.parse::<MacAddress>() {
+        Ok(mac_address) => mac_address.to_string(),
+        Err(_) => mac_address.to_lowercase(),
+    };
+
+    mac_address + "-" + &format!("{:?}", kind)
+}
+
+pub struct TransitionManager {
+    transitions: HashMap<String, Transition>,
+}
+
+impl TransitionManager {
+    pub fn new() -> Self {
+        TransitionManager {
+            transitions: HashMap::new(),
+        }
+    }
+
+    // returns the first actuator command of the transition
+    pub fn start(&mut self, command: TransitionCommand) -> Vec<serde_json::Value> {
+        self.start_at(command, Instant::now())
+    }
+
+    fn start_at(&mut self, mut command: TransitionCommand, now: Instant) -> Vec<serde_json::Value> {
+        let key = transition_key(&command.mac_address, &command.kind);
+
+        if let Some(previous) = self.transitions.remove(&key) {
+            if previous.command.to.len() == command.to.len() {
+                command.from = previous.levels(now);
+            }
+        }
+
+        if command.duration_ms < STEP_MS {
+            return vec![command
+                .kind
+                .command(&command.mac_address, &command.to, None)];
+        }
+
+        let transition = Transition {
+            command,
+            started: now,
+            next_step: now + Duration::from_millis(STEP_MS),
+        };
+
+        let first_step = transition.command.kind.command(
+            &transition.command.mac_address,
+            &transition.command.from,
+            None,
+        );
+
+        self.transitions.insert(key, transition);
+
+        vec![first_step]
+    }
+
+    // returns the actuator commands of the steps that are due
+    pub fn process_steps(&mut self) -> Vec<serde_json::Value> {
+        self.process_steps_at(Instant::now())
+    }
+
+    fn process_steps_at(&mut self, now: Instant) -> Vec<serde_json::Value> {
+        let mut commands = vec![];
+        let mut completed = vec![];
+
+        for (key, transition) in self.transitions.iter_mut() {
+            if transition.next_step > now {
+                continue;
+            }
+
+            let levels = transition.levels(now);
+
+            commands.push(transition.command.kind.command(
+                &transition.command.mac_address,
+                &levels,
+                None,
+            ));
+
+            if now >= transition.end() {
+                completed.push(key.clone());
+            } else {
+                transition.next_step = now + Duration::from_millis(STEP_MS);
+            }
+        }
+
+        for key in completed {
+            self.transitions.remove(&key);
+        }
+
+        commands
+    }
+
+    pub async fn wait_for_next_step(&self) {
+        let next_step = self.transitions.values().map(|t| t.next_step).min();
+
+        match next_step {
+            Some(next_step) => tokio::time::sleep_until(next_step).await,
+            None => tokio::time::sleep(Duration::from_secs(3600)).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgbw_levels(command: &serde_json::Value) -> serde_json::Value {
+        let payload = command["shelly_action"]["input"]["action"]["action_payload"]
+            .as_str()
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        payload["rgbw_status"].clone()
+    }
+
+    #[test]
+    fn test_rgbw_transition() {
+        let mut manager = TransitionManager::new();
+        let start = Instant::now();
+
+        let command = TransitionCommand {
+            mac_address: serde_json::json!("aa:bb:cc:dd:ee:ff"),
+            kind: TransitionKind::Rgbw,
+            from: vec![0.0, 0.0, 0.0, 0.0],
+            to: vec![200.0, 100.0, 0.0, 0.0],
+            duration_ms: 1000,
+        };
+
+        let commands = manager.start_at(command, start);
+        assert_eq!(rgbw_levels(&commands[0])["r_value"], 0);
+
+        assert!(manager
+            .process_steps_at(start + Duration::from_millis(50))
+            .is_empty());
+
+        let commands = manager.process_steps_at(start + Duration::from_millis(500));
+        assert_eq!(rgbw_levels(&commands[0])["r_value"], 100);
+        assert_eq!(rgbw_levels(&commands[0])["g_value"], 50);
+
+        let commands = manager.process_steps_at(start + Duration::from_millis(1000));
+        assert_eq!(rgbw_levels(&commands[0])["r_value"], 200);
+
+        assert!(manager
+            .process_steps_at(start + Duration::from_millis(2000))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_replaced_transition() {
+        let mut manager = TransitionManager::new();
+        let start = Instant::now();
+
+        let command = |mac_address: &str| TransitionCommand {
+            mac_address: serde_json::json!(mac_address),
+            kind: TransitionKind::Dimmer,
+            from: vec![0.0],
+            to: vec![100.0],
+            duration_ms: 1000,
+        };
+
+        manager.start_at(command("aa:bb:cc:dd:ee:ff"), start);
+
+        // same actuator in another format, the transition is replaced
+        manager.start_at(command("AABBCCDDEEFF"), start + Duration::from_millis(500));
+        assert_eq!(manager.transitions.len(), 1);
+    }
+
+    #[test]
+    fn test_led_dimmer_scale() {
+        let mut manager = TransitionManager::new();
+        let start = Instant::now();
+
+        let command = TransitionCommand {
+            mac_address: serde_json::json!("aa:bb:cc:dd:ee:ff"),
+            kind: TransitionKind::LedDimmer(String::from("w")),
+            from: vec![0.0],
+            to: vec![100.0],
+            duration_ms: 1000,
+        };
+
+        manager.start_at(command, start);
+
+        let commands = manager.process_steps_at(start + Duration::from_millis(500));
+        let payload = commands[0]["shelly_action"]["input"]["action"]["action_payload"]
+            .as_str()
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["led_dimmer_status"]["channel"], "w");
+        assert_eq!(payload["led_dimmer_status"]["value"], 128);
+
+        let commands = manager.process_steps_at(start + Duration::from_millis(1000));
+        let payload = commands[0]["shelly_action"]["input"]["action"]["action_payload"]
+            .as_str()
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["led_dimmer_status"]["value"], 255);
+    }
+}
@@ -1,21 +1,230 @@
+use crate::bleutils::parse_valve_report;
+use crate::macaddress::MacAddress;
+use crate::messages::BleBeaconMessage;
+use rand::Rng;
 use std::collections::HashMap;
-use std::time::SystemTime;
+use tokio::time::{Duration, Instant};
+
+// random uuid (version 4) for the topics created by the bridge
+pub fn new_topic_uuid() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = hex::encode(bytes);
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+const RSSI_EMA_ALPHA: f64 = 0.3;
+
+// exponential moving average of the RSSI of a device received by a scanner
+#[derive(Debug, Clone, Copy)]
+pub struct RssiEma {
+    pub value: f64,
+    pub last_update: Instant,
+}
+
+impl RssiEma {
+    pub fn new(rssi: i64, now: Instant) -> Self {
+        RssiEma {
+            value: rssi as f64,
+            last_update: now,
+        }
+    }
+
+    pub fn update(&mut self, rssi: i64, now: Instant) {
+        self.value += RSSI_EMA_ALPHA * (rssi as f64 - self.value);
+        self.last_update = now;
+    }
+}
+
+// reported positions within this distance confirm a set_position command
+const VALVE_POSITION_TOLERANCE: f64 = 5.0;
+
+// an attempt not confirmed within the report interval of the valve, the
+// optional report_interval_secs of its topic, is a failure of its scanner
+const DEFAULT_VALVE_REPORT_INTERVAL_SECS: u64 = 60;
 
 #[derive(Clone)]
 pub struct ValveData {
     pub desired_state: serde_json::Value,
     pub attempts: usize,
+    // epoch ms of the command
+    pub timestamp: u64,
+    // scanner the last attempt was sent through
+    pub actuator_mac_address: Option<String>,
+    last_attempt: Instant,
+}
+
+impl ValveData {
+    pub fn new(desired_state: serde_json::Value, attempts: usize) -> Self {
+        ValveData {
+            desired_state,
+            attempts,
+            timestamp: sifis_dht::utils::get_epoch_ms() as u64,
+            actuator_mac_address: None,
+            last_attempt: Instant::now(),
+        }
+    }
+
+    // a new attempt through the scanner
+    pub fn retry(&mut self, actuator_mac_address: String) {
+        self.attempts += 1;
+        self.actuator_mac_address = Some(actuator_mac_address);
+        self.last_attempt = Instant::now();
+    }
+
+    // whether the valve had the time to report after the last attempt, the
+    // commands without an attempt are sent at once
+    pub fn is_attempt_expired(&self, valve_value: &serde_json::Value) -> bool {
+        self.is_attempt_expired_at(valve_value, Instant::now())
+    }
+
+    fn is_attempt_expired_at(&self, valve_value: &serde_json::Value, now: Instant) -> bool {
+        if self.actuator_mac_address.is_none() {
+            return true;
+        }
+
+        let report_interval = valve_value["report_interval_secs"]
+            .as_u64()
+            .unwrap_or(DEFAULT_VALVE_REPORT_INTERVAL_SECS);
+
+        now.saturating_duration_since(self.last_attempt) >= Duration::from_secs(report_interval)
+    }
+
+    // the calibration and anti-calcification cycles are confirmed by any
+    // report of the valve received after the command
+    pub fn is_confirmed(&self, valve_value: &serde_json::Value) -> bool {
+        match self.desired_state["valve_action"]
+            .as_str()
+            .unwrap_or("set_position")
+        {
+            "set_position" => {
+                let desired_position = self.desired_state["position"].as_f64();
+                let reported_position = valve_value["position"].as_f64();
+
+                if let (Some(desired), Some(reported)) = (desired_position, reported_position) {
+                    return (desired - reported).abs() <= VALVE_POSITION_TOLERANCE;
+                }
+
+                match (
+                    self.desired_state["desired_state"].as_bool(),
+                    valve_value["status"].as_bool(),
+                ) {
+                    (Some(desired), Some(status)) => desired == status,
+                    _ => false,
+                }
+            }
+            _ => valve_value["last_update_timestamp"]
+                .as_u64()
+                .map(|t| t > self.timestamp)
+                .unwrap_or(false),
+        }
+    }
 }
 
-pub struct BestActuatorData {
-    actuator_mac_address: String,
-    rssi: i64,
-    timestamp: std::time::SystemTime,
+// scanners not receiving the valve for this time are not used for its commands
+const SCANNER_TIMEOUT_SECS: u64 = 60;
+
+// devices not received by any scanner for this time are forgotten, most of
+// the devices in range are not registered and many change their address
+const DEVICE_TIMEOUT_SECS: u64 = 600;
+
+// a scanner has to be better by this score to replace the current one
+const SCANNER_HYSTERESIS_DB: f64 = 4.0;
+
+// the share of confirmed commands moves the score of a scanner up to this
+const RELIABILITY_WEIGHT_DB: f64 = 10.0;
+
+// the scanners of a valve are ranked by their smoothed RSSI corrected by how
+// many of the commands they sent were confirmed
+struct ScannerStats {
+    rssi: RssiEma,
+    successes: u32,
+    failures: u32,
+}
+
+impl ScannerStats {
+    fn score(&self) -> f64 {
+        let reliability =
+            (self.successes as f64 + 1.0) / (self.successes as f64 + self.failures as f64 + 2.0);
+
+        self.rssi.value + RELIABILITY_WEIGHT_DB * (2.0 * reliability - 1.0)
+    }
+}
+
+pub struct ValveScanners {
+    scanners: HashMap<String, ScannerStats>,
+    best: Option<String>,
+}
+
+impl ValveScanners {
+    fn new() -> Self {
+        ValveScanners {
+            scanners: HashMap::new(),
+            best: None,
+        }
+    }
+
+    // scanners still receiving the valve, best first
+    fn ranking(&self, now: Instant) -> Vec<(&String, f64)> {
+        let timeout = Duration::from_secs(SCANNER_TIMEOUT_SECS);
+
+        let mut ranking: Vec<(&String, f64)> = self
+            .scanners
+            .iter()
+            .filter(|(_, stats)| now.saturating_duration_since(stats.rssi.last_update) < timeout)
+            .map(|(scanner, stats)| (scanner, stats.score()))
+            .collect();
+
+        ranking.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranking
+    }
+
+    fn last_update(&self) -> Option<Instant> {
+        self.scanners
+            .values()
+            .map(|stats| stats.rssi.last_update)
+            .max()
+    }
+
+    fn select_best(&mut self, now: Instant) {
+        let ranking = self.ranking(now);
+
+        let first = match ranking.first() {
+            Some((scanner, score)) => ((*scanner).clone(), *score),
+            None => return,
+        };
+
+        let current = self
+            .best
+            .as_ref()
+            .and_then(|best| ranking.iter().find(|(scanner, _)| *scanner == best));
+
+        let keep_current = match current {
+            Some((_, score)) => first.1 < score + SCANNER_HYSTERESIS_DB,
+            None => false,
+        };
+
+        if !keep_current {
+            self.best = Some(first.0);
+        }
+    }
 }
 
 pub struct ValveCommandManager {
     pub valve_commands: HashMap<String, ValveData>,
-    pub best_actuator: HashMap<String, BestActuatorData>,
+    // the scanners of the valves, and of the other BLE devices for their gatt
+    // commands
+    pub best_actuator: HashMap<MacAddress, ValveScanners>,
 }
 
 impl ValveCommandManager {
@@ -35,51 +244,299 @@ impl ValveCommandManager {
             .insert(valve_mac_address.to_owned(), valve_data);
     }
 
-    pub fn update_best_actuator(
+    // every advertisement ranks the scanners of its device, for the commands
+    // of the valves and the gatt commands of any device; the status reports
+    // of the valves are not advertisements
+    pub fn observe(&mut self, message: &BleBeaconMessage) {
+        self.observe_at(message, Instant::now());
+    }
+
+    fn observe_at(&mut self, message: &BleBeaconMessage, now: Instant) {
+        if parse_valve_report(&message.payload).is_none() {
+            self.update_best_actuator_at(
+                &message.mac_address,
+                &message.actuator,
+                message.rssi,
+                now,
+            );
+        }
+    }
+
+    // the devices with queued commands are kept
+    pub fn remove_stale_devices(&mut self) {
+        self.remove_stale_devices_at(Instant::now());
+    }
+
+    fn remove_stale_devices_at(&mut self, now: Instant) {
+        let timeout = Duration::from_secs(DEVICE_TIMEOUT_SECS);
+        let valve_commands = &self.valve_commands;
+
+        self.best_actuator.retain(|mac_address, valve| {
+            let recent = valve
+                .last_update()
+                .map(|t| now.saturating_duration_since(t) < timeout)
+                .unwrap_or(false);
+
+            recent
+                || valve_commands
+                    .keys()
+                    .any(|valve_mac_address| mac_address.matches(valve_mac_address))
+        });
+    }
+
+    fn update_best_actuator_at(
         &mut self,
         valve_mac_address: &str,
         actuator_mac_address: &str,
         rssi: i64,
+        now: Instant,
+    ) {
+        let valve_mac_address = match valve_mac_address.parse::<MacAddress>() {
+            Ok(mac_address) => mac_address,
+            Err(_) => return,
+        };
+
+        let valve = self
+            .best_actuator
+            .entry(valve_mac_address)
+            .or_insert_with(ValveScanners::new);
+
+        valve
+            .scanners
+            .entry(actuator_mac_address.to_owned())
+            .and_modify(|stats| stats.rssi.update(rssi, now))
+            .or_insert_with(|| ScannerStats {
+                rssi: RssiEma::new(rssi, now),
+                successes: 0,
+                failures: 0,
+            });
+
+        valve.select_best(now);
+    }
+
+    fn scanners(&self, valve_mac_address: &str) -> Option<&ValveScanners> {
+        self.best_actuator
+            .get(&valve_mac_address.parse::<MacAddress>().ok()?)
+    }
+
+    pub fn get_best_actuator_for_valve(&self, valve_mac_address: &str) -> Option<String> {
+        self.scanners(valve_mac_address)?.best.clone()
+    }
+
+    // the retries go to the best scanner other than the one that failed, so
+    // to the second best when the failed one is still the best
+    pub fn get_actuator_for_retry(
+        &self,
+        valve_mac_address: &str,
+        failed_actuator: Option<&str>,
+    ) -> Option<String> {
+        self.get_actuator_for_retry_at(valve_mac_address, failed_actuator, Instant::now())
+    }
+
+    fn get_actuator_for_retry_at(
+        &self,
+        valve_mac_address: &str,
+        failed_actuator: Option<&str>,
+        now: Instant,
+    ) -> Option<String> {
+        let valve = self.scanners(valve_mac_address)?;
+
+        valve
+            .ranking(now)
+            .into_iter()
+            .map(|(scanner, _)| scanner)
+            .find(|scanner| Some(scanner.as_str()) != failed_actuator)
+            .cloned()
+            .or_else(|| valve.best.clone())
+    }
+
+    // fed back from the valve commands, confirmed or not by the valve
+    pub fn record_result(
+        &mut self,
+        valve_mac_address: &str,
+        actuator_mac_address: &str,
+        success: bool,
     ) {
-        let now = SystemTime::now();
-        if self.best_actuator.contains_key(valve_mac_address) {
-            let data = self.best_actuator.get(valve_mac_address).unwrap();
-            if data.rssi < rssi || (data.timestamp.elapsed().unwrap().as_secs() > 30) {
-                self.best_actuator.insert(
-                    valve_mac_address.to_string(),
-                    BestActuatorData {
-                        actuator_mac_address: actuator_mac_address.to_string(),
-                        rssi,
-                        timestamp: now,
-                    },
-                );
-                //println!(
-                //    "BEST ACT FOR {} is {} ",
-                //    valve_mac_address, actuator_mac_address
-                //);
+        let valve = match valve_mac_address
+            .parse::<MacAddress>()
+            .ok()
+            .and_then(|mac_address| self.best_actuator.get_mut(&mac_address))
+        {
+            Some(valve) => valve,
+            None => return,
+        };
+
+        if let Some(stats) = valve.scanners.get_mut(actuator_mac_address) {
+            if success {
+                stats.successes += 1;
+            } else {
+                stats.failures += 1;
             }
-        } else {
-            self.best_actuator.insert(
-                valve_mac_address.to_string(),
-                BestActuatorData {
-                    actuator_mac_address: actuator_mac_address.to_string(),
-                    rssi,
-                    timestamp: now,
-                },
-            );
-            //println!(
-            //    "BEST ACT FOR {} is {} ",
-            //    valve_mac_address, actuator_mac_address
-            //);
         }
+
+        valve.select_best(Instant::now());
     }
+}
 
-    pub fn get_best_actuator_for_valve(&self, valve_mac_address: &str) -> Option<String> {
-        if self.best_actuator.contains_key(valve_mac_address) {
-            let act = self.best_actuator.get(valve_mac_address).unwrap();
-            Some(act.actuator_mac_address.clone())
-        } else {
-            None
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::beacon;
+
+    const VALVE: &str = "aa:aa:aa:aa:aa:aa";
+    const SCANNER_1: &str = "bb:bb:bb:bb:bb:01";
+    const SCANNER_2: &str = "bb:bb:bb:bb:bb:02";
+
+    #[test]
+    fn test_best_actuator_hysteresis() {
+        let mut manager = ValveCommandManager::new();
+        let now = Instant::now();
+
+        manager.update_best_actuator_at(VALVE, SCANNER_1, -70, now);
+        manager.update_best_actuator_at(VALVE, SCANNER_2, -68, now);
+        assert_eq!(
+            manager.get_best_actuator_for_valve(VALVE).unwrap(),
+            SCANNER_1
+        );
+
+        // a single strong sample doesn't steal the valve
+        manager.update_best_actuator_at(VALVE, SCANNER_2, -64, now);
+        assert_eq!(
+            manager.get_best_actuator_for_valve(VALVE).unwrap(),
+            SCANNER_1
+        );
+
+        for _ in 0..10 {
+            manager.update_best_actuator_at(VALVE, SCANNER_2, -60, now);
         }
+        assert_eq!(
+            manager.get_best_actuator_for_valve(VALVE).unwrap(),
+            SCANNER_2
+        );
+
+        // scanners not receiving the valve anymore are replaced
+        let later = now + Duration::from_secs(SCANNER_TIMEOUT_SECS + 1);
+        manager.update_best_actuator_at(VALVE, SCANNER_1, -80, later);
+        assert_eq!(
+            manager.get_best_actuator_for_valve(VALVE).unwrap(),
+            SCANNER_1
+        );
+    }
+
+    #[test]
+    fn test_best_actuator_scoring() {
+        let mut manager = ValveCommandManager::new();
+        let now = Instant::now();
+
+        manager.update_best_actuator_at(VALVE, SCANNER_1, -65, now);
+        manager.update_best_actuator_at(VALVE, SCANNER_2, -70, now);
+
+        assert_eq!(
+            manager
+                .get_actuator_for_retry_at(VALVE, Some(SCANNER_1), now)
+                .unwrap(),
+            SCANNER_2
+        );
+
+        for _ in 0..5 {
+            manager.record_result(VALVE, SCANNER_1, false);
+            manager.record_result(VALVE, SCANNER_2, true);
+        }
+        assert_eq!(
+            manager.get_best_actuator_for_valve(VALVE).unwrap(),
+            SCANNER_2
+        );
+    }
+
+    #[test]
+    fn test_retry_after_failure() {
+        let mut manager = ValveCommandManager::new();
+        let now = Instant::now();
+
+        manager.update_best_actuator_at(VALVE, SCANNER_1, -70, now);
+        manager.update_best_actuator_at(VALVE, SCANNER_2, -71, now);
+
+        // the failure is recorded before the retry is routed, as in the
+        // retry loop of the valve commands
+        let mut failed = manager.get_best_actuator_for_valve(VALVE).unwrap();
+        assert_eq!(failed, SCANNER_1);
+
+        for _ in 0..4 {
+            manager.record_result(VALVE, &failed, false);
+
+            let next = manager
+                .get_actuator_for_retry_at(VALVE, Some(&failed), now)
+                .unwrap();
+            assert_ne!(next, failed);
+
+            failed = next;
+        }
+    }
+
+    #[test]
+    fn test_valve_confirmation() {
+        let command = ValveData::new(
+            serde_json::json!({ "desired_state": true, "position": 40 }),
+            1,
+        );
+
+        assert!(command.is_confirmed(&serde_json::json!({ "status": true, "position": 43 })));
+        assert!(!command.is_confirmed(&serde_json::json!({ "status": true, "position": 100 })));
+
+        // the legacy reports have no position
+        assert!(command.is_confirmed(&serde_json::json!({ "status": true })));
+        assert!(!command.is_confirmed(&serde_json::json!({ "status": false })));
+    }
+
+    #[test]
+    fn test_valve_attempt_expiry() {
+        let mut command = ValveData::new(serde_json::json!({ "desired_state": true }), 0);
+        let now = command.last_attempt;
+
+        // no scanner received the valve yet
+        assert!(command.is_attempt_expired_at(&serde_json::Value::Null, now));
+
+        command.retry(SCANNER_1.to_owned());
+        let now = command.last_attempt;
+        let later = now + Duration::from_secs(20);
+
+        // the retry ticks before a report are not failures
+        assert!(!command.is_attempt_expired_at(&serde_json::Value::Null, later));
+        assert!(command.is_attempt_expired_at(
+            &serde_json::Value::Null,
+            now + Duration::from_secs(DEFAULT_VALVE_REPORT_INTERVAL_SECS)
+        ));
+        assert!(command
+            .is_attempt_expired_at(&serde_json::json!({ "report_interval_secs": 15 }), later));
+        assert!(!command.is_attempt_expired_at(
+            &serde_json::json!({ "report_interval_secs": 300 }),
+            now + Duration::from_secs(DEFAULT_VALVE_REPORT_INTERVAL_SECS)
+        ));
+    }
+
+    #[test]
+    fn test_observe() {
+        let mut manager = ValveCommandManager::new();
+        let now = Instant::now();
+
+        // the status reports of the valves are not advertisements
+        manager.observe_at(&beacon(SCANNER_1, VALVE, "1", -70), now);
+        assert_eq!(manager.get_best_actuator_for_valve(VALVE), None);
+
+        manager.observe_at(&beacon(SCANNER_1, VALVE, "AgEG", -70), now);
+        manager.observe_at(&beacon(SCANNER_1, "c4:7c:8d:6a:12:34", "AgEG", -70), now);
+        assert_eq!(
+            manager.get_best_actuator_for_valve("C47C8D6A1234").unwrap(),
+            SCANNER_1
+        );
+
+        // the devices out of range are forgotten, unless commands are queued
+        manager.insert(VALVE, ValveData::new(serde_json::json!({}), 1));
+        manager.remove_stale_devices_at(now + Duration::from_secs(DEVICE_TIMEOUT_SECS));
+        assert_eq!(
+            manager.get_best_actuator_for_valve("c4:7c:8d:6a:12:34"),
+            None
+        );
+        assert!(manager.scanners(VALVE).is_some());
     }
 }
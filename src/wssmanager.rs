@@ -2,6 +2,7 @@ use axum::{extract::Extension, http, response::IntoResponse, routing::get, Route
 
 use axum_auth::AuthBasic;
 
+use crate::macaddress::same_mac_address;
 use crate::messages::{AuthCredMessage, BleBeaconMessage, ESP32CommandMessage, ESP32CommandType};
 use axum::extract::ws::Message;
 use axum::extract::ws::WebSocketUpgrade;
@@ -16,6 +17,7 @@ use axum_server::tls_rustls::RustlsConfig;
 fn parse_esp32_message(
     shelly_message: &serde_json::Value,
     updates_channel: &broadcast::Sender<BleBeaconMessage>,
+    gatt_results_channel: &broadcast::Sender<serde_json::Value>,
 ) -> bool {
     if let Some(message_type) = shelly_message.get("messageType") {
         if message_type.as_str().unwrap() == "propertyStatus" {
@@ -43,7 +45,10 @@ fn parse_esp32_message(
                                 for prop in vec_prop {
                                     let prop_str = prop.as_str().unwrap();
 
-                                    if prop_str != "beacon_adv" && prop_str != "valve_operation" {
+                                    if prop_str != "beacon_adv"
+                                        && prop_str != "valve_operation"
+                                        && prop_str != "gatt_result"
+                                    {
                                         update_act = true;
                                     }
 
@@ -56,11 +61,19 @@ fn parse_esp32_message(
                                                 mac_address_actuator, beacon_adv_string
                                             );
 
-                                            let b = BleBeaconMessage::from(
+                                            match BleBeaconMessage::from(
                                                 beacon_adv_string,
                                                 mac_address_actuator,
-                                            );
-                                            let _ret = updates_channel.send(b);
+                                            ) {
+                                                Ok(b) => {
+                                                    let _ret = updates_channel.send(b);
+                                                }
+                                                Err(e) => log::warn!(
+                                                    "beacon_adv from {}: {}",
+                                                    mac_address_actuator,
+                                                    e
+                                                ),
+                                            }
                                         }
                                     } else if prop_str == "valve_operation" {
                                         if let Some(valve_operation) =
@@ -69,11 +82,31 @@ fn parse_esp32_message(
                                             let valve_operation_string =
                                                 valve_operation.as_str().unwrap();
 
-                                            let b = BleBeaconMessage::from(
+                                            match BleBeaconMessage::from(
                                                 valve_operation_string,
                                                 mac_address_actuator,
-                                            );
-                                            let _ret = updates_channel.send(b);
+                                            ) {
+                                                Ok(b) => {
+                                                    let _ret = updates_channel.send(b);
+                                                }
+                                                Err(e) => log::warn!(
+                                                    "valve_operation from {}: {}",
+                                                    mac_address_actuator,
+                                                    e
+                                                ),
+                                            }
+                                        }
+                                    } else if prop_str == "gatt_result" {
+                                        if let Some(gatt_result) = status_result.get("gatt_result")
+                                        {
+                                            // sent as a string like the other properties
+                                            let gatt_result = match gatt_result.as_str() {
+                                                Some(s) => serde_json::from_str(s)
+                                                    .unwrap_or(serde_json::Value::Null),
+                                                None => gatt_result.to_owned(),
+                                            };
+
+                                            let _ret = gatt_results_channel.send(gatt_result);
                                         }
                                     }
                                 }
@@ -88,6 +121,11 @@ fn parse_esp32_message(
     false
 }
 
+// the extensions of the router are told apart by type, the actuator updates
+// are json values too
+#[derive(Clone)]
+struct GattResultsChannel(broadcast::Sender<serde_json::Value>);
+
 pub struct WssManager {
     //  listening port
     pub http_port: u16,
@@ -97,6 +135,8 @@ pub struct WssManager {
     pub command_channel_rx: broadcast::Receiver<ESP32CommandMessage>,
     pub channel_of_actuator_updates_tx: broadcast::Sender<serde_json::Value>,
     pub channel_of_actuator_updates_rx: broadcast::Receiver<serde_json::Value>,
+    pub channel_of_gatt_results_tx: broadcast::Sender<serde_json::Value>,
+    pub channel_of_gatt_results_rx: broadcast::Receiver<serde_json::Value>,
     pub rx_auth_cred: mpsc::Receiver<AuthCredMessage>,
 }
 
@@ -131,6 +171,11 @@ impl WssManager {
 
         let channel_of_actuator_updates_tx_copy = channel_of_actuator_updates_tx.clone();
 
+        let (channel_of_gatt_results_tx, channel_of_gatt_results_rx) =
+            broadcast::channel::<serde_json::Value>(16);
+
+        let channel_of_gatt_results_tx_copy = channel_of_gatt_results_tx.clone();
+
         let app = Router::new()
             .route(
                 "/",
@@ -138,6 +183,9 @@ impl WssManager {
                     .layer(Extension(command_channel_tx_copy))
                     .layer(Extension(channel_of_updates_tx_copy))
                     .layer(Extension(channel_of_actuator_updates_tx_copy))
+                    .layer(Extension(GattResultsChannel(
+                        channel_of_gatt_results_tx_copy,
+                    )))
                     .layer(Extension(tx_auth_cred_copy)),
             )
             .layer(
@@ -161,6 +209,8 @@ impl WssManager {
             command_channel_rx,
             channel_of_actuator_updates_tx,
             channel_of_actuator_updates_rx,
+            channel_of_gatt_results_tx,
+            channel_of_gatt_results_rx,
             rx_auth_cred,
         }
     }
@@ -170,6 +220,7 @@ impl WssManager {
         Extension(command_channel): Extension<broadcast::Sender<ESP32CommandMessage>>,
         Extension(updates_channel): Extension<broadcast::Sender<BleBeaconMessage>>,
         Extension(updates_actuator_channel): Extension<broadcast::Sender<serde_json::Value>>,
+        Extension(GattResultsChannel(gatt_results_channel)): Extension<GattResultsChannel>,
         Extension(tx_cred): Extension<Sender<AuthCredMessage>>,
         AuthBasic((user, password)): AuthBasic,
     ) -> impl IntoResponse {
@@ -241,9 +292,9 @@ impl WssManager {
 
                                 if let Ok(cmd) = command {
                                     match cmd.command_type {
-                                        ESP32CommandType::Valve => {
+                                        ESP32CommandType::Valve | ESP32CommandType::Gatt => {
 
-                                               if esp32_mac_address == cmd.actuator_mac_address {
+                                               if same_mac_address(&esp32_mac_address, &cmd.actuator_mac_address) {
                                                     //println!("Received valve command {} ", esp32_mac_address);
                                                     if let Some(shelly_action_payload) = cmd.payload.get("shelly_action") {
                                                                 let shelly_action = serde_json::json!({ "shelly_action": shelly_action_payload });
@@ -261,7 +312,7 @@ impl WssManager {
                                         }
                                         ESP32CommandType::Actuator => {
                                             //println!("Received Actuator command");
-                                            if cmd.mac_address == esp32_mac_address {
+                                            if same_mac_address(&cmd.mac_address, &esp32_mac_address) {
                                                 if let Some(shelly_action_payload) = cmd.payload.get("shelly_action") {
                                                             let shelly_action = serde_json::json!({ "shelly_action": shelly_action_payload });
 
@@ -300,7 +351,7 @@ impl WssManager {
 
                                             let shelly_message: serde_json::Value = serde_json::from_str(&message).unwrap();
 
-                                            if !parse_esp32_message(&shelly_message, &updates_channel) {
+                                            if !parse_esp32_message(&shelly_message, &updates_channel, &gatt_results_channel) {
                                                 let _ret = updates_actuator_channel.send(shelly_message);
                                             }
                                         },